@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+use std::ops::{Bound,RangeBounds};
+
+// sorted runs merged by a cursor: the slot (through a sorted index) and the levels
+pub(crate) trait Runs<K> {
+    fn runs(&self) -> usize;
+    fn run_len(&self, r: usize) -> usize;
+    // None if the key of a spilled level can't be read, such keys go after all the others
+    fn key(&self, r: usize, p: usize) -> Option<&K>;
+    fn next_live(&self, r: usize, from: usize) -> usize; // or run_len
+    fn prev_live(&self, r: usize, before: usize) -> Option<usize>;
+}
+
+// slot indexes in key order
+pub(crate) fn sorted_order<K: Ord, V>(slot: &[(K,V)]) -> Vec<usize> {
+    let mut order = (0 .. slot.len()).collect::<Vec<_>>();
+    order.sort_by(|a,b| slot[*a].0.cmp(&slot[*b].0));
+    order
+}
+
+// Entries are ordered by (key,run), equal keys are possible only with pending deltas.
+//   lo[r] is the first live entry of the run r not less then the current one,
+//   if there is no current entry (cur is None) the cursor is in the gap before lo.
+#[derive(Debug,Clone)]
+pub(crate) struct Heads {
+    lo: Vec<usize>,
+    cur: Option<usize>,
+}
+impl Heads {
+    pub(crate) fn first<K: Ord, R: Runs<K>>(runs: &R) -> Heads {
+        Heads {
+            lo: (0 .. runs.runs()).map(|r| runs.next_live(r,0)).collect(),
+            cur: None,
+        }
+    }
+    pub(crate) fn last<K: Ord, R: Runs<K>>(runs: &R) -> Heads {
+        Heads {
+            lo: (0 .. runs.runs()).map(|r| runs.run_len(r)).collect(),
+            cur: None,
+        }
+    }
+    pub(crate) fn current(&self) -> Option<(usize,usize)> {
+        self.cur.map(|r| (r,self.lo[r]))
+    }
+    // the gap before the first entry not matching pred (entries matching pred go first)
+    pub(crate) fn gap<K: Ord, R: Runs<K>, F: Fn(&K) -> bool>(runs: &R, pred: F) -> Heads {
+        let lo = (0 .. runs.runs()).map(|r| {
+            let (mut a, mut b) = (0, runs.run_len(r));
+            while a < b {
+                let m = (a + b) / 2;
+                match runs.key(r,m).is_some_and(&pred) {
+                    true => a = m + 1,
+                    false => b = m,
+                }
+            }
+            runs.next_live(r,a)
+        }).collect();
+        Heads {
+            lo,
+            cur: None,
+        }
+    }
+    // front and back gaps of the range
+    pub(crate) fn range<K: Ord, R: Runs<K>, B: RangeBounds<K>>(runs: &R, range: &B) -> (Heads,Heads) {
+        let front = match range.start_bound() {
+            Bound::Included(a) => Heads::gap(runs,|k| k < a),
+            Bound::Excluded(a) => Heads::gap(runs,|k| k <= a),
+            Bound::Unbounded => Heads::first(runs),
+        };
+        let back = match range.end_bound() {
+            Bound::Included(b) => Heads::gap(runs,|k| k <= b),
+            Bound::Excluded(b) => Heads::gap(runs,|k| k < b),
+            Bound::Unbounded => Heads::last(runs),
+        };
+        (front,back)
+    }
+    pub(crate) fn seek<K: Ord, R: Runs<K>>(&mut self, runs: &R, k: &K) -> Option<(usize,usize)> {
+        *self = Heads::gap(runs,|x| x < k);
+        self.cur = min_head(runs,|r| self.lo[r]);
+        self.current()
+    }
+    pub(crate) fn next<K: Ord, R: Runs<K>>(&mut self, runs: &R) -> Option<(usize,usize)> {
+        if let Some(r) = self.cur {
+            self.lo[r] = runs.next_live(r,self.lo[r] + 1);
+        }
+        self.cur = min_head(runs,|r| self.lo[r]);
+        self.current()
+    }
+    pub(crate) fn prev<K: Ord, R: Runs<K>>(&mut self, runs: &R) -> Option<(usize,usize)> {
+        match max_prev(runs,|r| self.lo[r]) {
+            Some((r,p)) => {
+                self.lo[r] = p;
+                self.cur = Some(r);
+            },
+            None => self.cur = None,
+        }
+        self.current()
+    }
+    pub(crate) fn peek_next<K: Ord, R: Runs<K>>(&self, runs: &R) -> Option<(usize,usize)> {
+        let head = |r| match self.cur == Some(r) {
+            true => runs.next_live(r,self.lo[r] + 1),
+            false => self.lo[r],
+        };
+        min_head(runs,head).map(|r| (r,head(r)))
+    }
+    pub(crate) fn peek_prev<K: Ord, R: Runs<K>>(&self, runs: &R) -> Option<(usize,usize)> {
+        max_prev(runs,|r| self.lo[r])
+    }
+    // current entry of the run r was removed, p is its position in the run now
+    pub(crate) fn removed<K: Ord, R: Runs<K>>(&mut self, runs: &R, r: usize, p: usize) {
+        self.lo[r] = runs.next_live(r,p);
+        self.cur = None;
+    }
+}
+
+fn less<K: Ord, R: Runs<K>>(runs: &R, a: (usize,usize), b: (usize,usize)) -> bool {
+    let ord = match (runs.key(a.0,a.1),runs.key(b.0,b.1)) {
+        (Some(x),Some(y)) => x.cmp(y),
+        (Some(_),None) => Ordering::Less,
+        (None,Some(_)) => Ordering::Greater,
+        (None,None) => Ordering::Equal,
+    };
+    match ord {
+        Ordering::Less => true,
+        Ordering::Greater => false,
+        Ordering::Equal => a.0 < b.0,
+    }
+}
+
+fn min_head<K: Ord, R: Runs<K>, F: Fn(usize) -> usize>(runs: &R, head: F) -> Option<usize> {
+    let mut best: Option<(usize,usize)> = None;
+    for r in 0 .. runs.runs() {
+        let p = head(r);
+        if p >= runs.run_len(r) { continue; }
+        best = match best {
+            Some(b) if less(runs,b,(r,p)) => Some(b),
+            _ => Some((r,p)),
+        };
+    }
+    best.map(|(r,_)| r)
+}
+
+fn max_prev<K: Ord, R: Runs<K>, F: Fn(usize) -> usize>(runs: &R, head: F) -> Option<(usize,usize)> {
+    let mut best: Option<(usize,usize)> = None;
+    for r in 0 .. runs.runs() {
+        let p = match runs.prev_live(r,head(r)) {
+            Some(p) => p,
+            None => continue,
+        };
+        best = match best {
+            Some(b) if less(runs,(r,p),b) => Some(b),
+            _ => Some((r,p)),
+        };
+    }
+    best
+}
@@ -1,13 +1,25 @@
 use serde::{
     Serialize,Deserialize,
-    ser::{Serializer,SerializeStruct},
-    de::DeserializeOwned,
+    ser::{Serializer,SerializeStruct,SerializeMap},
+    de::{Deserializer,DeserializeOwned,Visitor,MapAccess,Error as _},
 };
 use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
-use std::io::{Read,Write};
+use bincode::Options;
+use std::io::{self,Read,Write,Seek,SeekFrom,BufReader};
+use std::sync::{Arc,atomic::{AtomicBool,Ordering}};
+use std::path::{Path,PathBuf};
+use std::collections::TryReserveError;
+use std::ops::RangeBounds;
+use std::borrow::{Borrow,Cow};
 use crate::{
-    Flags,Filled,Binary,
-    civs::{Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT},
+    Flags,Ones,FilterOnes,Filled,Binary,CivError,CivInvalid,CivLimits,Layout,
+    civs::{
+        Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT,range_span,try_range_span,merge_back,OpRef,
+        Directory,Compacted,Section,Fingerprint,Expected,SectionError,SlotRef,PortableSlot,Items,level_capacity,levels_for,capacity_overflow,single_run_parts,file_options,read_section,read_slot,scan_section,is_file_version,check_level,
+        Bounded,within_limits,limit_or,
+        cursor::{Runs,Heads,sorted_order},
+        spill::{Column,Counted,LevelWriter,SpillFile,into_io,index_column,write_column,read_ok,read_keys},
+    },
 };
 
 pub enum RemovedItem<'t,V> {
@@ -58,6 +70,114 @@ impl<'t,V: Clone> RemovedItem<'t,V> {
     }
 }
 
+type MergeOp<V> = Arc<dyn Fn(&V,&V) -> V + Send + Sync>;
+type CompactionFilter<K,V> = Arc<dyn Fn(&K,&V) -> Compaction<V> + Send + Sync>;
+type FilterRef<'t,K,V> = &'t dyn Fn(&K,&V) -> Compaction<V>;
+type StreamMerge<K,V> = fn(&Path, &MapMultiSlot<K,V>, &[K], &[V], usize, Option<OpRef<V>>, Option<FilterRef<K,V>>) -> io::Result<(MapMultiSlot<K,V>,usize)>;
+
+// heap budget of the levels, the largest ones over it are moved to files in dir
+#[derive(Clone)]
+struct Spill<K,V> {
+    budget: usize,
+    dir: PathBuf,
+    write: fn(&mut MapMultiSlot<K,V>, &Path) -> io::Result<()>,
+    merge: StreamMerge<K,V>,
+}
+
+// writes the level in the level encoding, keys and values are read back from the file on access
+fn spill_level<K,V>(ms: &mut MapMultiSlot<K,V>, dir: &Path) -> io::Result<()>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    let (file,mut wrt) = SpillFile::create(dir)?;
+    bincode::serialize_into(&mut wrt,&(ms.capacity,std::mem::size_of::<K>(),std::mem::size_of::<V>(),&ms.flags)).map_err(into_io)?;
+    let keys_offset = wrt.stream_position()?;
+    let mut wrt = Counted::new(wrt,keys_offset);
+    let key_marks = write_column(&mut wrt,ms.keys.heap())?;
+    let values_offset = wrt.position();
+    let value_marks = write_column(&mut wrt,ms.values.heap())?;
+    wrt.flush()?;
+    let len = ms.keys.len();
+    let file = Arc::new(file);
+    ms.keys = Column::spilled(file.clone(),keys_offset,len,Some(key_marks));
+    ms.values = Column::spilled(file,values_offset,len,Some(value_marks));
+    Ok(())
+}
+
+// Merges newer acc with the live entries of the spilled level src into a new level file,
+//   src is read sequentially and is not cached. Returns the level and the folded count,
+//   entries dropped by the filter are counted as folded.
+fn merge_spilled<K,V>(dir: &Path, src: &MapMultiSlot<K,V>, acc_keys: &[K], acc_values: &[V], capacity: usize, op: Option<OpRef<V>>, filter: Option<FilterRef<K,V>>) -> io::Result<(MapMultiSlot<K,V>,usize)>
+where
+    K: Ord + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    let mut out = LevelWriter::create(dir,capacity)?;
+    let mut folded = 0;
+    let mut put = |k: &K, v: &V, folded: &mut usize| match filter.map(|f| f(k,v)) {
+        None | Some(Compaction::Keep) => out.push(k,v),
+        Some(Compaction::Drop) => {
+            *folded += 1;
+            Ok(())
+        },
+        Some(Compaction::Replace(v)) => out.push(k,&v),
+    };
+    let mut j = 0;
+    for (i,(k,v)) in src.keys.reader()?.zip(src.values.reader()?).enumerate() {
+        let (k,v) = (k?,v?);
+        if !src.flags.get(i) { continue; }
+        while (j < acc_keys.len())&&(acc_keys[j] < k) {
+            put(&acc_keys[j],&acc_values[j],&mut folded)?;
+            j += 1;
+        }
+        match op {
+            Some(op) if (j < acc_keys.len())&&(acc_keys[j] == k) => {
+                put(&k,&op(&v,&acc_values[j]),&mut folded)?;
+                folded += 1;
+                j += 1;
+            },
+            _ => put(&k,&v,&mut folded)?,
+        }
+    }
+    for (k,v) in acc_keys[j ..].iter().zip(&acc_values[j ..]) {
+        put(k,v,&mut folded)?;
+    }
+    let (keys,values) = out.finish()?;
+    Ok((MapMultiSlot {
+        capacity,
+        flags: Flags::nulls(capacity),
+        keys,
+        values,
+        tree: Vec::new(),
+        released: None,
+    },folded))
+}
+
+// commutative monoid, aggregated with a segment tree per level
+#[derive(Clone)]
+struct Aggregate<V> {
+    identity: V,
+    op: MergeOp<V>,
+}
+impl<V> Aggregate<V> {
+    fn empty(&self) -> V {
+        (self.op)(&self.identity,&self.identity)
+    }
+}
+
+pub enum Compaction<V> {
+    Keep,
+    Drop,
+    Replace(V),
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Position {
+    Slot(usize),
+    Level(usize,usize),
+}
+
 #[derive(Deserialize)]
 struct SerdeMapMultiSlot<K,V> {
     capacity: usize,
@@ -69,20 +189,17 @@ struct SerdeMapMultiSlot<K,V> {
 }
 impl<K,V> std::convert::TryFrom<SerdeMapMultiSlot<K,V>> for MapMultiSlot<K,V> {
     type Error = String;
-    fn try_from(mut slot: SerdeMapMultiSlot<K,V>) -> Result<MapMultiSlot<K,V>,String> {
+    // buffers are reserved up to the capacity by CivMap::from_parts, after the limits are checked
+    fn try_from(slot: SerdeMapMultiSlot<K,V>) -> Result<MapMultiSlot<K,V>,String> {
         if slot.key_size != std::mem::size_of::<K>() { return Err(format!("Unvalid key size {}, must be {}",std::mem::size_of::<K>(),slot.key_size)); }
         if slot.value_size != std::mem::size_of::<V>() { return Err(format!("Unvalid value size {}, must be {}",std::mem::size_of::<V>(),slot.value_size)); }
-        if (slot.keys.len() > 0) && (slot.keys.len() < slot.capacity) {
-            slot.keys.reserve(slot.capacity - slot.keys.len());
-        }
-        if (slot.values.len() > 0) && (slot.values.len() < slot.capacity) {
-            slot.values.reserve(slot.capacity - slot.values.len());
-        }
         Ok(MapMultiSlot {
             capacity: slot.capacity,
-            flags: Flags(slot.flags),
-            keys: slot.keys,
-            values: slot.values,
+            flags: Flags::from_words(slot.flags),
+            keys: slot.keys.into(),
+            values: slot.values.into(),
+            tree: Vec::new(),
+            released: None,
         })
     }
 }
@@ -97,40 +214,348 @@ where
         state.serialize_field("capacity", &self.capacity)?;
         state.serialize_field("key_size", &std::mem::size_of::<K>())?;
         state.serialize_field("value_size", &std::mem::size_of::<V>())?;
-        state.serialize_field("flags", &self.flags)?;
-        state.serialize_field("keys", &self.keys)?;
-        state.serialize_field("values", &self.values)?;
+        state.serialize_field("flags", &self.kept_flags())?;
+        state.serialize_field("keys", &self.kept(&self.keys))?;
+        state.serialize_field("values", &self.kept(&self.values))?;
         state.end()
     }
 }
 
-#[derive(Debug,Clone,Deserialize)]
+// level since v0.2
+#[derive(Serialize)]
+struct MapMultiSlotRef<'t,K,V> {
+    capacity: u64,
+    flags: Cow<'t,Flags>,
+    keys: Kept<'t,K>,
+    values: Kept<'t,V>,
+}
+#[derive(Deserialize)]
+struct SerdePortableMapMultiSlot<K,V> {
+    capacity: u64,
+    flags: Vec<u64>,
+    keys: Vec<K>,
+    values: Vec<V>,
+}
+#[derive(Deserialize)]
+#[serde(try_from = "SerdePortableMapMultiSlot<K,V>")]
+struct PortableMapMultiSlot<K,V>(MapMultiSlot<K,V>);
+impl<K,V> std::convert::TryFrom<SerdePortableMapMultiSlot<K,V>> for PortableMapMultiSlot<K,V> {
+    type Error = String;
+    fn try_from(slot: SerdePortableMapMultiSlot<K,V>) -> Result<PortableMapMultiSlot<K,V>,String> {
+        let capacity = usize::try_from(slot.capacity).map_err(|_| format!("Unvalid capacity {}",slot.capacity))?;
+        Ok(PortableMapMultiSlot(MapMultiSlot {
+            capacity,
+            flags: Flags::from_words(slot.flags),
+            keys: slot.keys.into(),
+            values: slot.values.into(),
+            tree: Vec::new(),
+            released: None,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
 #[serde(try_from = "SerdeMapMultiSlot<K,V>")]
 pub(crate) struct MapMultiSlot<K,V> {
     capacity: usize,
     flags: Flags,
-    keys: Vec<K>,
-    values: Vec<V>,
+    keys: Column<K>,
+    values: Column<V>,
+    tree: Vec<V>,
+    // dead entries with the value moved out by eager release, they are cut out of the level
+    //   before it is merged, spilled or dropped and are skipped when it is written or cloned
+    released: Option<Flags>,
+}
+impl<K,V> Drop for MapMultiSlot<K,V> {
+    fn drop(&mut self) {
+        self.forget_released();
+    }
+}
+impl<K: Clone, V: Clone> Clone for MapMultiSlot<K,V> {
+    // the tree of a level with released entries is left empty, CivMap::clone rebuilds it
+    fn clone(&self) -> MapMultiSlot<K,V> {
+        match &self.released {
+            None => MapMultiSlot {
+                capacity: self.capacity,
+                flags: self.flags.clone(),
+                keys: self.keys.clone(),
+                values: self.values.clone(),
+                tree: self.tree.clone(),
+                released: None,
+            },
+            Some(_) => MapMultiSlot {
+                capacity: self.capacity,
+                flags: self.kept_flags().into_owned(),
+                keys: self.kept(&self.keys).iter().cloned().collect::<Vec<_>>().into(),
+                values: self.kept(&self.values).iter().cloned().collect::<Vec<_>>().into(),
+                tree: Vec::new(),
+                released: None,
+            },
+        }
+    }
+}
+impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for MapMultiSlot<K,V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MapMultiSlot")
+            .field("capacity", &self.capacity)
+            .field("flags", &self.kept_flags())
+            .field("keys", &self.kept(&self.keys))
+            .field("values", &self.kept(&self.values))
+            .finish()
+    }
+}
+
+// keys or values of a level without the released entries, only levels in memory have released ones
+struct Kept<'t,T> {
+    column: &'t Column<T>,
+    released: Option<&'t Flags>,
+}
+impl<'t,T> Kept<'t,T> {
+    fn len(&self) -> usize {
+        match self.released {
+            Some(released) => self.column.len() - released.count(),
+            None => self.column.len(),
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = &'t T> + '_ {
+        self.column.as_slice().unwrap_or_default().iter().enumerate()
+            .filter(|(i,_)| !self.released.is_some_and(|released| released.get(*i)))
+            .map(|(_,x)| x)
+    }
+}
+impl<'t,T: Serialize> Serialize for Kept<'t,T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.released {
+            None => self.column.serialize(serializer),
+            Some(_) => Items(self.len(),|| self.iter()).serialize(serializer),
+        }
+    }
+}
+impl<'t,T: std::fmt::Debug> std::fmt::Debug for Kept<'t,T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.column.is_spilled() {
+            true => self.column.fmt(f),
+            false => f.debug_list().entries(self.iter()).finish(),
+        }
+    }
 }
+
 impl<K,V> MapMultiSlot<K,V> {
+    fn kept<'t,T>(&'t self, column: &'t Column<T>) -> Kept<'t,T> {
+        Kept {
+            column,
+            released: self.released.as_ref(),
+        }
+    }
+    fn kept_flags(&self) -> Cow<'_,Flags> {
+        match &self.released {
+            Some(released) => Cow::Owned(self.flags.cut(released,self.keys.len())),
+            None => Cow::Borrowed(&self.flags),
+        }
+    }
+    fn portable(&self) -> MapMultiSlotRef<'_,K,V> {
+        MapMultiSlotRef {
+            capacity: self.capacity as u64,
+            flags: self.kept_flags(),
+            keys: self.kept(&self.keys),
+            values: self.kept(&self.values),
+        }
+    }
+    // moves the value of the dead entry idx out, the entry is released;
+    //   the value of a spilled level is read from its file instead
+    fn release(&mut self, idx: usize) -> io::Result<V> {
+        if let Some(v) = self.values.read_owned(idx) {
+            return v;
+        }
+        let capacity = self.capacity;
+        let released = self.released.get_or_insert_with(|| Flags::nulls(capacity));
+        released.set(idx);
+        let values = self.values.heap();
+        // the released value is never read or dropped by the level again, see forget_released
+        Ok(unsafe { std::ptr::read(&values[idx]) })
+    }
+    // cuts the released entries out without dropping their values, the positions are changed
+    fn forget_released(&mut self) -> bool {
+        let released = match self.released.take() {
+            Some(released) => released,
+            None => return false,
+        };
+        let len = self.keys.len();
+        self.flags = self.flags.cut(&released,len);
+        let values = self.values.heap();
+        let mut w = 0;
+        unsafe {
+            // a panic leaks the values instead of dropping the released ones
+            values.set_len(0);
+            let ptr = values.as_mut_ptr();
+            for r in 0 .. len {
+                if released.get(r) { continue; }
+                if r != w {
+                    std::ptr::copy_nonoverlapping(ptr.add(r),ptr.add(w),1);
+                }
+                w += 1;
+            }
+            values.set_len(w);
+        }
+        let mut r = 0;
+        self.keys.heap().retain(|_| { r += 1; !released.get(r-1) });
+        self.tree.clear();
+        true
+    }
+    // a spilled level is read back to be changed, both columns are read before any is replaced
+    fn unspill(&mut self) -> io::Result<()> {
+        let keys = match self.keys.is_spilled() {
+            true => Some(self.keys.read_all()?),
+            false => None,
+        };
+        if self.values.is_spilled() {
+            self.values = self.values.read_all()?.into();
+        }
+        if let Some(keys) = keys {
+            self.keys = keys.into();
+        }
+        Ok(())
+    }
     fn heap_mem(&self) -> usize {
-        self.flags.heap_mem() + self.keys.capacity() * std::mem::size_of::<K>() + self.values.capacity() * std::mem::size_of::<V>()
+        self.flags.heap_mem() + self.keys.capacity() * std::mem::size_of::<K>() + (self.values.capacity() + self.tree.capacity()) * std::mem::size_of::<V>()
+    }
+    fn build_tree(&mut self, agg: &Aggregate<V>) {
+        let m = self.keys.len();
+        self.tree.clear();
+        if m == 0 {
+            return;
+        }
+        // levels of a map with an aggregate are in memory
+        let values = self.values.heap();
+        self.tree.reserve_exact(2*m);
+        for _ in 0 .. m {
+            self.tree.push(agg.empty());
+        }
+        for (i,v) in values.iter().enumerate().take(m) {
+            self.tree.push(match self.flags.get(i) {
+                true => (agg.op)(&agg.identity,v),
+                false => agg.empty(),
+            });
+        }
+        for i in (1 .. m).rev() {
+            self.tree[i] = (agg.op)(&self.tree[2*i],&self.tree[2*i+1]);
+        }
+    }
+    fn update_tree(&mut self, idx: usize, agg: &Aggregate<V>) {
+        let m = self.keys.len();
+        if self.tree.len() != 2*m {
+            return;
+        }
+        let mut p = m + idx;
+        self.tree[p] = match self.flags.get(idx) {
+            true => (agg.op)(&agg.identity,&self.values.heap()[idx]),
+            false => agg.empty(),
+        };
+        while p > 1 {
+            p /= 2;
+            self.tree[p] = (agg.op)(&self.tree[2*p],&self.tree[2*p+1]);
+        }
+    }
+    // empties the leaves [from,to), the ancestors of a range of nodes are a range of the parents
+    fn clear_tree(&mut self, from: usize, to: usize, agg: &Aggregate<V>) {
+        let m = self.keys.len();
+        if (self.tree.len() != 2*m)||(from >= to) {
+            return;
+        }
+        for p in m + from .. m + to {
+            self.tree[p] = agg.empty();
+        }
+        let (mut l,mut r) = ((m + from) / 2,(m + to - 1) / 2);
+        while r >= 1 {
+            for p in std::cmp::max(l,1) ..= r {
+                self.tree[p] = (agg.op)(&self.tree[2*p],&self.tree[2*p+1]);
+            }
+            l /= 2;
+            r /= 2;
+        }
+    }
+    fn query_tree(&self, from: usize, to: usize, agg: &Aggregate<V>, mut acc: V) -> V {
+        let m = self.keys.len();
+        let mut l = from + m;
+        let mut r = to + m;
+        while l < r {
+            if l % 2 == 1 {
+                acc = (agg.op)(&acc,&self.tree[l]);
+                l += 1;
+            }
+            if r % 2 == 1 {
+                r -= 1;
+                acc = (agg.op)(&acc,&self.tree[r]);
+            }
+            l /= 2;
+            r /= 2;
+        }
+        acc
     }
 }
 impl<K: Ord, V> MapMultiSlot<K,V> {
-    pub(crate) fn new(data: Vec<(K,V)>) -> MapMultiSlot<K,V> {
-        let len = data.len();
-        let mut keys = Vec::with_capacity(len);
-        let mut values = Vec::with_capacity(len);
-        for (k,v) in data {
-            keys.push(k);
-            values.push(v);
-        }
+    fn try_new_empty(sz: usize, slot_sz: usize) -> Result<MapMultiSlot<K,V>,TryReserveError> {
+        let cap = level_capacity(slot_sz,sz-1).ok_or_else(capacity_overflow)?;
+        let mut keys = Vec::new();
+        keys.try_reserve_exact(cap)?;
+        let mut values = Vec::new();
+        values.try_reserve_exact(cap)?;
+        Ok(MapMultiSlot {
+            capacity: cap,
+            flags: Flags::try_nulls(cap)?,
+            keys: keys.into(),
+            values: values.into(),
+            tree: Vec::new(),
+            released: None,
+        })
+    }
+    fn read<R: Read>(rdr: &mut R, section: &Section) -> Result<MapMultiSlot<K,V>,SectionError>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        read_section::<PortableMapMultiSlot<K,V>,R>(rdr,section).map(|ms| ms.0)
+    }
+    // Level of a sectioned file with the keys and values left in the file, the reader must be at its offset.
+    //   The section is read through once to check it and to index the columns.
+    fn lazy<R: Read>(rdr: &mut R, section: &Section, file: &Arc<SpillFile>) -> Result<MapMultiSlot<K,V>,SectionError>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        scan_section(rdr,section,|rdr| {
+            let (capacity,flags): (u64,Vec<u64>) = bincode::deserialize_from(&mut *rdr)?;
+            let capacity = usize::try_from(capacity).map_err(|_| bincode::ErrorKind::Custom(format!("Unvalid capacity {}",capacity)))?;
+            let keys_offset = rdr.position();
+            let (len,key_marks) = index_column::<K,_>(rdr)?;
+            let values_offset = rdr.position();
+            let (values_len,value_marks) = index_column::<V,_>(rdr)?;
+            if values_len != len { return Err(Box::new(bincode::ErrorKind::Custom(format!("Unvalid values count {}, must be {}",values_len,len)))); }
+            let (keys,values) = match len {
+                0 => (Column::default(),Column::default()),
+                _ => (Column::spilled(file.clone(),keys_offset,len,Some(key_marks)),Column::spilled(file.clone(),values_offset,len,Some(value_marks))),
+            };
+            Ok(MapMultiSlot {
+                capacity,
+                flags: Flags::from_words(flags),
+                keys,
+                values,
+                tree: Vec::new(),
+                released: None,
+            })
+        })
+    }
+    // level of a run of a compacted file, the entries are live
+    fn run(capacity: usize, keys: Vec<K>, values: Vec<V>) -> MapMultiSlot<K,V> {
+        let mut flags = Flags::nulls(capacity);
+        flags.set_ones(keys.len());
         MapMultiSlot {
-            capacity: len,
-            flags: Flags::ones(len),
-            keys: keys,
-            values: values,
+            capacity,
+            flags,
+            keys: keys.into(),
+            values: values.into(),
+            tree: Vec::new(),
+            released: None,
         }
     }
     fn new_empty(sz: usize, slot_sz: usize) -> MapMultiSlot<K,V> {
@@ -138,63 +563,114 @@ impl<K: Ord, V> MapMultiSlot<K,V> {
         MapMultiSlot {
             capacity: cap,
             flags: Flags::nulls(cap),
-            keys: Vec::with_capacity(cap),
-            values: Vec::with_capacity(cap),
+            keys: Column::default(),
+            values: Column::default(),
+            tree: Vec::new(),
+            released: None,
         }
     }
     fn empty(&self) -> bool {
         self.keys.len() == 0
     }
+    fn section(&self) -> Result<Section,bincode::Error> where K: Serialize, V: Serialize {
+        // the flags follow the capacity
+        let header = file_options().serialized_size(&(self.capacity as u64))?;
+        let keys = header + file_options().serialized_size(&self.kept_flags())?;
+        let values = keys + file_options().serialized_size(&self.kept(&self.keys))?;
+        Section::of(&self.portable(),[keys,values])
+    }
+    fn write<W: Write>(&self, wrt: W) -> Result<(),bincode::Error> where K: Serialize, V: Serialize {
+        file_options().serialize_into(wrt,&self.portable())
+    }
+    fn spilled(&self) -> bool {
+        self.keys.is_spilled() || self.values.is_spilled()
+    }
     fn check_len(&self) -> usize {
-        self.flags.0.iter().fold(0,|acc,x| acc + x.count_ones() as usize)
+        self.flags.count()
     }
-    fn contains(&self, k: &K) -> Option<usize> {
-        if (self.keys.len() == 0)||(*k < self.keys[0])||(*k > self.keys[self.keys.len()-1]) { return None; }
-        match self.keys.binary_search(k) {
+    fn contains(&self, k: &K) -> io::Result<Option<usize>> {
+        if let Some(keys) = self.keys.as_slice() {
+            if keys.is_empty()||(*k < keys[0])||(*k > keys[keys.len()-1]) { return Ok(None); }
+        }
+        Ok(match self.keys.binary_search(k)? {
             Ok(idx) => match self.flags.get(idx) {
                 true => Some(idx),
                 false => None,
             },
             Err(_) => None,
-        }
-    }            
+        })
+    }
+    fn entry<'t>(&'t self, idx: usize, poisoned: &AtomicBool) -> Option<(&'t K, &'t V)> {
+        read_ok(poisoned,self.keys.get(idx).and_then(|k| Ok((k,self.values.get(idx)?))))
+    }
+    fn span<R: RangeBounds<K>>(&self, range: &R) -> io::Result<std::ops::Range<usize>> {
+        try_range_span(self.keys.len(),range,|pred| self.keys.partition_point(pred))
+    }
     fn clear(&mut self) {
+        self.forget_released();
         self.flags.set_nulls();
         self.keys.clear();
         self.values.clear();
+        self.tree.clear();
     }
     fn shrink_to_fit(&mut self) {
         self.keys.shrink_to_fit();
         self.values.shrink_to_fit();
     }
     fn reserve(&mut self, cnt: usize) {
-        self.keys.reserve(cnt);
-        self.values.reserve(cnt);
+        self.keys.heap().reserve(cnt);
+        self.values.heap().reserve(cnt);
     }
     fn drain(&mut self) -> MapMultiSlotDrainIterator<K,V> {
         MapMultiSlotDrainIterator {
-            iter: self.keys.drain(..).zip(self.values.drain(..)),
+            iter: self.keys.heap().drain(..).zip(self.values.heap().drain(..)),
         }
     }
     fn filtered_drain(&mut self) -> MapMultiSlotFilterDrainIterator<K,V> {
         MapMultiSlotFilterDrainIterator {
-            iter: self.keys.drain(..).zip(self.values.drain(..)).enumerate(),
-            flags: &self.flags,
+            iter: self.flags.filter(self.keys.heap().drain(..).zip(self.values.heap().drain(..))),
         }
     }
-    fn filtered_iter(&self) -> MapMultiSlotFilterIterator<K,V> {
+    fn filtered_iter<'t>(&'t self, poisoned: &'t AtomicBool) -> MapMultiSlotFilterIterator<'t,K,V> {
         MapMultiSlotFilterIterator {
-            iter: self.keys.iter().zip(self.values.iter()).enumerate(),
-            flags: &self.flags,
+            keys: &self.keys,
+            values: &self.values,
+            ones: self.flags.iter_ones_range(0,self.keys.len()),
+            poisoned,
+        }
+    }
+    // the flags of the merged level are used to mark the kept entries, so nothing is allocated
+    fn compact(&mut self, filter: &dyn Fn(&K,&V) -> Compaction<V>) -> usize { // dropped count
+        let len = self.keys.len();
+        self.flags.set_ones(len);
+        let (keys,values) = (self.keys.heap(),self.values.heap());
+        for i in 0 .. len {
+            match filter(&keys[i],&values[i]) {
+                Compaction::Keep => {},
+                Compaction::Drop => self.flags.unset(i),
+                Compaction::Replace(v) => values[i] = v,
+            }
         }
+        self.retain_live();
+        self.flags.set_nulls();
+        len - self.keys.len()
+    }
+    // drops dead entries, flags are left as is
+    fn retain_live(&mut self) {
+        if self.flags.count() == self.keys.len() { return; }
+        let flags = &self.flags;
+        let mut idx = 0;
+        self.keys.heap().retain(|_| { idx += 1; flags.get(idx-1) });
+        let mut idx = 0;
+        self.values.heap().retain(|_| { idx += 1; flags.get(idx-1) });
     }
     fn fill_in<'t>(&mut self, iter: &mut std::iter::Zip<std::vec::Drain<'t,K>,std::vec::Drain<'t,V>>) -> bool { // is exhausted
         let mut cur = 0;
         while cur < self.capacity {
             match iter.next() {
                 Some((k,v)) => {
-                    self.keys.push(k);
-                    self.values.push(v);
+                    self.keys.heap().push(k);
+                    self.values.heap().push(v);
                 },
                 None => return true,
             }
@@ -204,39 +680,50 @@ impl<K: Ord, V> MapMultiSlot<K,V> {
     }
 }
 
+// entries of a spilled level which can't be read are skipped and poison the map
 struct MapMultiSlotFilterIterator<'t,K,V> {
-    iter: std::iter::Enumerate<std::iter::Zip<std::slice::Iter<'t,K>,std::slice::Iter<'t,V>>>,
-    flags: &'t Flags,
+    keys: &'t Column<K>,
+    values: &'t Column<V>,
+    ones: Ones<'t>,
+    poisoned: &'t AtomicBool,
+}
+impl<'t,K,V> MapMultiSlotFilterIterator<'t,K,V> {
+    fn entry(&self, n: usize) -> Option<(&'t K, &'t V)> {
+        let (keys,values) = (self.keys,self.values);
+        read_ok(self.poisoned,keys.get(n).and_then(|k| Ok((k,values.get(n)?))))
+    }
 }
 impl<'t,K,V> Iterator for MapMultiSlotFilterIterator<'t,K,V> {
     type Item = (&'t K, &'t V);
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.iter.next() {
-                Some((n,(k,v))) if self.flags.get(n) => break Some((k,v)),
-                Some(_) => continue,
-                None => break None,
+            let n = self.ones.next()?;
+            if let Some(kv) = self.entry(n) {
+                return Some(kv);
+            }
+        }
+    }
+}
+impl<'t,K,V> DoubleEndedIterator for MapMultiSlotFilterIterator<'t,K,V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let n = self.ones.next_back()?;
+            if let Some(kv) = self.entry(n) {
+                return Some(kv);
             }
         }
     }
 }
 
 struct MapMultiSlotFilterDrainIterator<'t,K,V> {
-    iter: std::iter::Enumerate<std::iter::Zip<std::vec::Drain<'t,K>,std::vec::Drain<'t,V>>>,
-    flags: &'t Flags,
+    iter: FilterOnes<'t,std::iter::Zip<std::vec::Drain<'t,K>,std::vec::Drain<'t,V>>>,
 }
 impl<'t,K,V> Iterator for MapMultiSlotFilterDrainIterator<'t,K,V> {
     type Item = (K,V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.iter.next() {
-                Some((n,(k,v))) if self.flags.get(n) => break Some((k,v)),
-                Some(_) => continue,
-                None => break None,
-            }
-        }
+        self.iter.next()
     }
 }
 
@@ -276,9 +763,221 @@ impl<'t,K,V> Iterator for Iter<'t,K,V> {
         None
     }
 }
+impl<'t,K,V> DoubleEndedIterator for Iter<'t,K,V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while !self.data_iter.is_empty() {
+            match self.data_iter[0].next_back() {
+                Some((k,v)) => return Some((k, v)),
+                None => { self.data_iter.remove(0); },
+            }
+        }
+        if let Some(iter) = &mut self.cur_data_iter {
+            if let Some((k,v)) = iter.next_back() {
+                return Some((k, v));
+            }
+        }
+        match &mut self.slot_iter {
+            Some(iter) => iter.next_back().map(|(k,v)| (k, v)),
+            None => None,
+        }
+    }
+}
+
+
+struct MapRuns<'t,K,V> {
+    slot: &'t [(K,V)],
+    order: &'t [usize],
+    data: &'t [MapMultiSlot<K,V>],
+    poisoned: &'t AtomicBool,
+}
+impl<'t,K,V> Runs<K> for MapRuns<'t,K,V> {
+    fn runs(&self) -> usize {
+        self.data.len() + 1
+    }
+    fn run_len(&self, r: usize) -> usize {
+        match r {
+            0 => self.order.len(),
+            _ => self.data[r-1].keys.len(),
+        }
+    }
+    fn key(&self, r: usize, p: usize) -> Option<&K> {
+        match r {
+            0 => Some(&self.slot[self.order[p]].0),
+            _ => read_ok(self.poisoned,self.data[r-1].keys.get(p)),
+        }
+    }
+    fn next_live(&self, r: usize, from: usize) -> usize {
+        match r {
+            0 => std::cmp::min(from,self.order.len()),
+            _ => {
+                let ms = &self.data[r-1];
+                ms.flags.iter_ones_range(from,ms.keys.len()).next().unwrap_or(ms.keys.len())
+            },
+        }
+    }
+    fn prev_live(&self, r: usize, before: usize) -> Option<usize> {
+        match r {
+            0 => before.checked_sub(1),
+            _ => self.data[r-1].flags.prev_one(before),
+        }
+    }
+}
+
+// The copies of a key left by upsert, not yet folded by a merge, are visited one by one
+//   (the newest first), see CivMap::merged_iter.
+pub struct SortedIter<'t,K,V> {
+    map: &'t CivMap<K,V>,
+    order: Vec<usize>,
+    front: Heads,
+    back: Heads,
+    left: usize,
+}
+impl<'t,K: Ord,V> Iterator for SortedIter<'t,K,V> {
+    type Item = (&'t K, &'t V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        let map: &'t CivMap<K,V> = self.map;
+        self.front.next(&map.runs(&self.order)).and_then(|pos| map.entry_at(&self.order,pos))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left,Some(self.left))
+    }
+}
+impl<'t,K: Ord,V> DoubleEndedIterator for SortedIter<'t,K,V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        let map: &'t CivMap<K,V> = self.map;
+        self.back.prev(&map.runs(&self.order)).and_then(|pos| map.entry_at(&self.order,pos))
+    }
+}
+impl<'t,K: Ord,V> ExactSizeIterator for SortedIter<'t,K,V> {}
+
+pub struct Cursor<'t,K,V> {
+    map: &'t CivMap<K,V>,
+    order: Vec<usize>,
+    heads: Heads,
+}
+impl<'t,K: Ord,V> Cursor<'t,K,V> {
+    fn entry(&self, pos: Option<(usize,usize)>) -> Option<(&'t K, &'t V)> {
+        let map: &'t CivMap<K,V> = self.map;
+        pos.and_then(|pos| map.entry_at(&self.order,pos))
+    }
+    pub fn current(&self) -> Option<(&'t K, &'t V)> {
+        self.entry(self.heads.current())
+    }
+    // moves to the first key not less then k
+    pub fn seek(&mut self, k: &K) -> Option<(&'t K, &'t V)> {
+        let pos = self.heads.seek(&self.map.runs(&self.order),k);
+        self.entry(pos)
+    }
+    pub fn seek_first(&mut self) -> Option<(&'t K, &'t V)> {
+        self.heads = Heads::first(&self.map.runs(&self.order));
+        self.next()
+    }
+    pub fn seek_last(&mut self) -> Option<(&'t K, &'t V)> {
+        self.heads = Heads::last(&self.map.runs(&self.order));
+        self.prev()
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&'t K, &'t V)> {
+        let pos = self.heads.next(&self.map.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn prev(&mut self) -> Option<(&'t K, &'t V)> {
+        let pos = self.heads.prev(&self.map.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn peek_next(&self) -> Option<(&'t K, &'t V)> {
+        self.entry(self.heads.peek_next(&self.map.runs(&self.order)))
+    }
+    pub fn peek_prev(&self) -> Option<(&'t K, &'t V)> {
+        self.entry(self.heads.peek_prev(&self.map.runs(&self.order)))
+    }
+}
+
+pub struct CursorMut<'t,K,V> {
+    map: &'t mut CivMap<K,V>,
+    order: Vec<usize>,
+    heads: Heads,
+}
+impl<'t,K: Ord,V> CursorMut<'t,K,V> {
+    fn entry(&self, pos: Option<(usize,usize)>) -> Option<(&K, &V)> {
+        pos.and_then(|pos| self.map.entry_at(&self.order,pos))
+    }
+    pub fn current(&self) -> Option<(&K, &V)> {
+        self.entry(self.heads.current())
+    }
+    pub fn seek(&mut self, k: &K) -> Option<(&K, &V)> {
+        let pos = self.heads.seek(&self.map.runs(&self.order),k);
+        self.entry(pos)
+    }
+    pub fn seek_first(&mut self) -> Option<(&K, &V)> {
+        self.heads = Heads::first(&self.map.runs(&self.order));
+        self.next()
+    }
+    pub fn seek_last(&mut self) -> Option<(&K, &V)> {
+        self.heads = Heads::last(&self.map.runs(&self.order));
+        self.prev()
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(&K, &V)> {
+        let pos = self.heads.next(&self.map.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn prev(&mut self) -> Option<(&K, &V)> {
+        let pos = self.heads.prev(&self.map.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn peek_next(&self) -> Option<(&K, &V)> {
+        self.entry(self.heads.peek_next(&self.map.runs(&self.order)))
+    }
+    pub fn peek_prev(&self) -> Option<(&K, &V)> {
+        self.entry(self.heads.peek_prev(&self.map.runs(&self.order)))
+    }
+    pub fn value_mut(&mut self) -> Option<&mut V> {
+        match self.heads.current()? {
+            (0,p) => Some(&mut self.map.slot.data[self.order[p]].1),
+            (r,p) => {
+                if !self.map.unspill_level(r-1) {
+                    return None;
+                }
+                self.map.refresh_stale();
+                if self.map.aggregate.is_some() {
+                    self.map.stale = Some((r-1,p));
+                }
+                Some(&mut self.map.data[r-1].values.heap()[p])
+            },
+        }
+    }
+    // removes current entry, the cursor is left in the gap between its neighbours
+    pub fn remove_current(&mut self) -> bool {
+        let (r,p) = match self.heads.current() {
+            Some(pos) => pos,
+            None => return false,
+        };
+        match r {
+            0 => {
+                let idx = self.order.remove(p);
+                let last = self.map.slot.len() - 1;
+                self.map.slot.data.swap_remove(idx);
+                for i in self.order.iter_mut() {
+                    if *i == last { *i = idx; }
+                }
+                self.map.len -= 1;
+                self.map.tail = false;
+            },
+            _ => drop(self.map.tombstone(r-1,p)),
+        }
+        self.heads.removed(&self.map.runs(&self.order),r,p);
+        true
+    }
+}
 
 
-const CURRENT_CIVS_MAP_VERSION: (u32,u32) = (0,1);
+const CURRENT_CIVS_MAP_VERSION: (u32,u32) = (0,2);
 
 #[derive(Debug)]
 pub enum CivMapIoError {
@@ -290,6 +989,16 @@ pub enum CivMapIoError {
     ReadData(bincode::Error),
     InvalidHeader,
     InvalidVersion(u32,u32),
+    // key or value type doesn't match the mapped file, or the file is truncated
+    InvalidLayout,
+    // section of the file is damaged or truncated
+    Checksum,
+    // map is broken, found by the strict loading
+    Invalid(CivInvalid),
+    // file exceeds the limits of from_reader_with_limits
+    Limit,
+    // fingerprint of the file is not the expected one, it has none or was written for other types
+    TypeMismatch { expected: u64, found: Option<u64> },
 }
 
 impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Binary for CivMap<K,V> {
@@ -301,74 +1010,682 @@ impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Bin
         }
         std::mem::size_of::<CivMap<K,V>>() + self.slot.heap_mem() + data_mem
     }
+    // the copies of a key left by upsert are combined, the map is written as one run then
     fn into_writer<W: Write>(&self, mut wrt: W) -> Result<(),Self::IoError> {
+        if self.deltas > 0 {
+            return self.into_writer_compacted(wrt,true);
+        }
         let version = CURRENT_CIVS_MAP_VERSION;
         write!(wrt,"CIVM").map_err(|_|CivMapIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivMapIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivMapIoError::WriteHeader)?;
-        bincode::serialize_into(&mut wrt,&self.slot).map_err(CivMapIoError::WriteSlot)?;
-        bincode::serialize_into(&mut wrt,&self.data).map_err(CivMapIoError::WriteData)
-    }
-    fn from_reader<R: Read>(mut rdr: R) -> Result<CivMap<K,V>,Self::IoError> {
-        let mut buf = [0; 4];
-        rdr.read_exact(&mut buf).map_err(|_|CivMapIoError::ReadHeader)?;
-        if buf != "CIVM".as_bytes()[0..4] { return Err(CivMapIoError::InvalidHeader); }
-        let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
-        let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
-        if (maj != 0)||(min != 1) { return Err(CivMapIoError::InvalidVersion(maj,min)); }
-        let slot: Slot<K,V> = bincode::deserialize_from(&mut rdr).map_err(CivMapIoError::ReadSlot)?;
-        let data: Vec<MapMultiSlot<K,V>> = bincode::deserialize_from(&mut rdr).map_err(CivMapIoError::ReadData)?;
-        let mut len = slot.len();
-        let mut tombs = 0;
-        for ms in &data {
-            let ln = ms.check_len();
-            len += ln;
-            tombs += ms.capacity - ln;
-        }
-        Ok(CivMap {
-            len: len,
-            tombs: tombs,
-            slot: slot,
-            data: data,
-            
-            tmp_merge_keys: Vec::new(),
-            tmp_merge_values: Vec::new(),
-        })
+        let dir = self.directory().map_err(CivMapIoError::WriteData)?;
+        dir.write(&mut wrt).map_err(|_|CivMapIoError::WriteHeader)?;
+        self.slot.write(&mut wrt).map_err(CivMapIoError::WriteSlot)?;
+        for ms in &self.data {
+            ms.write(&mut wrt).map_err(CivMapIoError::WriteData)?;
+        }
+        Ok(())
+    }
+    fn from_reader<R: Read>(rdr: R) -> Result<CivMap<K,V>,Self::IoError> {
+        CivMap::from_reader_with_limits(rdr,CivLimits::default())
     }
 }
 
+// version of the map file
+fn read_map_header<R: Read>(rdr: &mut R) -> Result<(u32,u32),CivMapIoError> {
+    let mut buf = [0; 4];
+    rdr.read_exact(&mut buf).map_err(|_|CivMapIoError::ReadHeader)?;
+    if buf != "CIVM".as_bytes()[0..4] { return Err(CivMapIoError::InvalidHeader); }
+    let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
+    let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
+    match is_file_version((maj,min)) {
+        true => Ok((maj,min)),
+        false => Err(CivMapIoError::InvalidVersion(maj,min)),
+    }
+}
 
+fn check_fingerprint<K,V>(found: Option<Fingerprint>, expected: Expected) -> Result<Option<u64>,CivMapIoError> {
+    Fingerprint::check::<K,V>(found,expected).map_err(|(expected,found)| CivMapIoError::TypeMismatch { expected, found })
+}
 
-#[derive(Clone)]
-pub struct CivMap<K,V> {
-    len: usize,
-    tombs: usize,
-    slot: Slot<K,V>,
-    data: Vec<MapMultiSlot<K,V>>,
-
-    tmp_merge_keys: Vec<K>,
-    tmp_merge_values: Vec<V>,
+// run of a compacted file
+#[derive(Serialize)]
+struct RunRef<KS,VS> {
+    keys: KS,
+    values: VS,
 }
-impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for CivMap<K,V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("CivMap")
-            .field("len", &self.len)
-            .field("tombs", &self.tombs)
-            .field("slot", &self.slot)
-            .field("data", &self.data)
-            .finish()
+#[derive(Deserialize)]
+struct SerdeRun<K,V> {
+    keys: Vec<K>,
+    values: Vec<V>,
+}
+
+// the header is written
+fn write_runs<W,K,V,KS,VS>(mut wrt: W, fingerprint: Fingerprint, compacted: Compacted, slot: &SlotRef<K,V>, runs: &[RunRef<KS,VS>]) -> Result<(),CivMapIoError>
+where
+    W: Write,
+    K: Serialize,
+    V: Serialize,
+    KS: Serialize,
+    VS: Serialize,
+{
+    let mut levels = Vec::with_capacity(runs.len());
+    for run in runs {
+        let values = file_options().serialized_size(&run.keys).map_err(CivMapIoError::WriteData)?;
+        levels.push(Section::of(run,[0,values]).map_err(CivMapIoError::WriteData)?);
     }
-}     
-impl<K: Ord, V> CivMap<K,V> {
-    pub fn new() -> CivMap<K,V> {
-        CivMap {
-            len: 0,
-            tombs: 0,
-            slot: Slot::new(),
-            data: Vec::new(),
+    let slot_section = slot.section().map_err(CivMapIoError::WriteSlot)?;
+    let dir = Directory::new(fingerprint,compacted,slot_section,levels).map_err(CivMapIoError::WriteData)?;
+    dir.write(&mut wrt).map_err(|_|CivMapIoError::WriteHeader)?;
+    file_options().serialize_into(&mut wrt,slot).map_err(CivMapIoError::WriteSlot)?;
+    for run in runs {
+        file_options().serialize_into(&mut wrt,run).map_err(CivMapIoError::WriteData)?;
+    }
+    Ok(())
+}
 
-            tmp_merge_keys: Vec::new(),
-            tmp_merge_values: Vec::new(),
+// levels of a compacted file, the run of Compacted::Single is spread over the slot and full levels
+fn read_runs<K,V,R>(rdr: &mut R, dir: &Directory, slot: &mut Slot<K,V>, limits: &CivLimits, entries: &mut usize) -> Result<Vec<MapMultiSlot<K,V>>,CivMapIoError>
+where
+    K: Ord + DeserializeOwned,
+    V: DeserializeOwned,
+    R: Read,
+{
+    let slot_size = slot.max_size();
+    if slot_size == 0 { return Err(CivMapIoError::Invalid(CivInvalid::Slot)); }
+    if (dir.compacted == Compacted::Single) && (dir.levels.len() > 1) { return Err(CivMapIoError::InvalidHeader); }
+    let mut data = Vec::with_capacity(dir.levels.len());
+    for (level,section) in dir.levels.iter().enumerate() {
+        let run: SerdeRun<K,V> = read_section(rdr,section).map_err(|e| e.or(CivMapIoError::Checksum,CivMapIoError::Limit,CivMapIoError::ReadData))?;
+        let (mut keys,mut values) = (run.keys,run.values);
+        if keys.len() != values.len() {
+            return Err(CivMapIoError::Invalid(CivInvalid::Columns { level, keys: keys.len(), values: values.len() }));
+        }
+        if dir.compacted == Compacted::Levels {
+            let capacity = level_capacity(slot_size,level).ok_or(CivMapIoError::InvalidHeader)?;
+            if keys.len() > capacity {
+                return Err(CivMapIoError::Invalid(CivInvalid::Columns { level, keys: keys.len(), values: values.len() }));
+            }
+            if !within_limits(limits,entries,capacity,keys.len()) { return Err(CivMapIoError::Limit); }
+            data.push(MapMultiSlot::run(capacity,keys,values));
+            continue;
+        }
+        let (rest,lens) = single_run_parts(keys.len(),slot_size);
+        if slot.len() + rest > slot_size { return Err(CivMapIoError::Invalid(CivInvalid::Slot)); }
+        slot.data.extend(keys.drain(keys.len() - rest ..).zip(values.drain(values.len() - rest ..)));
+        // the greatest keys go to the smallest levels, the largest one keeps the buffers
+        let top = lens.len().saturating_sub(1);
+        for (level,len) in lens.into_iter().enumerate() {
+            let capacity = slot_size << level;
+            if !within_limits(limits,entries,capacity,len) { return Err(CivMapIoError::Limit); }
+            let (k,v) = match level == top {
+                true => (std::mem::take(&mut keys),std::mem::take(&mut values)),
+                false => (keys.split_off(keys.len() - len),values.split_off(values.len() - len)),
+            };
+            data.push(MapMultiSlot::run(capacity,k,v));
+        }
+    }
+    Ok(data)
+}
+
+fn read_map_directory<R: Read>(rdr: &mut R) -> Result<Directory,CivMapIoError> {
+    match Directory::read(rdr).map_err(|e| limit_or(e,CivMapIoError::Limit,|_|CivMapIoError::ReadHeader))? {
+        Some(dir) => Ok(dir),
+        None => Err(CivMapIoError::InvalidHeader),
+    }
+}
+
+impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> CivMap<K,V> {
+    fn directory(&self) -> Result<Directory,bincode::Error> {
+        let mut levels = Vec::with_capacity(self.data.len());
+        for ms in &self.data {
+            levels.push(ms.section()?);
+        }
+        Directory::new(Fingerprint::new::<K,V>(self.fingerprint),Compacted::No,self.slot.section()?,levels)
+    }
+    // Writes the live entries only, the file is read by from_reader. The levels keep their live entries,
+    //   or with single_level (and always while upserted deltas are pending) all the entries, the deltas
+    //   combined, are written as one run, which is spread over the slot and full levels on loading.
+    //   The map is not changed.
+    pub fn into_writer_compacted<W: Write>(&self, mut wrt: W, single_level: bool) -> Result<(),CivMapIoError> {
+        let single_level = single_level || (self.deltas > 0);
+        let version = CURRENT_CIVS_MAP_VERSION;
+        let fingerprint = Fingerprint::new::<K,V>(self.fingerprint);
+        write!(wrt,"CIVM").map_err(|_|CivMapIoError::WriteHeader)?;
+        wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivMapIoError::WriteHeader)?;
+        wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivMapIoError::WriteHeader)?;
+        if single_level {
+            // the merged run is encoded for the directory and to the file, it's merged once
+            let (keys,values): (Vec<_>,Vec<_>) = self.merged_iter().unzip();
+            let slot: SlotRef<K,V> = SlotRef { size: self.slot.max_size() as u64, data: &[] };
+            let runs = match keys.len() {
+                0 => Vec::new(),
+                _ => vec![RunRef { keys, values }],
+            };
+            return write_runs(wrt,fingerprint,Compacted::Single,&slot,&runs);
+        }
+        let runs = self.data.iter().map(|ms| RunRef {
+            keys: Items(ms.check_len(),|| ms.filtered_iter(&self.poisoned).map(|(k,_)| k)),
+            values: Items(ms.check_len(),|| ms.filtered_iter(&self.poisoned).map(|(_,v)| v)),
+        }).collect::<Vec<_>>();
+        write_runs(wrt,fingerprint,Compacted::Levels,&self.slot.portable(),&runs)
+    }
+    // from_reader of an untrusted file, reading more bytes or entries than the limits or a larger level
+    //   is CivMapIoError::Limit. Allocations are bounded by the limits.
+    pub fn from_reader_with_limits<R: Read>(rdr: R, limits: CivLimits) -> Result<CivMap<K,V>,CivMapIoError> {
+        CivMap::read_from(rdr,limits,Expected::Layout)
+    }
+    // from_reader of a file written with the fingerprint set by set_fingerprint
+    pub fn from_reader_with_fingerprint<R: Read>(rdr: R, fingerprint: u64) -> Result<CivMap<K,V>,CivMapIoError> {
+        CivMap::read_from(rdr,CivLimits::default(),Expected::User(fingerprint))
+    }
+    // from_reader without the check of the type fingerprint, for files of types with another layout
+    //   which still decode to the same values. Nothing tells a file of other types apart then.
+    pub fn from_reader_unchecked<R: Read>(rdr: R) -> Result<CivMap<K,V>,CivMapIoError> {
+        CivMap::read_from(rdr,CivLimits::default(),Expected::Unchecked)
+    }
+    fn read_from<R: Read>(rdr: R, limits: CivLimits, expected: Expected) -> Result<CivMap<K,V>,CivMapIoError> {
+        let mut rdr = Bounded::new(rdr,limits.bytes);
+        let mut entries = 0;
+        let (found,slot,data) = match read_map_header(&mut rdr)? {
+            (0,1) => {
+                let slot: Slot<K,V> = bincode::deserialize_from(&mut rdr).map_err(|e| limit_or(e,CivMapIoError::Limit,CivMapIoError::ReadSlot))?;
+                if !within_limits(&limits,&mut entries,slot.max_size(),slot.len()) { return Err(CivMapIoError::Limit); }
+                let data: Vec<MapMultiSlot<K,V>> = bincode::deserialize_from(&mut rdr).map_err(|e| limit_or(e,CivMapIoError::Limit,CivMapIoError::ReadData))?;
+                for ms in &data {
+                    if !within_limits(&limits,&mut entries,ms.capacity,ms.keys.len()) { return Err(CivMapIoError::Limit); }
+                }
+                (check_fingerprint::<K,V>(None,expected)?,slot,data)
+            },
+            _ => {
+                let dir = read_map_directory(&mut rdr)?;
+                let found = check_fingerprint::<K,V>(Some(dir.fingerprint),expected)?;
+                let slot: Slot<K,V> = read_slot(&mut rdr,&dir.slot).map_err(|e| e.or(CivMapIoError::Checksum,CivMapIoError::Limit,CivMapIoError::ReadSlot))?;
+                if !within_limits(&limits,&mut entries,slot.max_size(),slot.len()) { return Err(CivMapIoError::Limit); }
+                if dir.compacted != Compacted::No {
+                    let mut slot = slot;
+                    let data = read_runs(&mut rdr,&dir,&mut slot,&limits,&mut entries)?;
+                    (found,slot,data)
+                } else {
+                    let mut data = Vec::with_capacity(dir.levels.len());
+                    for section in &dir.levels {
+                        let ms = MapMultiSlot::read(&mut rdr,section).map_err(|e| e.or(CivMapIoError::Checksum,CivMapIoError::Limit,CivMapIoError::ReadData))?;
+                        if !within_limits(&limits,&mut entries,ms.capacity,ms.keys.len()) { return Err(CivMapIoError::Limit); }
+                        data.push(ms);
+                    }
+                    (found,slot,data)
+                }
+            },
+        };
+        let mut map = CivMap::from_parts(slot,data);
+        map.fingerprint = found;
+        Ok(map)
+    }
+    // from_reader_with_limits checking the loaded map with validate
+    pub fn from_reader_strict<R: Read>(rdr: R, limits: CivLimits) -> Result<CivMap<K,V>,CivMapIoError> {
+        let map = CivMap::from_reader_with_limits(rdr,limits)?;
+        map.validate().map_err(CivMapIoError::Invalid)?;
+        Ok(map)
+    }
+    // Reads the slot and the level headers of a sectioned file, keys and values of the levels are read
+    //   from the file entry by entry (as the spilled ones). The file must not be changed while the map is
+    //   in use. The file is read through once to verify the checksums and to index the columns. Files
+    //   of v0.1 and compacted ones are read at once.
+    pub fn open_lazy<P: AsRef<Path>>(path: P) -> Result<CivMap<K,V>,CivMapIoError> {
+        let path = path.as_ref();
+        let mut rdr = BufReader::new(std::fs::File::open(path).map_err(|e| CivMapIoError::ReadData(e.into()))?);
+        let version = read_map_header(&mut rdr)?;
+        if version == (0,1) {
+            rdr.seek(SeekFrom::Start(0)).map_err(|_|CivMapIoError::ReadHeader)?;
+            return CivMap::from_reader(rdr);
+        }
+        let dir = read_map_directory(&mut rdr)?;
+        if dir.compacted != Compacted::No {
+            rdr.seek(SeekFrom::Start(0)).map_err(|_|CivMapIoError::ReadHeader)?;
+            return CivMap::from_reader(rdr);
+        }
+        let found = check_fingerprint::<K,V>(Some(dir.fingerprint),Expected::Layout)?;
+        let slot: Slot<K,V> = read_slot(&mut rdr,&dir.slot).map_err(|e| e.or(CivMapIoError::Checksum,CivMapIoError::Limit,CivMapIoError::ReadSlot))?;
+        let file = Arc::new(SpillFile::open(path));
+        let mut data = Vec::with_capacity(dir.levels.len());
+        for section in &dir.levels {
+            data.push(MapMultiSlot::lazy(&mut rdr,section,&file).map_err(|e| e.or(CivMapIoError::Checksum,CivMapIoError::Limit,CivMapIoError::ReadData))?);
+        }
+        let mut map = CivMap::from_parts(slot,data);
+        map.fingerprint = found;
+        Ok(map)
+    }
+}
+
+
+
+// value of a key with the upserted deltas combined, or the stored one of a single copy
+pub enum MergedValue<'t,V> {
+    Ref(&'t V),
+    Owned(V),
+}
+impl<'t,V: Clone> MergedValue<'t,V> {
+    pub fn into_owned(self) -> V {
+        match self {
+            MergedValue::Ref(v) => v.clone(),
+            MergedValue::Owned(v) => v,
+        }
+    }
+}
+impl<'t,V> AsRef<V> for MergedValue<'t,V> {
+    fn as_ref(&self) -> &V {
+        match self {
+            MergedValue::Ref(v) => v,
+            MergedValue::Owned(v) => v,
+        }
+    }
+}
+impl<'t,V: Serialize> Serialize for MergedValue<'t,V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MergedValue::Ref(v) => v.serialize(serializer),
+            MergedValue::Owned(v) => v.serialize(serializer),
+        }
+    }
+}
+
+impl<K: Ord, V> CivMap<K,V> {
+    // live entries in key order, one per key: the copies of a key left by upsert are combined
+    //   with the merge op, which sorted_iter visits one by one
+    pub fn merged_iter(&self) -> impl Iterator<Item = (&K,MergedValue<'_,V>)> + '_ {
+        let op = self.merge_op.clone();
+        let mut iter = self.sorted_iter().peekable();
+        // occurrences of a key, the newest first
+        let mut group = Vec::new();
+        std::iter::from_fn(move || {
+            let (k,v) = iter.next()?;
+            group.clear();
+            group.push(v);
+            while let Some((_,v)) = iter.next_if(|(x,_)| *x == k) {
+                group.push(v);
+            }
+            let v = match &op {
+                Some(op) if group.len() > 1 => {
+                    let n = group.len();
+                    let acc = op(group[n-1],group[n-2]);
+                    MergedValue::Owned(group[.. n-2].iter().rev().fold(acc,|acc,v| op(&acc,v)))
+                },
+                _ => MergedValue::Ref(group[0]),
+            };
+            Some((k,v))
+        })
+    }
+    // keys counted once, len while upserted deltas are pending
+    fn logical_len(&self) -> usize {
+        let mut last = None;
+        self.sorted_iter().filter(|(k,_)| last.replace(*k) != Some(*k)).count()
+    }
+}
+
+// map of the live entries
+impl<K: Ord + Serialize, V: Serialize> Serialize for CivMap<K,V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k,v) in self.merged_iter() {
+            map.serialize_entry(k,&v)?;
+        }
+        map.end()
+    }
+}
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for CivMap<K,V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CivMap<K,V>, D::Error> {
+        struct MapVisitor<K,V>(std::marker::PhantomData<(K,V)>);
+        impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for MapVisitor<K,V> {
+            type Value = CivMap<K,V>;
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a map")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<CivMap<K,V>, A::Error> {
+                let mut map = CivMap::new();
+                // entries are serialized in key order
+                while let Some((k,v)) = access.next_entry()? {
+                    map.push_back(k,v);
+                }
+                Ok(map)
+            }
+        }
+        deserializer.deserialize_map(MapVisitor(std::marker::PhantomData))
+    }
+}
+
+// slot and levels as in the v0.2 files
+#[derive(Serialize)]
+#[serde(rename = "CivMap")]
+struct LayoutRef<'t,K,V> {
+    fingerprint: Option<u64>,
+    deltas: u64,
+    slot: SlotRef<'t,K,V>,
+    data: Vec<MapMultiSlotRef<'t,K,V>>,
+}
+#[derive(Deserialize)]
+#[serde(rename = "CivMap")]
+struct SerdeLayout<K,V> {
+    fingerprint: Option<u64>,
+    deltas: u64,
+    slot: PortableSlot<K,V>,
+    data: Vec<PortableMapMultiSlot<K,V>>,
+}
+impl<K: Serialize, V: Serialize> Serialize for Layout<&CivMap<K,V>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LayoutRef {
+            fingerprint: self.0.fingerprint,
+            deltas: self.0.deltas as u64,
+            slot: self.0.slot.portable(),
+            data: self.0.data.iter().map(|ms| ms.portable()).collect(),
+        }.serialize(serializer)
+    }
+}
+impl<K: Serialize, V: Serialize> Serialize for Layout<CivMap<K,V>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Layout(&self.0).serialize(serializer)
+    }
+}
+// the loaded map is checked by validate, the copies of a key left by upsert are kept
+//   and combined once the merge op is set again (see set_merge_op)
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Layout<CivMap<K,V>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Layout<CivMap<K,V>>, D::Error> {
+        let layout = SerdeLayout::deserialize(deserializer)?;
+        let mut map = CivMap::from_parts(layout.slot.0,layout.data.into_iter().map(|ms| ms.0).collect());
+        map.fingerprint = layout.fingerprint;
+        map.deltas = usize::try_from(layout.deltas).map_err(|_| D::Error::custom(format!("Unvalid deltas {}",layout.deltas)))?;
+        map.validate().map_err(|e| D::Error::custom(format!("invalid map: {:?}",e)))?;
+        Ok(Layout(map))
+    }
+}
+
+// drops the levels of a merge left by an error or a panic of user code
+struct Interrupted<'t,K: Ord,V> {
+    map: &'t mut CivMap<K,V>,
+    n: usize,
+}
+impl<K: Ord,V> Drop for Interrupted<'_,K,V> {
+    fn drop(&mut self) {
+        self.map.drop_merged(self.n);
+    }
+}
+
+pub struct CivMap<K,V> {
+    // live entries, the copies of a key left by upsert are counted until a merge folds them
+    len: usize,
+    tombs: usize,
+    // upserted entries which may have an older copy in the levels, len is exact if none
+    deltas: usize,
+    slot: Slot<K,V>,
+    data: Vec<MapMultiSlot<K,V>>,
+
+    merge_op: Option<MergeOp<V>>,
+    filter: Option<CompactionFilter<K,V>>,
+    // slot is sorted and all its keys are greater then the keys of data
+    tail: bool,
+    aggregate: Option<Aggregate<V>>,
+    // level value given out by the last get_mut, not yet updated in its aggregate tree
+    stale: Option<(usize,usize)>,
+    // values of the removed level entries are moved out at once
+    release: bool,
+    spill: Option<Spill<K,V>>,
+    // a merge was interrupted by a panic of user code (Ord, merge op, filter, aggregate)
+    //   or a spilled level can't be read
+    poisoned: AtomicBool,
+    // written with the layout fingerprint
+    fingerprint: Option<u64>,
+}
+impl<K: Clone, V: Clone> Clone for CivMap<K,V> {
+    fn clone(&self) -> CivMap<K,V> {
+        let mut data = self.data.clone();
+        let mut stale = self.stale;
+        if let Some(agg) = &self.aggregate {
+            // the levels with released entries are cloned without them and get a new tree
+            for (msi,ms) in data.iter_mut().enumerate() {
+                if ms.tree.len() != 2*ms.keys.len() {
+                    ms.build_tree(agg);
+                    if stale.is_some_and(|(smsi,_)| smsi == msi) {
+                        stale = None;
+                    }
+                }
+            }
+        }
+        CivMap {
+            len: self.len,
+            tombs: self.tombs,
+            deltas: self.deltas,
+            slot: self.slot.clone(),
+            data,
+
+            merge_op: self.merge_op.clone(),
+            filter: self.filter.clone(),
+            tail: self.tail,
+            aggregate: self.aggregate.clone(),
+            stale,
+            release: self.release,
+            spill: self.spill.clone(),
+            poisoned: AtomicBool::new(self.poisoned.load(Ordering::Relaxed)),
+            fingerprint: self.fingerprint,
+        }
+    }
+}
+impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for CivMap<K,V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CivMap")
+            .field("len", &self.len)
+            .field("tombs", &self.tombs)
+            .field("slot", &self.slot)
+            .field("data", &self.data)
+            .finish()
+    }
+}     
+impl<K: Ord, V> CivMap<K,V> {
+    fn from_parts(slot: Slot<K,V>, mut data: Vec<MapMultiSlot<K,V>>) -> CivMap<K,V> {
+        let mut len = slot.len();
+        let mut tombs = 0;
+        for ms in &mut data {
+            let ln = ms.keys.len();
+            if (ln > 0) && (ln < ms.capacity) && !ms.spilled() {
+                ms.reserve(ms.capacity - ln);
+            }
+            let ln = ms.check_len();
+            len += ln;
+            // empty levels have no tombs, a broken level is reported by validate
+            if !ms.empty() {
+                tombs += ms.capacity.saturating_sub(ln);
+            }
+        }
+        CivMap {
+            len,
+            tombs,
+            deltas: 0,
+            slot,
+            data,
+
+            merge_op: None,
+            filter: None,
+            tail: false,
+            aggregate: None,
+            stale: None,
+            release: false,
+            spill: None,
+            poisoned: AtomicBool::new(false),
+            fingerprint: None,
+        }
+    }
+    pub fn new() -> CivMap<K,V> {
+        CivMap {
+            len: 0,
+            tombs: 0,
+            deltas: 0,
+            slot: Slot::new(),
+            data: Vec::new(),
+
+            merge_op: None,
+            filter: None,
+            tail: false,
+            aggregate: None,
+            stale: None,
+            release: false,
+            spill: None,
+            poisoned: AtomicBool::new(false),
+            fingerprint: None,
+        }
+    }
+    // op(older,newer) must be associative, deltas are combined lazily (see upsert)
+    pub fn with_merge_op<F>(op: F) -> CivMap<K,V>
+    where F: Fn(&V,&V) -> V + Send + Sync + 'static
+    {
+        let mut map = CivMap::new();
+        map.merge_op = Some(Arc::new(op));
+        map
+    }
+    // merge op of a map read with Layout, which doesn't keep it
+    pub fn set_merge_op<F>(&mut self, op: F)
+    where F: Fn(&V,&V) -> V + Send + Sync + 'static
+    {
+        self.merge_op = Some(Arc::new(op));
+    }
+    // called for every surviving entry of a merged level
+    pub fn set_compaction_filter<F>(&mut self, filter: F)
+    where F: Fn(&K,&V) -> Compaction<V> + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(filter));
+    }
+    pub fn remove_compaction_filter(&mut self) {
+        self.filter = None;
+    }
+    // Fingerprint of the key and value types written to files with the one of their layout,
+    //   e.g. a hash of a schema. Such files are read by from_reader_with_fingerprint.
+    pub fn set_fingerprint(&mut self, fingerprint: Option<u64>) {
+        self.fingerprint = fingerprint;
+    }
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+    // values removed from levels are moved out at once, so remove always returns RemovedItem::Owned
+    pub fn set_eager_release(&mut self) {
+        self.release = true;
+    }
+    // Keeps the heap of the levels within budget bytes: after merges the largest levels are written
+    //   to files in dir, they are read back on access and cached until the budget is exceeded again.
+    //   Merges of a spilled level stream it from disk. Levels of a map with an aggregate are not spilled.
+    pub fn set_memory_budget<P: Into<PathBuf>>(&mut self, budget: usize, dir: P)
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        self.spill = Some(Spill {
+            budget,
+            dir: dir.into(),
+            write: spill_level,
+            merge: merge_spilled,
+        });
+        self.spill_levels();
+    }
+    // reads all the spilled levels back, the ones which can't be read are dropped and poison the map
+    pub fn remove_memory_budget(&mut self) {
+        self.spill = None;
+        for msi in 0 .. self.data.len() {
+            if self.read_back(msi) {
+                *self.poisoned.get_mut() = true;
+            }
+        }
+    }
+    // op must be associative and commutative (as sum, min or max), identity is the neutral element.
+    //   Keys of a range are spread over the slot and the levels, aggregate combines the slot values
+    //   and the tree nodes of the levels as it finds them, not in key order, so a non-commutative op
+    //   (e.g. concatenation) would get its operands out of order.
+    pub fn set_aggregate<F>(&mut self, identity: V, op: F)
+    where F: Fn(&V,&V) -> V + Send + Sync + 'static
+    {
+        let agg = Aggregate {
+            identity,
+            op: Arc::new(op),
+        };
+        // levels of a map with an aggregate are kept in memory
+        for msi in 0 .. self.data.len() {
+            if self.read_back(msi) {
+                *self.poisoned.get_mut() = true;
+            }
+        }
+        for ms in &mut self.data {
+            ms.build_tree(&agg);
+        }
+        self.aggregate = Some(agg);
+        self.stale = None;
+    }
+    pub fn remove_aggregate(&mut self) {
+        self.aggregate = None;
+        self.stale = None;
+        for ms in &mut self.data {
+            ms.tree = Vec::new();
+        }
+    }
+    // None if there is no aggregate set
+    pub fn aggregate<R: RangeBounds<K>>(&self, range: R) -> Option<V> {
+        let agg = self.aggregate.as_ref()?;
+        let mut acc = agg.empty();
+        for (k,v) in self.slot.iter() {
+            if range.contains(k) {
+                acc = (agg.op)(&acc,v);
+            }
+        }
+        for (msi,ms) in self.data.iter().enumerate() {
+            // levels of a map with an aggregate are in memory
+            let (keys,values) = match (ms.keys.as_slice(),ms.values.as_slice()) {
+                (Some(keys),Some(values)) => (keys,values),
+                _ => continue,
+            };
+            let span = range_span(keys,&range);
+            acc = match self.stale {
+                // the tree nodes covering the stale value are skipped, the value is taken as is
+                Some((smsi,idx)) if (smsi == msi) && span.contains(&idx) => {
+                    let acc = ms.query_tree(span.start,idx,agg,acc);
+                    let acc = ms.query_tree(idx + 1,span.end,agg,acc);
+                    match ms.flags.get(idx) {
+                        true => (agg.op)(&acc,&values[idx]),
+                        false => acc,
+                    }
+                },
+                _ => ms.query_tree(span.start,span.end,agg,acc),
+            };
+        }
+        Some(acc)
+    }
+    // reads a spilled level back to change it, the map is poisoned if it can't be read
+    fn unspill_level(&mut self, msi: usize) -> bool {
+        match self.data[msi].unspill() {
+            Ok(()) => true,
+            Err(_) => {
+                *self.poisoned.get_mut() = true;
+                false
+            },
+        }
+    }
+    // reads a spilled level back to merge it in memory, a level which can't be read is dropped
+    //   (the caller poisons the map). Returns if it's dropped.
+    fn read_back(&mut self, msi: usize) -> bool {
+        let ms = &mut self.data[msi];
+        if ms.unspill().is_ok() {
+            return false;
+        }
+        let live = ms.check_len();
+        self.len -= live;
+        if !ms.empty() {
+            self.tombs -= ms.capacity - live;
+        }
+        ms.clear();
+        true
+    }
+    fn refresh_stale(&mut self) {
+        if let (Some(agg),Some((msi,idx))) = (&self.aggregate,self.stale.take()) {
+            self.data[msi].update_tree(idx,agg);
+        }
+    }
+    fn update_aggregate(&mut self, msi: usize, idx: usize) {
+        if let Some(agg) = &self.aggregate {
+            self.data[msi].update_tree(idx,agg);
         }
     }
 
@@ -377,7 +1694,7 @@ impl<K: Ord, V> CivMap<K,V> {
             let mut v = Vec::new();
             let n = self.data.len();
             for i in 0 .. n {
-                v.push(self.data[n-i-1].filtered_iter());
+                v.push(self.data[n-i-1].filtered_iter(&self.poisoned));
             }
             v
         };
@@ -388,13 +1705,72 @@ impl<K: Ord, V> CivMap<K,V> {
         }
     }
 
+    fn runs<'t>(&'t self, order: &'t [usize]) -> MapRuns<'t,K,V> {
+        MapRuns {
+            slot: &self.slot.data,
+            order,
+            data: &self.data,
+            poisoned: &self.poisoned,
+        }
+    }
+    // None if the entry of a spilled level can't be read
+    fn entry_at(&self, order: &[usize], (r,p): (usize,usize)) -> Option<(&K, &V)> {
+        match r {
+            0 => {
+                let (k,v) = &self.slot.data[order[p]];
+                Some((k,v))
+            },
+            _ => self.data[r-1].entry(p,&self.poisoned),
+        }
+    }
+    pub fn sorted_iter(&self) -> SortedIter<'_,K,V> {
+        self.range(..)
+    }
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SortedIter<'_,K,V> {
+        let order = sorted_order(&self.slot.data);
+        let (front,back) = Heads::range(&self.runs(&order),&range);
+        SortedIter {
+            map: self,
+            order,
+            front,
+            back,
+            left: self.count_range((range.start_bound(),range.end_bound())),
+        }
+    }
+    // positioned before the first entry
+    pub fn cursor(&self) -> Cursor<'_,K,V> {
+        let order = sorted_order(&self.slot.data);
+        let heads = Heads::first(&self.runs(&order));
+        Cursor {
+            map: self,
+            order,
+            heads,
+        }
+    }
+    pub fn cursor_mut(&mut self) -> CursorMut<'_,K,V> {
+        let order = sorted_order(&self.slot.data);
+        let heads = Heads::first(&self.runs(&order));
+        CursorMut {
+            map: self,
+            order,
+            heads,
+        }
+    }
+
+    // The map lost entries because of a panic during a merge (the levels being merged are dropped, the map
+    //   stays usable), or a spilled level can't be read: its entries are skipped by reads and the level is
+    //   dropped by the next merge. clear() resets it.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
     pub fn clear(&mut self) {
         self.len = 0;
         self.tombs = 0;
+        self.deltas = 0;
         self.slot.clear();
         self.data.clear();
-        self.tmp_merge_keys.clear();
-        self.tmp_merge_values.clear();
+        self.stale = None;
+        *self.poisoned.get_mut() = false;
     }
     
     pub fn contains(&self, k: &K) -> bool {
@@ -403,87 +1779,560 @@ impl<K: Ord, V> CivMap<K,V> {
             None => self.multy_contains(k).is_some(),
         }
     }    
+    // levels which can't be read are skipped
     fn multy_contains(&self, k: &K) -> Option<(usize,usize)> {
         for (n,ms) in self.data.iter().enumerate() {
-            if let Some(idx) = ms.contains(k) {
+            if let Some(Some(idx)) = read_ok(&self.poisoned,ms.contains(k)) {
                 return Some((n,idx));
             }
         }
         None
     }
+    fn try_multy_contains(&self, k: &K) -> io::Result<Option<(usize,usize)>> {
+        for (n,ms) in self.data.iter().enumerate() {
+            if let Some(idx) = ms.contains(k)? {
+                return Ok(Some((n,idx)));
+            }
+        }
+        Ok(None)
+    }
+    // a value of a spilled level which can't be read is taken for a missing one and poisons the map,
+    //   see try_get. With a merge op the newest copy of k is returned, see get_merged
     pub fn get(&self, k: &K) -> Option<&V> {
         match self.slot.get(k) {
             r @ Some(_) => r,
-            None => match self.multy_contains(k) {
-                Some((msi,idx)) => Some(&self.data[msi].values[idx]),
-                None => None,
-            }
+            None => {
+                let (msi,idx) = self.multy_contains(k)?;
+                read_ok(&self.poisoned,self.data[msi].values.get(idx))
+            },
+        }
+    }
+    // as get, but returns the error of reading a spilled level (the map is poisoned as well)
+    pub fn try_get(&self, k: &K) -> Result<Option<&V>,CivError> {
+        if let r @ Some(_) = self.slot.get(k) {
+            return Ok(r);
+        }
+        let found = self.try_multy_contains(k).and_then(|pos| match pos {
+            Some((msi,idx)) => self.data[msi].values.get(idx).map(Some),
+            None => Ok(None),
+        });
+        found.map_err(|e| {
+            self.poisoned.store(true,Ordering::Relaxed);
+            CivError::Io(e)
+        })
+    }
+    // value of k with the deltas left by upsert combined, get returns the newest copy only
+    pub fn get_merged(&self, k: &K) -> Option<V> where V: Clone {
+        let op = match &self.merge_op {
+            Some(op) => op,
+            None => return self.get(k).cloned(),
+        };
+        let mut acc: Option<V> = None;
+        let occurrences = self.data.iter().rev()
+            .filter_map(|ms| read_ok(&self.poisoned,ms.contains(k)).flatten().and_then(|idx| read_ok(&self.poisoned,ms.values.get(idx))))
+            .chain(self.slot.get(k));
+        for v in occurrences {
+            acc = Some(match acc {
+                Some(a) => op(&a,v),
+                None => v.clone(),
+            });
         }
+        acc
     }
+    // a spilled level is read back to change the value
     pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
-        match self.multy_contains(k) {
-            Some((msi,idx)) => Some(&mut self.data[msi].values[idx]),
-            None => self.slot.get_mut(k),
+        match self.fold(k) {
+            Some(Position::Level(msi,idx)) => {
+                if !self.unspill_level(msi) {
+                    return None;
+                }
+                self.refresh_stale();
+                if self.aggregate.is_some() {
+                    self.stale = Some((msi,idx));
+                }
+                Some(&mut self.data[msi].values.heap()[idx])
+            },
+            Some(Position::Slot(idx)) => Some(&mut self.slot.data[idx].1),
+            None => None,
         }
     }
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        if let Some((msi,idx)) = self.multy_contains(&k) {
-            let mut tmp = v;
-            std::mem::swap(&mut tmp, &mut self.data[msi].values[idx]);
-            return Some(tmp);
+        match self.insert_inner(k,v,false) {
+            Ok(r) => r,
+            Err(e) => panic!("Unreachable insert: {:?}",e),
         }
-        let (r,filled) = self.slot.insert(k,v);
-        if let Filled::Full = filled {
-            if self.data.len() == 0 {
-                self.data.push(self.slot.into_map_multislot());
-            } else {
-                let mut n = 0;
-                while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
-                if n == self.data.len() {
-                    self.data.push(MapMultiSlot::new_empty(n+1,self.slot.max_size()));
+    }
+    fn insert_inner(&mut self, k: K, v: V, reserve: bool) -> Result<Option<V>,CivError> {
+        match self.fold(&k) {
+            Some(Position::Level(msi,idx)) if !self.data[msi].spilled() => {
+                let mut tmp = v;
+                std::mem::swap(&mut tmp, &mut self.data[msi].values.heap()[idx]);
+                self.update_aggregate(msi,idx);
+                Ok(Some(tmp))
+            },
+            // the entry of a spilled level is removed and the new value goes to the slot
+            Some(Position::Level(msi,idx)) => {
+                if reserve && (self.slot.len() + 1 >= self.slot.max_size()) {
+                    self.try_reserve_merge()?;
                 }
-                if let Err(s) = self.merge_into(n) {
-                    panic!("Unreachable merge_into: {}",s);
+                let old = self.data[msi].values.read_owned(idx).and_then(|old| read_ok(&self.poisoned,old));
+                drop(self.tombstone(msi,idx));
+                self.insert_inner(k,v,false)?;
+                Ok(old)
+            },
+            pos => {
+                if reserve && pos.is_none() && (self.slot.len() + 1 >= self.slot.max_size()) {
+                    self.try_reserve_merge()?;
                 }
-                if let Err(s) = self.check_tombs(n) {
-                    panic!("Unreachable check_tombs: {}",s);
+                self.tail = false;
+                let (r,filled) = self.slot.insert(k,v);
+                if r.is_none() {
+                    self.len += 1;
                 }
-                self.shrink_long();
-            }
+                if let Filled::Full = filled {
+                    self.try_flush().map_err(CivError::Internal)?;
+                }
+                Ok(r)
+            },
         }
-        if r.is_none() {
+    }
+    // appends delta without probing the levels, it is combined with the older copies of k
+    //   during merges or on read (see get_merged); without a merge op works as insert
+    pub fn upsert(&mut self, k: K, delta: V) {
+        let op = match &self.merge_op {
+            Some(op) => op.clone(),
+            None => {
+                self.insert(k,delta);
+                return;
+            },
+        };
+        self.tail = false;
+        let (new,filled) = self.slot.upsert(k,delta,&*op);
+        if new {
             self.len += 1;
+            self.deltas += 1;
+        }
+        if let Filled::Full = filled {
+            self.flush();
+        }
+    }
+    // appends k greater then all the keys without probing the levels, falls back to insert otherwise
+    pub fn push_back(&mut self, k: K, v: V) -> Option<V> {
+        let is_tail = match self.slot.data.last() {
+            Some((last,_)) => self.tail && (k > *last),
+            None => self.data.iter().all(|ms| match read_ok(&self.poisoned,ms.keys.last()) {
+                Some(Some(last)) => k > *last,
+                Some(None) => true,
+                None => false,
+            }),
+        };
+        if !is_tail {
+            return self.insert(k,v);
+        }
+        self.tail = true;
+        self.len += 1;
+        if let Filled::Full = self.slot.push(k,v) {
+            self.flush();
+        }
+        None
+    }
+    fn flush(&mut self) {
+        if let Err(s) = self.try_flush() {
+            panic!("Unreachable flush: {}",s);
         }
-        r
     }
+    // the levels being merged are dropped and the map is poisoned on error or on a panic of user code
+    fn try_flush(&mut self) -> Result<(),&'static str> {
+        self.refresh_stale();
+        // a panicking Ord leaves the slot unsorted but complete
+        self.slot.sort();
+        // the first flush is a merge into an empty data[0] too, so the compaction filter runs on it
+        //   spilled levels merged in memory are read back, the ones which can't be read are dropped
+        //   and the merge goes into the first of them
+        let mut lost = false;
+        let n = loop {
+            let mut n = 0;
+            while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
+            let on_disk = self.spill.is_some() && (n > 0) && self.data[n-1].spilled();
+            let in_memory = match on_disk {
+                true => n-1,
+                false => n,
+            };
+            match (0 .. in_memory).find(|i| self.data[*i].spilled()) {
+                Some(i) => lost |= self.read_back(i),
+                None => break n,
+            }
+        };
+        if lost {
+            *self.poisoned.get_mut() = true;
+        }
+        if n == self.data.len() {
+            self.data.push(MapMultiSlot::new_empty(n+1,self.slot.max_size()));
+        }
+        // drops data[0..=n] if user code panics in the middle of the merge
+        let guard = Interrupted { map: self, n };
+        let lost = guard.map.merge_into(n)?;
+        // data[n] is empty if everything was dropped by the compaction filter
+        if !guard.map.data[n].empty() {
+            guard.map.check_tombs(n)?;
+        }
+        guard.map.shrink_long();
+        if let Some(agg) = &guard.map.aggregate {
+            for ms in &mut guard.map.data[..= n] {
+                ms.build_tree(agg);
+            }
+        }
+        guard.map.spill_levels();
+        std::mem::forget(guard);
+        if lost {
+            *self.poisoned.get_mut() = true;
+        }
+        Ok(())
+    }
+    // the levels of an interrupted merge are dropped, as their columns may not match,
+    //   and the map is recounted from the remaining ones
+    fn drop_merged(&mut self, n: usize) {
+        for ms in self.data.iter_mut().take(n+1) {
+            ms.released = None;
+            ms.clear();
+        }
+        self.len = self.check_len();
+        self.tombs = self.data.iter().filter(|ms| !ms.empty()).map(|ms| ms.capacity - ms.check_len()).sum();
+        self.tail = false;
+        *self.poisoned.get_mut() = true;
+    }
+    // Allocates all the levels needed to hold additional new keys (and their aggregate trees), so merges
+    //   of the next inserts do not allocate the levels (empty levels of AUTO_SHRINK_LIMIT and more are
+    //   released by merges). A merge op, a filter and an aggregate producing new values still allocate.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(),CivError> {
+        let sz = self.slot.max_size();
+        let mut total = self.slot.len().saturating_add(additional);
+        for ms in &self.data {
+            if !ms.empty() { total = total.saturating_add(ms.capacity); }
+        }
+        let levels = levels_for(sz,total).ok_or_else(capacity_overflow)?;
+        // the largest level goes first, so nothing is allocated in vain
+        let have = self.data.len();
+        let mut fresh = Vec::new();
+        fresh.try_reserve_exact(levels.saturating_sub(have))?;
+        for i in (have .. levels).rev() {
+            fresh.push(MapMultiSlot::try_new_empty(i+1,sz)?);
+        }
+        for ms in self.data.iter_mut().take(levels) {
+            // spilled levels are not read back
+            if ms.spilled() { continue; }
+            let ln = ms.keys.len();
+            ms.keys.heap().try_reserve_exact(ms.capacity - ln)?;
+            ms.values.heap().try_reserve_exact(ms.capacity - ln)?;
+            if self.aggregate.is_some() {
+                ms.tree.try_reserve_exact(2*ms.capacity - ms.tree.len())?;
+            }
+        }
+        self.data.try_reserve(fresh.len())?;
+        self.data.extend(fresh.into_iter().rev());
+        Ok(())
+    }
+    // reserves what the merge of the full slot fills: the level it goes into (pushed if missing),
+    //   the levels above it (one of them is the acc buffer, the others take back the split acc
+    //   or a level moved down by check_tombs) and their aggregate trees. Spilled levels are skipped.
+    fn try_reserve_merge(&mut self) -> Result<(),CivError> {
+        let sz = self.slot.max_size();
+        let mut n = 0;
+        while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
+        if n == self.data.len() {
+            self.data.try_reserve(1)?;
+            self.data.push(MapMultiSlot::try_new_empty(n+1,sz)?);
+        }
+        for ms in &mut self.data[..= n] {
+            if ms.spilled() { continue; }
+            let ln = ms.keys.len();
+            ms.keys.heap().try_reserve_exact(ms.capacity - ln)?;
+            ms.values.heap().try_reserve_exact(ms.capacity - ln)?;
+            if self.aggregate.is_some() {
+                ms.tree.try_reserve_exact(2*ms.capacity - ms.tree.len())?;
+            }
+        }
+        Ok(())
+    }
+    // as insert, but reports allocation failures and broken invariants instead of aborting,
+    //   the map is not changed if allocation fails
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<Option<V>,CivError> {
+        if self.is_poisoned() {
+            return Err(CivError::Poisoned);
+        }
+        self.insert_inner(k,v,true)
+    }
+    // combines all pending deltas of k into the oldest occurrence
+    fn fold(&mut self, k: &K) -> Option<Position> {
+        let op = match &self.merge_op {
+            Some(op) => op.clone(),
+            None => return match self.multy_contains(k) {
+                Some((msi,idx)) => Some(Position::Level(msi,idx)),
+                None => self.slot.contains(k).map(Position::Slot),
+            },
+        };
+        // the oldest copy is changed in memory, the deltas are left as they are if it can't be read
+        let mut oldest: Option<(usize,usize)> = None;
+        for msi in (0 .. self.data.len()).rev() {
+            if let Some(Some(idx)) = read_ok(&self.poisoned,self.data[msi].contains(k)) {
+                match oldest {
+                    None => oldest = Some((msi,idx)),
+                    Some((omsi,oidx)) => {
+                        if !self.unspill_level(omsi) { return oldest.map(|(omsi,oidx)| Position::Level(omsi,oidx)); }
+                        let v = match (self.data[omsi].values.as_slice(),read_ok(&self.poisoned,self.data[msi].values.get(idx))) {
+                            (Some(older),Some(newer)) => op(&older[oidx],newer),
+                            _ => return Some(Position::Level(omsi,oidx)),
+                        };
+                        self.data[omsi].values.heap()[oidx] = v;
+                        self.update_aggregate(omsi,oidx);
+                        drop(self.tombstone(msi,idx));
+                        self.deltas = self.deltas.saturating_sub(1);
+                    },
+                }
+            }
+        }
+        match oldest {
+            None => self.slot.contains(k).map(Position::Slot),
+            Some((omsi,oidx)) => {
+                if let Some(idx) = self.slot.contains(k) {
+                    if !self.unspill_level(omsi) { return Some(Position::Level(omsi,oidx)); }
+                    let values = self.data[omsi].values.heap();
+                    values[oidx] = op(&values[oidx],&self.slot.data[idx].1);
+                    self.update_aggregate(omsi,oidx);
+                    self.slot.data.swap_remove(idx);
+                    self.tail = false;
+                    self.len -= 1;
+                    self.deltas = self.deltas.saturating_sub(1);
+                }
+                Some(Position::Level(omsi,oidx))
+            },
+        }
+    }
+    fn tombstone(&mut self, msi: usize, idx: usize) -> Option<V> { // released value
+        self.tombs += 1;
+        self.len -= 1;
+        self.data[msi].flags.unset(idx);
+        self.update_aggregate(msi,idx);
+        match self.release {
+            true => read_ok(&self.poisoned,self.data[msi].release(idx)),
+            false => None,
+        }
+    }
+    // the keys are counted while upserted deltas may have older copies in the levels
     pub fn len(&self) -> usize {
-        self.len
+        match self.deltas {
+            0 => self.len,
+            _ => self.logical_len(),
+        }
     }
     pub fn tombs(&self) -> usize {
         self.tombs
     }
     pub fn remove(&mut self, k: &K) -> Option<RemovedItem<V>> {
-        let r = match self.multy_contains(&k) {
-            Some((msi,idx)) => {
-                self.tombs += 1;
-                self.data[msi].flags.unset(idx);
-                Some(RemovedItem::Ref(&mut self.data[msi].values[idx]))
-            },
-            None => match self.slot.remove(k) {
+        match self.fold(k) {
+            Some(Position::Level(msi,idx)) => match self.tombstone(msi,idx) {
                 Some(v) => Some(RemovedItem::Owned(v)),
-                None => None,
+                // the value of a spilled level is read from its file
+                None if self.data[msi].spilled() => read_ok(&self.poisoned,self.data[msi].values.read_owned(idx)?).map(RemovedItem::Owned),
+                None => Some(RemovedItem::Ref(&mut self.data[msi].values.heap()[idx])),
             },
-        };
-        if r.is_some() {
-            self.len -= 1;
+            Some(Position::Slot(idx)) => {
+                self.len -= 1;
+                self.tail = false;
+                Some(RemovedItem::Owned(self.slot.data.swap_remove(idx).1))
+            },
+            None => None,
+        }
+    }
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let mut cnt = self.slot.remove_range(&range);
+        self.len -= cnt;
+        for ms in &mut self.data {
+            let span = match read_ok(&self.poisoned,ms.span(&range)) {
+                Some(span) => span,
+                None => continue,
+            };
+            // values of a spilled level are not in memory
+            if self.release && !ms.spilled() {
+                let mut from = span.start;
+                while let Some(idx) = ms.flags.next_one(from) {
+                    if idx >= span.end { break; }
+                    drop(ms.release(idx));
+                    from = idx + 1;
+                }
+            }
+            let c = ms.flags.unset_range(span.start,span.end);
+            self.tombs += c;
+            self.len -= c;
+            cnt += c;
+            if let Some(agg) = &self.aggregate {
+                if c > 0 {
+                    ms.clear_tree(span.start,span.end,agg);
+                }
+            }
+        }
+        cnt
+    }
+    // number of live keys less then k
+    pub fn rank(&self, k: &K) -> usize {
+        self.rank_bound(k,false)
+    }
+    fn rank_bound(&self, k: &K, inclusive: bool) -> usize {
+        let mut r = self.slot.iter().filter(|(x,_)| (x < k)||(inclusive && (x == k))).count();
+        for ms in &self.data {
+            let idx = match inclusive {
+                true => ms.keys.partition_point(|x| x <= k),
+                false => ms.keys.partition_point(|x| x < k),
+            };
+            if let Some(idx) = read_ok(&self.poisoned,idx) {
+                r += ms.flags.rank(idx);
+            }
         }
         r
     }
+    // i-th smallest live key: each step takes the step-th live entry of every run by the rank index
+    //   and drops the run prefix ending with the smallest of them, which are all among the i+1 smallest
+    pub fn select(&self, i: usize) -> Option<(&K,&V)> {
+        if i >= self.len { return None; }
+        let order = sorted_order(&self.slot.data);
+        let entry = |r: usize, p: usize| match r {
+            0 => self.entry_at(&order,(0,p)),
+            _ => {
+                let ms = &self.data[r-1];
+                ms.entry(ms.flags.select(p)?,&self.poisoned)
+            },
+        };
+        // live positions [from,to) of the slot and of the levels left to look at
+        let mut runs = std::iter::once(order.len())
+            .chain(self.data.iter().map(|ms| ms.flags.count()))
+            .map(|to| (0,to))
+            .collect::<Vec<_>>();
+        let mut left = i + 1;
+        loop {
+            let active = runs.iter().filter(|(from,to)| from < to).count();
+            let step = std::cmp::max(1,left / std::cmp::max(1,active));
+            let mut best: Option<(usize,usize,(&K,&V))> = None;
+            for (r,&(from,to)) in runs.iter().enumerate() {
+                if from == to { continue; }
+                let n = std::cmp::min(to - from,step);
+                let kv = entry(r,from + n - 1)?;
+                let better = match best {
+                    Some((_,_,b)) => kv.0 < b.0,
+                    None => true,
+                };
+                if better {
+                    best = Some((r,n,kv));
+                }
+            }
+            let (r,n,kv) = best?;
+            if n == left {
+                return Some(kv);
+            }
+            runs[r].0 += n;
+            left -= n;
+        }
+    }
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let mut cnt = self.slot.iter().filter(|(k,_)| range.contains(k)).count();
+        for ms in &self.data {
+            if let Some(span) = read_ok(&self.poisoned,ms.span(&range)) {
+                cnt += ms.flags.rank(span.end) - ms.flags.rank(span.start);
+            }
+        }
+        cnt
+    }
+    // spilled levels are read back, the ones which can't be read are left as they are
+    pub fn retain<F: FnMut(&K,&mut V) -> bool>(&mut self, mut f: F) {
+        for msi in 0 .. self.data.len() {
+            if !self.unspill_level(msi) { continue; }
+            let mut from = 0;
+            while let Some(idx) = self.data[msi].flags.next_one(from) {
+                if idx >= self.data[msi].keys.len() { break; }
+                let ms = &mut self.data[msi];
+                if !f(&ms.keys.heap()[idx],&mut ms.values.heap()[idx]) {
+                    drop(self.tombstone(msi,idx));
+                }
+                from = idx + 1;
+            }
+        }
+        let ln = self.slot.len();
+        self.slot.data.retain_mut(|(k,v)| f(k,v));
+        self.len -= ln - self.slot.len();
+        if let Some(agg) = &self.aggregate {
+            // values could be changed by f
+            for ms in &mut self.data {
+                ms.build_tree(agg);
+            }
+            self.stale = None;
+        }
+    }
     pub fn shrink_to_fit(&mut self) {
         for ms in &mut self.data {
             ms.shrink_to_fit();
         }
     }
+    // empty levels keep their buffers to be reused by the next merges, frees them
+    pub fn release_scratch(&mut self) {
+        for ms in &mut self.data {
+            if ms.empty() {
+                ms.shrink_to_fit();
+            }
+        }
+    }
+    // Checks the invariants: capacities of the levels layout, keys sorted and unique, flags of the capacity,
+    //   no key live in two levels unless upserted deltas are pending, len and tombs counters. Spilled levels are read.
+    pub fn validate(&self) -> Result<(),CivInvalid> {
+        let unique = self.deltas == 0;
+        self.slot.check()?;
+        let (mut len, mut tombs) = (self.slot.len(),0);
+        for (level,ms) in self.data.iter().enumerate() {
+            if (ms.keys.len() != ms.values.len())||(ms.keys.len() > ms.capacity) {
+                return Err(CivInvalid::Columns { level, keys: ms.keys.len(), values: ms.values.len() });
+            }
+            match ms.keys.as_slice() {
+                Some(keys) => {
+                    check_level::<K,_>(level,self.slot.max_size(),ms.capacity,&ms.flags,keys.len(),keys.iter())?;
+                    if unique {
+                        self.check_overlap(level,keys.iter())?;
+                    }
+                },
+                None => {
+                    // keys of a spilled level are streamed, a level which can't be read poisons the map
+                    let mut read = 0;
+                    check_level::<K,_>(level,self.slot.max_size(),ms.capacity,&ms.flags,ms.keys.len(),read_keys(&ms.keys,&mut read))?;
+                    if unique && (read == ms.keys.len()) {
+                        read = 0;
+                        self.check_overlap(level,read_keys(&ms.keys,&mut read))?;
+                    }
+                    if read != ms.keys.len() {
+                        self.poisoned.store(true,Ordering::Relaxed);
+                        return Err(CivInvalid::Columns { level, keys: read, values: ms.values.len() });
+                    }
+                },
+            }
+            let live = ms.check_len();
+            len += live;
+            if !ms.empty() {
+                tombs += ms.capacity - live;
+            }
+        }
+        match (len,tombs) == (self.len,self.tombs) {
+            true => Ok(()),
+            false => Err(CivInvalid::Counters { len: self.len, tombs: self.tombs, expected_len: len, expected_tombs: tombs }),
+        }
+    }
+    // live keys of data[level] live in the slot or in a newer level
+    fn check_overlap<B: Borrow<K>, I: Iterator<Item = B>>(&self, level: usize, keys: I) -> Result<(),CivInvalid> {
+        let ms = &self.data[level];
+        for (index,k) in keys.enumerate() {
+            let k = k.borrow();
+            let newer = self.slot.contains(k).is_some() || self.data[.. level].iter().any(|ms| read_ok(&self.poisoned,ms.contains(k)).flatten().is_some());
+            if newer && ms.flags.get(index) {
+                return Err(CivInvalid::Overlap { level, index });
+            }
+        }
+        Ok(())
+    }
     fn shrink_long(&mut self) {
         for ms in &mut self.data {
             if (ms.capacity >= AUTO_SHRINK_LIMIT)&&(ms.empty()) {   
@@ -491,6 +2340,42 @@ impl<K: Ord, V> CivMap<K,V> {
             }
         }
     }
+    fn levels_mem(&self) -> usize {
+        self.slot.heap_mem() + self.data.iter().map(|ms| ms.heap_mem()).sum::<usize>()
+    }
+    // evicts or writes to files the largest levels while the heap is over the budget
+    fn spill_levels(&mut self) {
+        let budget = match &self.spill {
+            Some(spill) if self.aggregate.is_none() => spill.budget,
+            _ => return,
+        };
+        if self.levels_mem() <= budget { return; }
+        self.release_scratch();
+        let (dir,write) = match &self.spill {
+            Some(spill) => (spill.dir.clone(),spill.write),
+            None => return,
+        };
+        while self.levels_mem() > budget {
+            let largest = self.data.iter().enumerate()
+                .filter(|(_,ms)| (ms.keys.capacity() > 0)||(ms.values.capacity() > 0))
+                .max_by_key(|(_,ms)| ms.heap_mem())
+                .map(|(i,_)| i);
+            let ms = match largest {
+                Some(i) => &mut self.data[i],
+                None => break,
+            };
+            ms.forget_released();
+            if ms.spilled() {
+                ms.keys.evict();
+                ms.values.evict();
+            } else if write(ms,&dir).is_err() {
+                // the level stays in memory if it can't be written
+                break;
+            }
+        }
+    }
+    // moves data[n] into data[0..n] if it has too many tombs, the entries of data[n] are already passed
+    //   through the compaction filter by merge_into, so the rewritten levels hold filtered entries only
     fn check_tombs(&mut self, n: usize) -> Result<(),&'static str> {
         if self.data[n].empty() { return Err("data[n] is empty"); }
         for i in 0 .. n {
@@ -500,12 +2385,13 @@ impl<K: Ord, V> CivMap<K,V> {
         let sz =  self.slot.max_size();
         let local_tombs = self.data[n].capacity - self.data[n].keys.len();
         let local_part = (local_tombs as f64) / (self.data[n].capacity as f64);
-        if (local_tombs > sz) && (local_part > TOMBS_LIMIT) {
-            std::mem::swap(&mut self.data[n].keys, &mut self.tmp_merge_keys);
-            std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
+        // a level merged on disk is left as it is if it can't be read back
+        if (local_tombs > sz) && (local_part > TOMBS_LIMIT) && self.data[n].unspill().is_ok() {
+            let mut keys = std::mem::take(self.data[n].keys.heap());
+            let mut values = std::mem::take(self.data[n].values.heap());
             {
-                let mut count = self.tmp_merge_keys.len();
-                let mut iter = self.tmp_merge_keys.drain(..).zip(self.tmp_merge_values.drain(..));
+                let mut count = keys.len();
+                let mut iter = keys.drain(..).zip(values.drain(..));
 
                 let mut msi = self.data[..n].iter_mut();
                 while let Some(ms) = msi.next_back() {
@@ -513,8 +2399,8 @@ impl<K: Ord, V> CivMap<K,V> {
                     if count >= cap {
                         for _ in 0 .. cap {
                             if let Some((k,v)) = iter.next() {
-                                ms.keys.push(k);
-                                ms.values.push(v);
+                                ms.keys.heap().push(k);
+                                ms.values.heap().push(v);
                             }
                         }
                         if ms.keys.len() != cap {
@@ -522,7 +2408,14 @@ impl<K: Ord, V> CivMap<K,V> {
                         }
                         ms.flags.set_ones(cap);
                         count -= cap;
-                        if count == 0 { break; }
+                        if count == 0 {
+                            // all data[0..n] are full, no tombs left
+                            if local_tombs > self.tombs {
+                                return Err("local_tombs > self.tombs");
+                            }
+                            self.tombs -= local_tombs;
+                            break;
+                        }
                         continue;
                     }
                     if (cap - count) > sz { continue; }
@@ -530,8 +2423,8 @@ impl<K: Ord, V> CivMap<K,V> {
                     let d_tombs = local_tombs - (cap - count);
                     for _ in 0 .. count {
                         if let Some((k,v)) = iter.next() {
-                            ms.keys.push(k);
-                            ms.values.push(v);
+                            ms.keys.heap().push(k);
+                            ms.values.heap().push(v);
                         }
                     }
                     if ms.keys.len() != count {
@@ -548,111 +2441,166 @@ impl<K: Ord, V> CivMap<K,V> {
                     return Err("merged data greater then the sum of the parts");
                 }
             }
-            std::mem::swap(&mut self.data[n].keys, &mut self.tmp_merge_keys);
-            std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
+            self.data[n].keys = keys.into();
+            self.data[n].values = values.into();
             self.data[n].clear();
         }
         Ok(())
     }
-    fn merge_into(&mut self, n: usize) -> Result<(),&'static str> {
-        // merge sort for sorted inflating vectors
+    // compacts data[n-1] and moves it into data[n], data[n] keeps its buffer if cnt more fit in
+    fn move_down(&mut self, n: usize, cnt: usize) {
+        let (lower,upper) = self.data.split_at_mut(n);
+        let (src,dst) = (&mut lower[n-1],&mut upper[0]);
+        src.retain_live();
+        if dst.keys.capacity() >= src.keys.len() + cnt {
+            dst.keys.heap().append(src.keys.heap());
+            dst.values.heap().append(src.values.heap());
+        } else {
+            std::mem::swap(&mut dst.keys,&mut src.keys);
+            std::mem::swap(&mut dst.values,&mut src.values);
+        }
+    }
+    // spilled data[n-1] is merged on disk, the other levels are in memory. Returns if data[n-1] is dropped
+    //   because it can't be merged on disk or read back.
+    fn merge_into(&mut self, n: usize) -> Result<bool,&'static str> {
+        // merge sort for sorted inflating vectors: slot and data[0..n-1] are merged into acc,
+        //   acc is merged in place with data[n-1] into data[n]
         
         if !self.data[n].empty() { return Err("data[n] is not empty"); }
+        for ms in &mut self.data[.. n] {
+            ms.forget_released();
+        }
         let mut cnt = self.slot.len();
+        let mut holes = 0;
         for i in 0 .. n {
             if self.data[i].empty() { return Err("one of data[0..n] is empty"); }
-            cnt += self.data[i].keys.len();
+            if i + 1 < n {
+                cnt += self.data[i].keys.len();
+            }
+            holes += self.data[i].capacity - self.data[i].check_len();
         }
-        self.data[n].reserve(cnt);
 
-        std::mem::swap(&mut self.data[n].keys, &mut self.tmp_merge_keys);
-        std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
-        {
-            if n == 0 {
+        let op = self.merge_op.clone();
+        let mut folded = 0;
+        // filter is applied while streaming
+        let mut streamed = false;
+        let mut lost = false;
+        if n == 0 {
+            self.data[0].reserve(cnt);
+            for (k,v) in self.slot.sorted_drain() {
+                self.data[0].keys.heap().push(k);
+                self.data[0].values.heap().push(v);
+            }
+            self.slot.clear();
+        } else {
+            // spilled data[n-1] is merged with acc on disk, without reading it back
+            let stream = self.spill.is_some() && self.data[n-1].spilled();
+            if !stream {
+                self.move_down(n,cnt);
+            }
+            // the freed buffer of data[n-1] (of data[n] if data[n-1] stays on disk) is used for acc
+            let acc = match stream {
+                true => n,
+                false => n-1,
+            };
+            let mut acc_keys = std::mem::take(self.data[acc].keys.heap());
+            let mut acc_values = std::mem::take(self.data[acc].values.heap());
+            acc_keys.reserve(cnt);
+            acc_values.reserve(cnt);
+            if n == 1 {
                 for (k,v) in self.slot.sorted_drain() {
-                    self.tmp_merge_keys.push(k);
-                    self.tmp_merge_values.push(v);
+                    acc_keys.push(k);
+                    acc_values.push(v);
                 }
                 self.slot.clear();
             } else {
-                let mut slot = self.slot.into_map_multislot();
-                self.slot.clear();
-                for i in 0 .. n {
-                    { // for split_at_mut
+                // the slot is the first part of acc split back after each pass, so its buffer is reused
+                let slot_len = self.slot.len();
+                for i in 0 .. n-1 {
+                    let concat = {
+                        // non-overlapping runs (e.g. after push_back) are concatenated without comparisons
                         let (sorted,to_sort) = self.data[..].split_at_mut(i);
-                        
-                        let mut f_data = slot.drain();
-                        let mut s_data = to_sort[0].filtered_drain();
-                        let mut sorted = sorted.iter_mut(); 
-                        
-                        let mut f = f_data.next();
-                        let mut s = s_data.next();
-                        
-                        loop {
-                            while f.is_some() && s.is_some() {
-                                let fe = f.take().unwrap(); // safe
-                                let se = s.take().unwrap(); // safe
-                                match fe.0 < se.0 {
-                                    true => {
-                                        self.tmp_merge_keys.push(fe.0);
-                                        self.tmp_merge_values.push(fe.1);
-                                        f = f_data.next();
-                                        s = Some(se);
-                                    },
-                                    false => {
-                                        self.tmp_merge_keys.push(se.0);
-                                        self.tmp_merge_values.push(se.1);                           
-                                        f = Some(fe);
-                                        s = s_data.next();
-                                    },
-                                }
+                        let f_first = match self.slot.data.first() {
+                            Some((k,_)) => Some(k),
+                            None => sorted.iter().find_map(|ms| ms.keys.as_slice().and_then(|keys| keys.first())),
+                        };
+                        let concat = match (to_sort[0].keys.as_slice().and_then(|keys| keys.last()),f_first) {
+                            (Some(s_last),Some(f_first)) => s_last < f_first,
+                            _ => false,
+                        };
+                        if concat {
+                            for (k,v) in to_sort[0].filtered_drain().chain(self.slot.data.drain(..)) {
+                                acc_keys.push(k);
+                                acc_values.push(v);
                             }
-                            if f.is_none() {
-                                // f_data finished, try to get next
-                                match sorted.next() {
-                                    Some(ms) => {
-                                        f_data = ms.drain();
-                                        f = f_data.next();
-                                    },
-                                    None => break, // all fs are done
+                            for ms in sorted.iter_mut() {
+                                for (k,v) in ms.drain() {
+                                    acc_keys.push(k);
+                                    acc_values.push(v);
                                 }
-                            } else {
-                                // s is done
-                                break;
                             }
                         }
-                        if f.is_some() {
-                            loop {
-                                while let Some(fe) = f {
-                                    self.tmp_merge_keys.push(fe.0);
-                                    self.tmp_merge_values.push(fe.1);
+                        concat
+                    };
+                    if !concat { // for split_at_mut
+                        let (sorted,to_sort) = self.data[..].split_at_mut(i);
+                        // slot and data[0..i] are the consecutive sorted parts of the previous acc
+                        let mut f_data = self.slot.data.drain(..).chain(sorted.iter_mut().flat_map(|ms| ms.drain()));
+                        let mut s_data = to_sort[0].filtered_drain();
+
+                        let mut f = f_data.next();
+                        let mut s = s_data.next();
+
+                        while f.is_some() && s.is_some() {
+                            let fe = f.take().unwrap(); // safe
+                            let se = s.take().unwrap(); // safe
+                            match fe.0.cmp(&se.0) {
+                                std::cmp::Ordering::Less => {
+                                    acc_keys.push(fe.0);
+                                    acc_values.push(fe.1);
                                     f = f_data.next();
-                                }
-                                match sorted.next() {
-                                    Some(ms) => {
-                                        f_data = ms.drain();
-                                        f = f_data.next();
-                                    },
-                                    None => break, // all fs are done
-                                }
-                            }
-                        } else {
-                            while let Some(se) = s {
-                                self.tmp_merge_keys.push(se.0);
-                                self.tmp_merge_values.push(se.1);
-                                s = s_data.next();
+                                    s = Some(se);
+                                },
+                                std::cmp::Ordering::Equal if op.is_some() => {
+                                    // se is older then fe
+                                    let v = (op.as_ref().unwrap())(&se.1,&fe.1); // safe
+                                    acc_keys.push(se.0);
+                                    acc_values.push(v);
+                                    folded += 1;
+                                    f = f_data.next();
+                                    s = s_data.next();
+                                },
+                                _ => {
+                                    acc_keys.push(se.0);
+                                    acc_values.push(se.1);
+                                    f = Some(fe);
+                                    s = s_data.next();
+                                },
                             }
                         }
+                        while let Some(fe) = f {
+                            acc_keys.push(fe.0);
+                            acc_values.push(fe.1);
+                            f = f_data.next();
+                        }
+                        while let Some(se) = s {
+                            acc_keys.push(se.0);
+                            acc_values.push(se.1);
+                            s = s_data.next();
+                        }
                     }
-                    
-                    // fs and s are done, spliting tmp_merge_* into previous slots
+
+                    // fs and s are done, spliting acc into previous slots
                     //   on all iters except last
-                    if i < (n-1) {
-                        let mut iter = self.tmp_merge_keys.drain(..).zip(self.tmp_merge_values.drain(..));
-                        let mut ex = slot.fill_in(&mut iter);
-                        for j in 0 ..= i {
-                            ex = self.data[j].fill_in(&mut iter);
-                            if ex { break; }
+                    if i < (n-2) {
+                        let mut iter = acc_keys.drain(..).zip(acc_values.drain(..));
+                        self.slot.data.extend(iter.by_ref().take(slot_len));
+                        let mut ex = self.slot.len() < slot_len;
+                        if !ex {
+                            for j in 0 ..= i {
+                                ex = self.data[j].fill_in(&mut iter);
+                                if ex { break; }
+                            }
                         }
                         if !ex {
                             if let Some(_) = iter.next() {
@@ -661,17 +2609,93 @@ impl<K: Ord, V> CivMap<K,V> {
                         }
                     }
                 }
+                self.slot.clear();
             }
-            for i in 0 .. n {
+            for i in 0 .. n-1 {
                 self.data[i].clear();
             }
+            let op: Option<OpRef<V>> = match &op {
+                Some(op) => Some(&**op),
+                None => None,
+            };
+            if let (true,Some(spill)) = (stream,&self.spill) {
+                let filter = self.filter.as_ref().map(|f| &**f as FilterRef<K,V>);
+                // falls back to the merge in memory if the level can't be written
+                if let Ok((ms,f)) = (spill.merge)(&spill.dir,&self.data[n-1],&acc_keys,&acc_values,self.data[n].capacity,op,filter) {
+                    self.data[n] = ms;
+                    folded += f;
+                    streamed = true;
+                    acc_keys.clear();
+                    acc_values.clear();
+                }
+            }
+            if !streamed {
+                if stream {
+                    let (capacity,live) = (self.data[n-1].capacity,self.data[n-1].check_len());
+                    if self.read_back(n-1) {
+                        // its tombs are already subtracted
+                        holes -= capacity - live;
+                        lost = true;
+                    }
+                    self.move_down(n,0);
+                }
+                let dst = &mut self.data[n];
+                folded += merge_back(dst.keys.heap(),dst.values.heap(),&mut acc_keys,&mut acc_values,op);
+            }
+            self.data[n-1].keys = acc_keys.into();
+            self.data[n-1].values = acc_values.into();
+            self.data[n-1].clear();
         }
-        std::mem::swap(&mut self.data[n].keys, &mut self.tmp_merge_keys);
-        std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
-   
+        let dropped = match (&self.filter,streamed) {
+            (Some(filter),false) => self.data[n].compact(&**filter),
+            _ => 0,
+        };
+
+        // holes of data[0..n] are moved to data[n] together with the folded and dropped ones
         let c = self.data[n].keys.len();
+        if holes > self.tombs {
+            return Err("holes > self.tombs");
+        }
+        self.tombs -= holes;
+        if c > 0 {
+            self.tombs += self.data[n].capacity - c;
+        }
+        self.len -= folded + dropped;
+        // all the copies of a key meet once the levels above n are empty
+        self.deltas = match self.data[n+1 ..].iter().all(|ms| ms.empty()) {
+            true => 0,
+            false => self.deltas.saturating_sub(folded),
+        };
         self.data[n].flags.set_ones(c);
-        Ok(())
+        Ok(lost)
+    }
+}
+
+#[cfg(feature = "bytemuck")]
+impl<K: Ord + bytemuck::Pod, V: bytemuck::Pod> CivMap<K,V> {
+    // merged_iter of the values copied out
+    fn merged_entries(&self) -> impl Iterator<Item = (K,V)> + '_ {
+        let op = self.merge_op.clone();
+        let mut iter = self.sorted_iter().peekable();
+        // occurrences of a key, the newest first
+        let mut group = Vec::new();
+        std::iter::from_fn(move || {
+            let (k,v) = iter.next()?;
+            group.clear();
+            group.push(*v);
+            while let Some((_,v)) = iter.next_if(|(x,_)| *x == k) {
+                group.push(*v);
+            }
+            let v = match &op {
+                Some(op) => group[.. group.len()-1].iter().rev().fold(group[group.len()-1],|acc,v| op(&acc,v)),
+                None => group[0],
+            };
+            Some((*k,v))
+        })
+    }
+    // writes the plain layout read by MappedCivMap
+    pub fn into_mapped_writer<W: Write>(&self, wrt: W) -> Result<(),CivMapIoError> {
+        crate::civs::mapped::write_mapped(wrt,|| self.merged_entries())
     }
 }
 
@@ -711,7 +2735,23 @@ impl<K: Ord, V> CivMap<K,V> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
+    // map of n inserts of keys below p, with every third insert removing another key, and its BTreeMap
+    fn churned(n: u64, p: u64) -> (CivMap<u64,u64>,std::collections::BTreeMap<u64,u64>) {
+        let mut map = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. n {
+            let k = (i * 7919) % p;
+            map.insert(k,i);
+            res.insert(k,i);
+            if i % 3 == 0 {
+                map.remove(&((i * 13) % p));
+                res.remove(&((i * 13) % p));
+            }
+        }
+        (map,res)
+    }
+
     #[test]
     #[ignore]
     fn test_merge_sort_1() {
@@ -759,6 +2799,26 @@ mod tests {
         panic!();
     }
 
+    #[test]
+    fn test_check_tombs_exact_fill() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        // data[0] of 64 and data[1] of 128
+        for i in 0 .. 192 {
+            map.insert(i,i);
+        }
+        // leaves 64 live keys of data[1] and none of data[0]
+        for i in 64 .. 192 {
+            map.remove(&i);
+        }
+        assert_eq!(map.len(),64);
+        // merged into data[2] of 256 with 128 live keys, which fill data[1] exactly
+        for i in 192 .. 256 {
+            map.insert(i,i);
+        }
+        assert_eq!(map.len(),128);
+        assert_eq!(map.tombs(),0);
+    }
+
     #[test]
     fn test_iter() {
         let cnt = 1_000_000;
@@ -781,5 +2841,1121 @@ mod tests {
 
         assert_eq!(res,lib);
     }
-    
+
+    #[test]
+    fn test_upsert() {
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a, b| a + b);
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 100_000u64 {
+            let k = (i * 7919) % 5_000;
+            map.upsert(k,i);
+            *res.entry(k).or_insert(0) += i;
+        }
+        for (k,v) in &res {
+            assert_eq!(map.get_merged(k),Some(*v));
+        }
+        for k in 0 .. 100 {
+            let v = map.remove(&k).map(|ritem|ritem.copied());
+            assert_eq!(v,res.remove(&k));
+        }
+        for (k,v) in res.iter().take(100) {
+            assert_eq!(map.get_mut(k).copied(),Some(*v));
+        }
+    }
+
+    #[test]
+    fn test_upsert_merged() {
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a, b| a + b);
+        for k in 0 .. 1_000 {
+            map.upsert(k,1);
+        }
+        // all the keys are in the levels now, the deltas are new copies
+        for k in 0 .. 1_000 {
+            map.upsert(k,2);
+        }
+        assert!(map.sorted_iter().count() > 1_000);
+        assert_eq!(map.len(),1_000);
+        assert_eq!(map.get_merged(&10),Some(3));
+        assert_eq!(map.merged_iter().map(|(k,v)| (*k,*v.as_ref())).collect::<Vec<_>>(),(0 .. 1_000).map(|k| (k,3)).collect::<Vec<_>>());
+        assert_eq!(map.validate(),Ok(()));
+
+        // the layout keeps the copies, the file has them combined
+        let Layout(mut map2): Layout<CivMap<u64,u64>> = bincode::deserialize(&bincode::serialize(&Layout(&map)).unwrap()).unwrap();
+        assert_eq!(map2.len(),1_000);
+        map2.set_merge_op(|a, b| a + b);
+        assert!(map2.merged_iter().map(|(k,v)| (k,v.into_owned())).eq(map.merged_iter().map(|(k,v)| (k,v.into_owned()))));
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let map2 = CivMap::<u64,u64>::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert_eq!(map2.sorted_iter().count(),1_000);
+        assert!(map2.sorted_iter().all(|(_,v)| *v == 3));
+
+        // the count is settled by the merge into the last level, which meets all the copies
+        let mut k = 1_000;
+        while map.deltas > 0 {
+            map.upsert(k,1);
+            k += 1;
+        }
+        assert_eq!(map.len(),k as usize);
+        assert_eq!(map.sorted_iter().count(),k as usize);
+        assert_eq!(map.get(&10),Some(&3));
+    }
+
+    #[test]
+    fn test_upsert_append_only() {
+        use std::sync::atomic::AtomicUsize;
+        // comparisons of the keys, the slot finds its keys by equality
+        static COMPARED: AtomicUsize = AtomicUsize::new(0);
+        #[derive(Debug,Clone,Copy,PartialEq,Eq)]
+        struct Key(u64);
+        impl Ord for Key {
+            fn cmp(&self, other: &Key) -> std::cmp::Ordering {
+                COMPARED.fetch_add(1,Ordering::Relaxed);
+                self.0.cmp(&other.0)
+            }
+        }
+        impl PartialOrd for Key {
+            fn partial_cmp(&self, other: &Key) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        let mut map: CivMap<Key,u64> = CivMap::with_merge_op(|a, b| a + b);
+        for k in 0 .. 10_000 {
+            map.upsert(Key(k),1);
+        }
+        assert!(map.slot.len() < map.slot.max_size() / 2);
+        // keys of the levels, fewer than the slot takes before its merge
+        let keys = (0 .. (map.slot.max_size() / 2) as u64).map(|k| Key(k * 13)).filter(|k| map.slot.contains(k).is_none()).collect::<Vec<_>>();
+        COMPARED.store(0,Ordering::Relaxed);
+        for &k in &keys {
+            map.upsert(k,1);
+        }
+        assert_eq!(COMPARED.load(Ordering::Relaxed),0);
+        for &k in &keys {
+            assert_eq!(map.get_merged(&k),Some(2));
+        }
+        assert!(COMPARED.load(Ordering::Relaxed) > 0);
+        assert_eq!(map.len(),10_000);
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        map.set_compaction_filter(|k,v| match k % 3 {
+            0 => Compaction::Drop,
+            1 => Compaction::Replace(*v + 1),
+            _ => Compaction::Keep,
+        });
+        for i in 0 .. 10_000 {
+            map.insert(i,0);
+        }
+        let live = map.filtered_iter().collect::<Vec<_>>();
+        assert_eq!(live.len(),map.len());
+        assert!(live.iter().filter(|(k,_)| *k % 3 == 0).count() < 64);
+        for (k,v) in live {
+            if k % 3 == 2 { assert_eq!(*v,0); }
+        }
+        for i in (0 .. 10_000).filter(|i| i % 3 != 0) {
+            assert!(map.contains(&i));
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_tombs() {
+        // drops most of the keys, so the merged levels are rewritten by check_tombs
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        map.set_compaction_filter(|k,v| match (k % 8,*v) {
+            (0,0) => Compaction::Replace(1),
+            (0,_) => Compaction::Keep,
+            _ => Compaction::Drop,
+        });
+        for i in 0 .. 10_000 {
+            map.insert(i,0);
+        }
+        assert_eq!(map.validate(),Ok(()));
+        let live = map.filtered_iter().collect::<Vec<_>>();
+        assert_eq!(live.len(),map.len());
+        for ms in &map.data {
+            for idx in ms.flags.iter_ones_range(0,ms.keys.len()) {
+                assert_eq!((ms.keys.get(idx).unwrap() % 8,*ms.values.get(idx).unwrap()),(0,1));
+            }
+        }
+        assert!(map.data.iter().all(|ms| ms.empty() || (ms.capacity - ms.check_len() <= map.slot.max_size())));
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 100_000 {
+            map.insert((i * 7919) % 100_000,i);
+        }
+        assert_eq!(map.remove_range(1_000 .. 90_000),89_000);
+        assert_eq!(map.remove_range(..= 500),501);
+        assert_eq!(map.remove_range(99_990 ..),10);
+        assert_eq!(map.len(),100_000 - 89_000 - 501 - 10);
+        for i in 0 .. 100_000 {
+            let live = (501 .. 1_000).contains(&i) || (90_000 .. 99_990).contains(&i);
+            assert_eq!(map.contains(&i),live);
+        }
+    }
+
+    #[test]
+    fn test_push_back() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 100_000 {
+            assert_eq!(map.push_back(2 * i,i),None);
+            if i % 1_000 == 0 {
+                // out of order, falls back to insert
+                assert_eq!(map.push_back(i + 1,i),None);
+            }
+        }
+        assert_eq!(map.push_back(0,1),Some(0));
+        assert_eq!(map.len(),100_100);
+        for i in 0 .. 100_000 {
+            assert_eq!(map.get(&(2 * i)),Some(&if i == 0 { 1 } else { i }));
+        }
+        let mut keys = map.filtered_iter().map(|(k,_)| *k).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(),100_100);
+    }
+
+    #[test]
+    fn test_order_statistics() {
+        let (map,res) = churned(50_000,100_003);
+        let keys = res.keys().cloned().collect::<Vec<_>>();
+        for (i,k) in keys.iter().enumerate().step_by(97) {
+            assert_eq!(map.rank(k),i);
+            assert_eq!(map.rank(&(k + 1)),i + 1);
+            assert_eq!(map.select(i),Some((k,&res[k])));
+        }
+        assert_eq!(map.select(keys.len()),None);
+        assert_eq!(map.count_range(1_000 .. 50_000),res.range(1_000 .. 50_000).count());
+        assert_eq!(map.count_range(..),res.len());
+    }
+
+    #[test]
+    fn test_select_all() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 3_000 {
+            let k = (i * 7919) % 5_003;
+            map.insert(k,i);
+            res.insert(k,i);
+            if i % 5 == 0 {
+                let k = (i * 104_729) % 5_003;
+                assert_eq!(map.remove(&k).is_some(),res.remove(&k).is_some());
+            }
+        }
+        for (i,(k,v)) in res.iter().enumerate() {
+            assert_eq!(map.select(i),Some((k,v)));
+        }
+        assert_eq!(map.select(res.len()),None);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 30_000 {
+            let k = (i * 7919) % 50_000;
+            map.insert(k,i);
+            res.insert(k,i);
+        }
+        map.set_aggregate(0,|a, b| a + b);
+        for i in 30_000 .. 60_000 {
+            let k = (i * 7919) % 50_000;
+            match i % 3 {
+                0 => { map.insert(k,i); res.insert(k,i); },
+                1 => { map.remove(&k); res.remove(&k); },
+                _ => if let Some(v) = map.get_mut(&k) {
+                    *v += 1;
+                    *res.get_mut(&k).unwrap() += 1;
+                },
+            }
+        }
+        map.remove_range(10_000 .. 11_000);
+        res.retain(|k,_| !(10_000 .. 11_000).contains(k));
+        for (a,b) in [(0,50_000),(5_000,12_000),(49_000,49_001),(100,100)].iter() {
+            assert_eq!(map.aggregate(a .. b),Some(res.range(a .. b).map(|(_,v)| *v).sum::<u64>()));
+        }
+
+        map.set_aggregate(u64::MAX,|a, b| std::cmp::min(*a,*b));
+        assert_eq!(map.aggregate(..),res.values().min().copied());
+        map.remove_aggregate();
+        assert_eq!(map.aggregate(..),None);
+    }
+
+    #[test]
+    fn test_aggregate_stale() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        map.set_aggregate(0,|a, b| a + b);
+        for i in 0 .. 10_000 {
+            map.insert(i,1);
+        }
+        for i in 0 .. 1_000 {
+            *map.get_mut(&(i * 7)).unwrap() += 1;
+            // only the value given out last is not in its tree
+            assert!(map.stale.iter().count() <= 1);
+            assert_eq!(map.aggregate(..),Some(10_000 + i + 1));
+            assert_eq!(map.aggregate(i * 7 .. i * 7 + 1),Some(2));
+        }
+        for i in 0 .. 100 {
+            map.remove_range(i * 100 .. i * 100 + 7);
+        }
+        let expected = map.sorted_iter().map(|(_,v)| *v).sum::<u64>();
+        assert_eq!(map.aggregate(..),Some(expected));
+        let expected = map.range(2_345 .. 8_765).map(|(_,v)| *v).sum::<u64>();
+        assert_eq!(map.aggregate(2_345 .. 8_765),Some(expected));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let (mut map,mut res) = churned(20_000,30_011);
+
+        let mut cursor = map.cursor();
+        assert_eq!(cursor.current(),None);
+        let mut it = res.iter();
+        while let Some((k,v)) = cursor.next() {
+            assert_eq!(Some((k,v)),it.next());
+        }
+        assert_eq!(it.next(),None);
+        let mut it = res.iter().rev();
+        while let Some((k,v)) = cursor.prev() {
+            assert_eq!(Some((k,v)),it.next());
+        }
+        assert_eq!(it.next(),None);
+
+        assert_eq!(cursor.seek_last(),res.iter().next_back());
+        for k in [0,1,777,15_000,29_999,30_011].iter() {
+            let mut it = res.range(k ..);
+            let mut back = res.range(.. k);
+            assert_eq!(cursor.seek(k),it.next());
+            assert_eq!(cursor.peek_next(),it.clone().next());
+            assert_eq!(cursor.peek_prev(),back.clone().next_back());
+            assert_eq!(cursor.next(),it.next());
+            if cursor.current().is_some() {
+                assert_eq!(cursor.prev(),res.range(k ..).next());
+            }
+            assert_eq!(cursor.prev(),back.next_back());
+        }
+
+        let mut cursor = map.cursor_mut();
+        cursor.seek(&10_000);
+        while let Some((k,_)) = cursor.current() {
+            match k % 2 {
+                0 => { cursor.remove_current(); },
+                _ => { *cursor.value_mut().unwrap() += 1; },
+            }
+            if cursor.next().map(|(k,_)| *k >= 20_000).unwrap_or(true) { break; }
+        }
+        let tail = res.range(10_000 ..).map(|(k,_)| *k).take_while(|k| *k < 20_000).collect::<Vec<_>>();
+        for k in tail {
+            match k % 2 {
+                0 => { res.remove(&k); },
+                _ => { *res.get_mut(&k).unwrap() += 1; },
+            }
+        }
+        assert_eq!(map.len(),res.len());
+        assert_eq!(map.cursor().seek_last(),res.iter().next_back());
+        let mut cursor = map.cursor();
+        for (k,v) in res.iter() {
+            assert_eq!(map.get(k),Some(v));
+            assert_eq!(cursor.next(),Some((k,v)));
+        }
+    }
+
+    #[test]
+    fn test_rev_iter() {
+        let (map,res) = churned(20_000,30_011);
+        let fwd = map.filtered_iter().collect::<Vec<_>>();
+        let mut back = map.filtered_iter().rev().collect::<Vec<_>>();
+        back.reverse();
+        assert_eq!(fwd,back);
+
+        assert_eq!(map.sorted_iter().len(),res.len());
+        assert!(map.sorted_iter().rev().eq(res.iter().rev()));
+        assert_eq!(map.sorted_iter().rev().take(10).collect::<Vec<_>>(),res.iter().rev().take(10).collect::<Vec<_>>());
+        for (a,b) in [(0,30_011),(1_000,1_500),(7,8),(500,100)].iter() {
+            let mut it = map.range(a ..= b);
+            let mut rit = res.range(a ..= std::cmp::max(a,b));
+            if a > b { rit = res.range(a .. a); }
+            assert_eq!(it.len(),rit.clone().count());
+            let mut i = 0;
+            loop {
+                let (x,y) = match i % 3 {
+                    0 => (it.next_back(),rit.next_back()),
+                    _ => (it.next(),rit.next()),
+                };
+                assert_eq!(x,y);
+                if x.is_none() { break; }
+                i += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_iter() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 50_000 {
+            map.insert(i,i);
+        }
+        map.remove_range(130 .. 45_000);
+        for i in (0 .. 130).filter(|i| i % 7 != 0) {
+            map.remove(&i);
+        }
+        let mut res = (0 .. 130).step_by(7).chain(45_000 .. 50_000).collect::<Vec<u64>>();
+        let mut v = map.filtered_iter().map(|(k,_)| *k).collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(v,res);
+        let mut v = map.filtered_iter().rev().map(|(k,_)| *k).collect::<Vec<_>>();
+        v.sort();
+        assert_eq!(v,res);
+        assert!(map.sorted_iter().map(|(k,_)| *k).eq(res.iter().cloned()));
+        assert_eq!(map.range(100 .. 45_001).map(|(k,_)| *k).collect::<Vec<_>>(),vec![105,112,119,126,45_000]);
+
+        map.retain(|k,_| k % 2 == 0);
+        res.retain(|k| k % 2 == 0);
+        assert_eq!(map.len(),res.len());
+        assert!(map.sorted_iter().rev().map(|(k,_)| *k).eq(res.iter().rev().cloned()));
+    }
+
+    #[test]
+    fn test_eager_release() {
+        let mut map: CivMap<u64,Arc<u64>> = CivMap::new();
+        map.set_eager_release();
+        let hold = Arc::new(0);
+        for i in 0 .. 1_000 {
+            map.insert(i,hold.clone());
+        }
+        assert_eq!(Arc::strong_count(&hold),1_001);
+        for i in 0 .. 100 {
+            match map.remove(&i) {
+                Some(RemovedItem::Owned(v)) => assert!(Arc::ptr_eq(&v,&hold)),
+                _ => panic!("value is not released"),
+            }
+        }
+        map.remove_range(100 .. 500);
+        map.retain(|k,_| k % 2 == 0);
+        let mut cursor = map.cursor_mut();
+        cursor.seek(&900);
+        cursor.remove_current();
+        assert_eq!(map.len(),249);
+        assert_eq!(Arc::strong_count(&hold),250);
+        for i in 1_000 .. 2_000 {
+            map.insert(i,hold.clone());
+        }
+        assert_eq!(Arc::strong_count(&hold),1_250);
+        drop(map);
+        assert_eq!(Arc::strong_count(&hold),1);
+    }
+
+    #[test]
+    fn test_eager_release_drops() {
+        use std::sync::atomic::{AtomicUsize,Ordering};
+        // neither Default nor Clone, counts its drops
+        struct Handle(u64,Arc<AtomicUsize>);
+        impl Drop for Handle {
+            fn drop(&mut self) {
+                self.1.fetch_add(1,Ordering::Relaxed);
+            }
+        }
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut map: CivMap<u64,Handle> = CivMap::new();
+        map.set_eager_release();
+        for i in 0 .. 5_000 {
+            map.insert(i,Handle(i,drops.clone()));
+        }
+        for i in 0 .. 1_000 {
+            match map.remove(&(i * 3)) {
+                Some(RemovedItem::Owned(h)) => assert_eq!(h.0,i * 3),
+                _ => panic!("value is not released"),
+            }
+        }
+        assert_eq!(drops.load(Ordering::Relaxed),1_000);
+        map.remove_range(4_000 .. 4_500);
+        assert_eq!(drops.load(Ordering::Relaxed),1_500);
+        // merges cut the released entries out
+        for i in 5_000 .. 10_000 {
+            map.insert(i,Handle(i,drops.clone()));
+        }
+        assert_eq!(map.validate(),Ok(()));
+        assert_eq!(drops.load(Ordering::Relaxed),1_500);
+        for (k,h) in map.sorted_iter() {
+            assert_eq!(*k,h.0);
+        }
+        let live = map.len();
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed),10_000);
+        assert_eq!(live,8_500);
+    }
+
+    #[test]
+    fn test_eager_release_write() {
+        let mut map: CivMap<u64,String> = CivMap::new();
+        map.set_eager_release();
+        map.set_aggregate(String::new(),|a,b| std::cmp::max(a,b).clone());
+        for i in 0 .. 1_000 {
+            map.insert(i,format!("{:04}",i));
+        }
+        map.remove_range(100 .. 300);
+        map.remove(&999);
+        let copy = map.clone();
+        assert_eq!(copy.validate(),Ok(()));
+        assert_eq!(copy.aggregate(..),Some("0998".to_string()));
+        assert_eq!(format!("{:?}",copy),format!("{:?}",map));
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let map2: CivMap<u64,String> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert_eq!(map2.len(),799);
+        assert!(map2.sorted_iter().eq(map.sorted_iter()));
+        let Layout(map3): Layout<CivMap<u64,String>> = bincode::deserialize(&bincode::serialize(&Layout(&map)).unwrap()).unwrap();
+        assert!(map3.sorted_iter().eq(map.sorted_iter()));
+    }
+
+    thread_local! {
+        // comparisons left before Bomb::cmp panics
+        static FUSE: std::cell::Cell<usize> = const { std::cell::Cell::new(usize::MAX) };
+    }
+    #[derive(Debug,Clone,Copy,PartialEq,Eq)]
+    struct Bomb(u64);
+    impl PartialOrd for Bomb {
+        fn partial_cmp(&self, other: &Bomb) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Bomb {
+        fn cmp(&self, other: &Bomb) -> std::cmp::Ordering {
+            FUSE.with(|f| match f.get() {
+                0 => panic!("comparator fuse"),
+                n => f.set(n - 1),
+            });
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn test_panic_safety() {
+        let mut map: CivMap<Bomb,u64> = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        let (mut kept, mut poisoned) = (0, 0);
+        for i in 0 .. 20_000u64 {
+            let k = (i * 7919) % 10_007;
+            FUSE.with(|f| f.set((i as usize * 31) % 1_500));
+            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| map.insert(Bomb(k),i)));
+            FUSE.with(|f| f.set(usize::MAX));
+            if map.is_poisoned() {
+                poisoned += 1;
+                map.clear();
+                res.clear();
+                assert!(!map.is_poisoned());
+                continue;
+            }
+            match r {
+                Ok(_) => { res.insert(k,i); },
+                Err(_) => {
+                    kept += 1;
+                    // either inserted or not
+                    if map.get(&Bomb(k)) == Some(&i) { res.insert(k,i); }
+                },
+            }
+            assert_eq!(map.len(),res.len());
+        }
+        assert!((kept > 0)&&(poisoned > 0));
+        for (k,v) in res.iter() {
+            assert_eq!(map.get(&Bomb(*k)),Some(v));
+        }
+        assert!(map.sorted_iter().map(|(k,v)| (k.0,*v)).eq(res.into_iter()));
+    }
+
+    #[test]
+    fn test_insert_after_poison() {
+        let mut map: CivMap<Bomb,u64> = CivMap::new();
+        let mut i = 0u64;
+        while !map.is_poisoned() {
+            let k = (i * 7919) % 10_007;
+            FUSE.with(|f| f.set((i as usize * 31) % 1_500));
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| map.insert(Bomb(k),i)));
+            FUSE.with(|f| f.set(usize::MAX));
+            i += 1;
+        }
+        // the interrupted merge dropped its levels, the rest is consistent
+        assert!(map.validate().is_ok());
+        let mut res: std::collections::BTreeMap<u64,u64> = map.sorted_iter().map(|(k,v)| (k.0,*v)).collect();
+        assert_eq!(map.len(),res.len());
+        assert!(matches!(map.try_insert(Bomb(0),0),Err(CivError::Poisoned)));
+        for j in 0 .. 20_000u64 {
+            let k = (j * 13) % 30_011;
+            assert_eq!(map.insert(Bomb(k),j),res.insert(k,j));
+            if j % 5 == 0 {
+                assert_eq!(map.remove(&Bomb(k / 2)).is_some(),res.remove(&(k / 2)).is_some());
+            }
+        }
+        assert!(map.is_poisoned());
+        assert!(map.validate().is_ok());
+        assert_eq!(map.len(),res.len());
+        assert!(map.sorted_iter().map(|(k,v)| (k.0,*v)).eq(res.into_iter()));
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 20_000 {
+            let k = (i * 7919) % 10_007;
+            assert_eq!(map.try_insert(k,i).unwrap(),res.insert(k,i));
+        }
+        let levels = map.data.len();
+        assert!(matches!(map.try_reserve(usize::MAX),Err(CivError::Alloc(_))));
+        assert_eq!(map.data.len(),levels);
+        // the capacity of the levels overflows before usize::MAX entries fit
+        assert_eq!(levels_for(64,usize::MAX),None);
+        assert_eq!(levels_for(64,64 * 7),Some(3));
+        assert_eq!(levels_for(64,64 * 7 + 1),Some(4));
+        assert_eq!(map.len(),res.len());
+
+        // a slot fill reserves the levels of its merge, not the empty ones behind it
+        map.release_scratch();
+        let n = map.data.iter().position(|ms| ms.empty()).unwrap();
+        let mut k = 10_007;
+        while map.slot.len() > 0 {
+            map.try_insert(k,k).unwrap();
+            k += 1;
+        }
+        assert!(!map.data[n].empty());
+        assert!(map.data[n+1 ..].iter().filter(|ms| ms.empty()).all(|ms| ms.keys.capacity() == 0));
+
+        map.try_reserve(100_000).unwrap();
+        let caps = map.data.iter().map(|ms| ms.keys.capacity()).collect::<Vec<_>>();
+        for i in 0 .. 100_000 {
+            map.insert(20_000 + i,i);
+        }
+        assert_eq!(map.data.iter().map(|ms| ms.keys.capacity()).collect::<Vec<_>>(),caps);
+
+        *map.poisoned.get_mut() = true;
+        assert!(matches!(map.try_insert(0,0),Err(CivError::Poisoned)));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let dir = std::env::temp_dir().join(format!("civs-test-budget-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = || std::fs::read_dir(&dir).unwrap().count();
+
+        let mut map: CivMap<u64,String> = CivMap::with_merge_op(|a,b| format!("{}{}",a,b));
+        map.set_memory_budget(32 << 10,&dir);
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 20_000u64 {
+            let k = (i * 7919) % 12_007;
+            map.upsert(k,format!("{}.",i % 10));
+            res.entry(k).or_insert_with(String::new).push_str(&format!("{}.",i % 10));
+            if i % 11 == 0 {
+                map.remove(&((i * 13) % 12_007));
+                res.remove(&((i * 13) % 12_007));
+            }
+        }
+        assert!(map.data.iter().any(|ms| ms.spilled()));
+        assert!(files() > 0);
+        for (k,v) in res.iter() {
+            assert_eq!(map.get_merged(k).as_ref(),Some(v));
+        }
+        drop(map);
+
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        map.set_compaction_filter(|k,_| match k % 2 {
+            0 => Compaction::Keep,
+            _ => Compaction::Drop,
+        });
+        map.set_memory_budget(16 << 10,&dir);
+        for i in 0 .. 50_000u64 {
+            map.insert(i,i);
+        }
+        assert!(map.data.iter().any(|ms| ms.spilled()));
+        for i in 0 .. 49_984u64 {
+            assert_eq!(map.get(&i),[Some(&i),None][(i % 2) as usize]);
+        }
+        assert_eq!(map.sorted_iter().count(),map.len());
+        assert_eq!(map.get_mut(&2).map(|v| { *v += 1; *v }),Some(3));
+        map.remove_memory_budget();
+        assert!(!map.data.iter().any(|ms| ms.spilled()));
+        assert_eq!(map.get(&2),Some(&3));
+
+        map.clear();
+        assert_eq!(files(),0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spilled_reads() {
+        let dir = std::env::temp_dir().join(format!("civs-test-reads-{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        map.set_memory_budget(16 << 10,&dir);
+        for i in 0 .. 50_000u64 {
+            map.insert(i * 2,i);
+        }
+        let level = map.data.iter().position(|ms| ms.spilled() && (ms.keys.len() > 10_000)).unwrap();
+        let (first,last) = (*map.data[level].keys.get(0).unwrap(),*map.data[level].keys.last().unwrap().unwrap());
+        let cached = |map: &CivMap<u64,u64>| (map.data[level].keys.capacity(),map.data[level].values.capacity());
+        assert_eq!(cached(&map),(128,0));
+        map.data[level].keys.evict();
+
+        // single entries are read from the file, the columns are not loaded
+        let k = first + 2 * 1_000;
+        assert!(map.contains(&k) && !map.contains(&(k + 1)));
+        assert_eq!(map.rank(&k),(k / 2) as usize);
+        assert_eq!(map.count_range(first ..= last),map.data[level].keys.len());
+        assert_eq!(cached(&map),(0,0));
+        assert_eq!(map.get(&k),Some(&(k / 2)));
+        assert_eq!(map.try_get(&(k + 2)).unwrap(),Some(&(k / 2 + 1)));
+        assert_eq!(cached(&map),(0,64));
+        assert!(!map.is_poisoned());
+
+        // a level file which can't be read poisons the map instead of panicking
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            std::fs::File::options().write(true).open(entry.unwrap().path()).unwrap().set_len(0).unwrap();
+        }
+        assert_eq!(map.get(&(last - 2)),None);
+        assert!(map.is_poisoned());
+        assert!(matches!(map.try_get(&(last - 4)),Err(CivError::Io(_))));
+        assert!(map.sorted_iter().count() < map.len());
+        assert!(matches!(map.validate(),Err(CivInvalid::Columns { .. })));
+        assert!(matches!(map.try_insert(1,1),Err(CivError::Poisoned)));
+        // the merges drop the levels which can't be read
+        for i in 0 .. 10_000u64 {
+            map.insert(100_001 + i * 2,i);
+        }
+        assert_eq!(map.get(&100_001),Some(&0));
+        map.clear();
+        assert!(!map.is_poisoned());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sectioned_format() {
+        let (map,res) = churned(20_000,15_013);
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        assert_eq!(&buf[4 .. 12],&[0,0,0,0,2,0,0,0]);
+        let map2: CivMap<u64,u64> = CivMap::from_reader(&buf[..]).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+        assert!(map2.sorted_iter().eq(res.iter()));
+
+        // v0.1
+        let mut buf = Vec::new();
+        write!(buf,"CIVM").unwrap();
+        buf.write_u32::<LittleEndian>(0).unwrap();
+        buf.write_u32::<LittleEndian>(1).unwrap();
+        bincode::serialize_into(&mut buf,&map.slot).unwrap();
+        bincode::serialize_into(&mut buf,&map.data).unwrap();
+        let map2: CivMap<u64,u64> = CivMap::from_reader(&buf[..]).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+        assert!(map2.sorted_iter().eq(res.iter()));
+
+        let path = std::env::temp_dir().join(format!("civs-test-lazy-{}.civm",std::process::id()));
+        map.into_writer(std::io::BufWriter::new(std::fs::File::create(&path).unwrap())).unwrap();
+        let mut map2: CivMap<u64,u64> = CivMap::open_lazy(&path).unwrap();
+        assert!(map2.data.iter().filter(|ms| !ms.empty()).all(|ms| ms.spilled() && (ms.keys.capacity() == 0)));
+        assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+        // a point read loads a single block of values
+        let (&k,&v) = res.iter().nth(res.len() / 2).unwrap();
+        assert_eq!(map2.get(&k),Some(&v));
+        let cached = map2.data.iter().map(|ms| (ms.keys.capacity(),ms.values.capacity())).fold((0,0),|a,c| (a.0 + c.0,a.1 + c.1));
+        assert!((cached.0 == 0) && (cached.1 <= 64));
+        assert!(!map2.is_poisoned());
+        assert!(map2.sorted_iter().eq(res.iter()));
+        for i in 0 .. 1_000 {
+            map2.insert(20_000 + i,i);
+        }
+        assert_eq!(map2.len(),res.len() + 1_000);
+        assert_eq!(map2.get(&20_500),Some(&500));
+        drop(map2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_portable_sections() {
+        let mut map: CivMap<u32,u16> = CivMap::new();
+        for i in 0 .. 1_000u32 {
+            map.insert(i * 7,i as u16);
+        }
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let dir = map.directory().unwrap();
+        let at = |offset: u64| u64::from_le_bytes(buf[offset as usize .. offset as usize + 8].try_into().unwrap());
+        // sizes, the word length and the type sizes are not written
+        assert_eq!(at(dir.slot.offset),64);
+        assert_eq!(dir.slot.length,16 + 6 * map.slot.len() as u64);
+        for (ms,s) in map.data.iter().zip(&dir.levels) {
+            assert_eq!(at(s.offset),ms.capacity as u64);
+            assert_eq!(s.columns[0],16 + 8 * at(s.offset + 8));
+            assert_eq!(s.length,s.columns[1] + 8 + 2 * ms.keys.len() as u64);
+        }
+        let map2: CivMap<u32,u16> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert!(map2.sorted_iter().eq(map.sorted_iter()));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let (map,_) = churned(5_000,5_003);
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let dir = map.directory().unwrap();
+        let last = dir.levels.iter().rev().find(|s| s.length > s.columns[1] + 8).unwrap();
+
+        let mut broken = buf.clone();
+        broken[(last.offset + last.columns[1] + 8) as usize] ^= 0x10;
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&broken[..]),Err(CivMapIoError::Checksum)));
+        let mut broken = buf.clone();
+        broken[(dir.slot.offset + 30) as usize] ^= 0x01;
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&broken[..]),Err(CivMapIoError::Checksum)));
+        // length of the keys, can't be decoded
+        let mut broken = buf.clone();
+        broken[(last.offset + last.columns[0] + 7) as usize] = 0xff;
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&broken[..]),Err(CivMapIoError::Checksum)));
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&buf[.. buf.len() - 100]),Err(CivMapIoError::Checksum)));
+
+        let path = std::env::temp_dir().join(format!("civs-test-checksum-{}.civm",std::process::id()));
+        let mut broken = buf.clone();
+        broken[(last.offset + last.columns[1] + 8) as usize] ^= 0x10;
+        std::fs::write(&path,&broken).unwrap();
+        assert!(matches!(CivMap::<u64,u64>::open_lazy(&path),Err(CivMapIoError::Checksum)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a,b| a + b);
+        for i in 0 .. 20_000u64 {
+            map.upsert((i * 7919) % 3_001,1);
+            if i % 7 == 0 {
+                map.remove(&((i * 13) % 3_001));
+            }
+        }
+        // copies of a key left by upsert are accepted while pending
+        map.validate().unwrap();
+        let (map,_) = churned(20_000,15_013);
+        map.validate().unwrap();
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        CivMap::<u64,u64>::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        let level = map.data.iter().position(|ms| ms.keys.len() > 10).unwrap();
+
+        let mut broken = map.clone();
+        broken.data[level].keys.heap().swap(3,4);
+        assert_eq!(broken.validate(),Err(CivInvalid::Unsorted { level, index: 4 }));
+        let mut buf = Vec::new();
+        broken.into_writer(&mut buf).unwrap();
+        assert!(matches!(CivMap::<u64,u64>::from_reader_strict(&buf[..],CivLimits::default()),Err(CivMapIoError::Invalid(CivInvalid::Unsorted { .. }))));
+
+        let mut broken = map.clone();
+        broken.data[level].capacity *= 2;
+        assert!(matches!(broken.validate(),Err(CivInvalid::Capacity { .. })));
+        let mut broken = map.clone();
+        broken.data[level].values.heap().pop();
+        assert!(matches!(broken.validate(),Err(CivInvalid::Columns { .. })));
+        let mut broken = map.clone();
+        broken.data[level].flags = Flags::nulls(map.data[level].capacity + 64);
+        assert!(matches!(broken.validate(),Err(CivInvalid::Flags { .. })));
+        let mut broken = map.clone();
+        let last = (0 .. map.data[level].keys.len()).rev().find(|i| map.data[level].flags.get(*i)).unwrap();
+        broken.data[level].keys.heap().truncate(last);
+        broken.data[level].values.heap().truncate(last);
+        assert_eq!(broken.validate(),Err(CivInvalid::DeadTail { level }));
+        // a key twice in the unsorted slot, apart
+        let mut broken = map.clone();
+        broken.slot.data.clear();
+        for k in [9_000_001,7,9_000_002,9_000_001] {
+            broken.slot.data.push((k,0));
+        }
+        assert_eq!(broken.validate(),Err(CivInvalid::Slot));
+
+        let mut broken = map.clone();
+        let (k,v) = map.data[level].filtered_iter(&map.poisoned).next().map(|(k,v)| (*k,*v)).unwrap();
+        broken.slot.data.push((k,v));
+        broken.len += 1;
+        assert!(matches!(broken.validate(),Err(CivInvalid::Overlap { level: l, .. }) if l == level));
+        let mut broken = map.clone();
+        broken.slot.data.push((u64::MAX,0));
+        assert!(matches!(broken.validate(),Err(CivInvalid::Counters { .. })));
+    }
+
+    #[test]
+    fn test_limits() {
+        let (map,_) = churned(5_000,5_003);
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        // live entries and tombstones are counted
+        let entries = map.slot.len() + map.data.iter().map(|ms| ms.keys.len()).sum::<usize>();
+        let limits = CivLimits {
+            bytes: buf.len() as u64,
+            entries,
+            level_capacity: 4_096,
+        };
+        let map2: CivMap<u64,u64> = CivMap::from_reader_with_limits(&buf[..],limits).unwrap();
+        assert!(map2.sorted_iter().eq(map.sorted_iter()));
+        let limit = |limits| matches!(CivMap::<u64,u64>::from_reader_with_limits(&buf[..],limits),Err(CivMapIoError::Limit));
+        assert!(limit(CivLimits { bytes: buf.len() as u64 - 1, ..limits }));
+        assert!(limit(CivLimits { bytes: 100, ..limits }));
+        assert!(limit(CivLimits { entries: entries - 1, ..limits }));
+        assert!(limit(CivLimits { level_capacity: 2_048, ..limits }));
+        assert!(CivMap::<u64,u64>::from_reader_strict(&buf[..],limits).is_ok());
+        assert!(matches!(CivMap::<u64,u64>::from_reader_strict(&buf[..],CivLimits { entries: entries - 1, ..limits }),Err(CivMapIoError::Limit)));
+
+        // v0.1 files claiming a huge level or vector
+        let mut head = Vec::new();
+        write!(head,"CIVM").unwrap();
+        head.write_u32::<LittleEndian>(0).unwrap();
+        head.write_u32::<LittleEndian>(1).unwrap();
+        bincode::serialize_into(&mut head,&map.slot).unwrap();
+        let mut buf = head.clone();
+        bincode::serialize_into(&mut buf,&(1u64,1usize << 40,8usize,8usize,vec![0u64],vec![1u64],vec![1u64])).unwrap();
+        assert!(matches!(CivMap::<u64,u64>::from_reader_with_limits(&buf[..],CivLimits { level_capacity: 1 << 20, ..Default::default() }),Err(CivMapIoError::Limit)));
+        let mut buf = head.clone();
+        bincode::serialize_into(&mut buf,&(1u64,64usize,8usize,8usize,vec![0u64])).unwrap();
+        buf.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        buf.extend_from_slice(&[0; 4096]);
+        assert!(matches!(CivMap::<u64,u64>::from_reader_with_limits(&buf[..],CivLimits { bytes: 4096, ..Default::default() }),Err(CivMapIoError::Limit)));
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&buf[..]),Err(CivMapIoError::ReadData(_))));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut map: CivMap<u64,f64> = CivMap::new();
+        for i in 0 .. 1_000u64 {
+            map.insert(i,i as f64);
+        }
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        assert!(CivMap::<u64,f64>::from_reader(&buf[..]).is_ok());
+        // the layout is checked by the default readers
+        assert!(matches!(CivMap::<u32,f64>::from_reader(&buf[..]),Err(CivMapIoError::TypeMismatch { found: Some(_), .. })));
+        assert!(matches!(CivMap::<u64,u32>::from_reader_with_limits(&buf[..],CivLimits::default()),Err(CivMapIoError::TypeMismatch { .. })));
+        let path = std::env::temp_dir().join(format!("civs-test-fingerprint-{}.civm",std::process::id()));
+        std::fs::write(&path,&buf).unwrap();
+        assert!(matches!(CivMap::<u32,f64>::open_lazy(&path),Err(CivMapIoError::TypeMismatch { .. })));
+        std::fs::remove_file(&path).unwrap();
+        // types of the same layout are told apart by the user fingerprint only
+        assert!(CivMap::<i64,u64>::from_reader(&buf[..]).is_ok());
+        assert!(matches!(CivMap::<u64,f64>::from_reader_with_fingerprint(&buf[..],7),Err(CivMapIoError::TypeMismatch { expected: 7, found: None })));
+
+        map.set_fingerprint(Some(42));
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let map2 = CivMap::<u64,f64>::from_reader_with_fingerprint(&buf[..],42).unwrap();
+        assert_eq!(map2.fingerprint(),Some(42));
+        assert!(map2.sorted_iter().eq(map.sorted_iter()));
+        assert_eq!(CivMap::<u64,f64>::from_reader(&buf[..]).unwrap().fingerprint(),Some(42));
+        assert!(matches!(CivMap::<i64,u64>::from_reader_with_fingerprint(&buf[..],7),Err(CivMapIoError::TypeMismatch { expected: 7, found: Some(42) })));
+        assert!(matches!(CivMap::<u64,f64>::from_reader_with_fingerprint(&buf[..],43),Err(CivMapIoError::TypeMismatch { expected: 43, found: Some(42) })));
+        // the opt-out reads a file of another layout
+        assert!(matches!(CivMap::<u64,[u32; 2]>::from_reader(&buf[..]),Err(CivMapIoError::TypeMismatch { .. })));
+        let map2 = CivMap::<u64,[u32; 2]>::from_reader_unchecked(&buf[..]).unwrap();
+        assert!(map2.sorted_iter().map(|(k,_)| k).eq(map.sorted_iter().map(|(k,_)| k)));
+    }
+
+    #[test]
+    fn test_file_versions() {
+        let (map,_) = churned(5_000,5_003);
+        // v0.1 is read only, as is the current one
+        let mut v1 = Vec::new();
+        write!(v1,"CIVM").unwrap();
+        v1.write_u32::<LittleEndian>(0).unwrap();
+        v1.write_u32::<LittleEndian>(1).unwrap();
+        bincode::serialize_into(&mut v1,&map.slot).unwrap();
+        bincode::serialize_into(&mut v1,&map.data).unwrap();
+        let mut current = Vec::new();
+        map.into_writer(&mut current).unwrap();
+        assert_eq!(&current[4 .. 12],&[0,0,0,0,2,0,0,0]);
+        let path = std::env::temp_dir().join(format!("civs-test-versions-{}.civm",std::process::id()));
+        for buf in [&v1,&current] {
+            let map2: CivMap<u64,u64> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+            assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+            assert!(map2.sorted_iter().eq(map.sorted_iter()));
+            std::fs::write(&path,buf).unwrap();
+            let map2: CivMap<u64,u64> = CivMap::open_lazy(&path).unwrap();
+            assert!(map2.sorted_iter().eq(map.sorted_iter()));
+        }
+        std::fs::remove_file(&path).unwrap();
+        for version in [(0,3),(1,0)] {
+            let mut buf = current.clone();
+            buf[4 .. 8].copy_from_slice(&u32::to_le_bytes(version.0));
+            buf[8 .. 12].copy_from_slice(&u32::to_le_bytes(version.1));
+            assert!(matches!(CivMap::<u64,u64>::from_reader(&buf[..]),Err(CivMapIoError::InvalidVersion(maj,min)) if (maj,min) == version));
+        }
+    }
+
+    #[test]
+    fn test_compacted() {
+        let (map,res) = churned(20_000,15_013);
+        assert!(map.tombs() > 0);
+        let mut full = Vec::new();
+        map.into_writer(&mut full).unwrap();
+
+        let mut buf = Vec::new();
+        map.into_writer_compacted(&mut buf,false).unwrap();
+        assert!(buf.len() < full.len());
+        let map2: CivMap<u64,u64> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+        assert_eq!(map2.data.iter().map(|ms| ms.keys.len()).collect::<Vec<_>>(),map.data.iter().map(|ms| ms.check_len()).collect::<Vec<_>>());
+        assert!(map2.sorted_iter().eq(res.iter()));
+
+        let mut buf = Vec::new();
+        map.into_writer_compacted(&mut buf,true).unwrap();
+        let mut map2: CivMap<u64,u64> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(res.len(),0));
+        assert_eq!(map2.slot.len(),res.len() % 64);
+        assert!(map2.data.iter().all(|ms| ms.empty() || (ms.keys.len() == ms.capacity)));
+        assert!(map2.sorted_iter().eq(res.iter()));
+        for i in 0 .. 1_000 {
+            map2.insert(20_000 + i,i);
+        }
+        map2.validate().unwrap();
+        let path = std::env::temp_dir().join(format!("civs-test-compacted-{}.civm",std::process::id()));
+        std::fs::write(&path,&buf).unwrap();
+        assert!(CivMap::<u64,u64>::open_lazy(&path).unwrap().sorted_iter().eq(res.iter()));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut broken = buf.clone();
+        let n = broken.len() - 20;
+        broken[n] ^= 0x04;
+        assert!(matches!(CivMap::<u64,u64>::from_reader(&broken[..]),Err(CivMapIoError::Checksum)));
+
+        // pending deltas are merged into a single level
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a,b| a + b);
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 5_000u64 {
+            let k = (i * 7919) % 701;
+            map.upsert(k,i);
+            *res.entry(k).or_insert(0) += i;
+        }
+        let mut buf = Vec::new();
+        map.into_writer_compacted(&mut buf,true).unwrap();
+        let map2: CivMap<u64,u64> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+        assert!(map2.sorted_iter().eq(res.iter()));
+
+        for n in [0,10,64,200] {
+            let mut map: CivMap<u64,u64> = CivMap::new();
+            for i in 0 .. n {
+                map.insert(i,i);
+            }
+            let mut buf = Vec::new();
+            map.into_writer_compacted(&mut buf,true).unwrap();
+            let map2: CivMap<u64,u64> = CivMap::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+            assert_eq!((map2.len(),map2.tombs()),(n as usize,0));
+            assert!(map2.sorted_iter().eq(map.sorted_iter()));
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        use std::collections::BTreeMap;
+
+        let (map,res) = churned(5_000,5_003);
+        let buf = bincode::serialize(&map).unwrap();
+        assert_eq!(buf,bincode::serialize(&res).unwrap());
+        let map2: CivMap<u64,u64> = bincode::deserialize(&buf).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(res.len(),0));
+        assert!(map2.sorted_iter().eq(res.iter()));
+
+        let buf = bincode::serialize(&Layout(&map)).unwrap();
+        let Layout(map2): Layout<CivMap<u64,u64>> = bincode::deserialize(&buf).unwrap();
+        assert_eq!((map2.len(),map2.tombs()),(map.len(),map.tombs()));
+        assert_eq!(map2.data.len(),map.data.len());
+        assert!(map2.sorted_iter().eq(res.iter()));
+        // the slot size is zero, behind the fingerprint and the deltas
+        let mut broken = buf.clone();
+        broken[9 .. 17].copy_from_slice(&[0; 8]);
+        let e = bincode::deserialize::<Layout<CivMap<u64,u64>>>(&broken).err().unwrap();
+        assert!(e.to_string().starts_with("invalid map: Slot"));
+
+        // pending deltas are merged
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a,b| a + b);
+        let mut res = BTreeMap::new();
+        for i in 0 .. 5_000u64 {
+            let k = (i * 7919) % 701;
+            map.upsert(k,i);
+            *res.entry(k).or_insert(0) += i;
+        }
+        let map2: CivMap<u64,u64> = bincode::deserialize(&bincode::serialize(&map).unwrap()).unwrap();
+        assert!(map2.sorted_iter().eq(res.iter()));
+        let Layout(map2): Layout<CivMap<u64,u64>> = bincode::deserialize(&bincode::serialize(&Layout(&map)).unwrap()).unwrap();
+        assert_eq!(map2.len(),map.len());
+        assert!(map2.sorted_iter().eq(map.sorted_iter()));
+
+        // a key live in two levels is refused as by validate
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 2_000u64 {
+            map.insert(i,i);
+        }
+        map.remove(&5);
+        map.insert(5,0);
+        let (msi,idx) = map.data.iter().enumerate().find_map(|(msi,ms)| Some((msi,ms.keys.binary_search(&5).unwrap().ok()?))).unwrap();
+        map.data[msi].flags.set(idx);
+        let e = bincode::deserialize::<Layout<CivMap<u64,u64>>>(&bincode::serialize(&Layout(&map)).unwrap()).err().unwrap();
+        assert!(e.to_string().starts_with("invalid map: Overlap"));
+    }
+
+    #[test]
+    fn test_merge_scratch() {
+        let mut map: CivMap<u64,u64> = CivMap::with_merge_op(|a,b| a + b);
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 30_000u64 {
+            let k = (i * 7919) % 3_001;
+            map.upsert(k,i);
+            *res.entry(k).or_insert(0) += i;
+            if i % 7 == 0 {
+                map.remove(&((i * 13) % 3_001));
+                res.remove(&((i * 13) % 3_001));
+            }
+        }
+        for (k,v) in res.iter() {
+            assert_eq!(map.get_merged(k),Some(*v));
+        }
+
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 50_000 {
+            map.push_back(i,i);
+        }
+        assert!(map.sorted_iter().map(|(k,v)| (*k,*v)).eq((0 .. 50_000).map(|i| (i,i))));
+        assert_eq!(map.len(),50_000);
+
+        let levels = map.data.iter().filter(|ms| !ms.empty()).count();
+        map.release_scratch();
+        assert_eq!(map.data.iter().filter(|ms| ms.keys.capacity() > 0).count(),levels);
+        // empty levels give their buffers back, the others keep theirs
+        for ms in &map.data {
+            assert_eq!(ms.keys.capacity() == 0,ms.empty());
+            assert_eq!(ms.values.capacity() == 0,ms.empty());
+        }
+        for i in 0 .. 50_000 {
+            assert_eq!(map.get(&i),Some(&i));
+        }
+
+        // acc goes into the buffer move_down frees, data[n-1] and data[n] swap or keep theirs:
+        //   once the levels are reserved no merge allocates a column
+        let buffers = |map: &CivMap<u64,u64>| {
+            let mut b: Vec<usize> = map.data.iter()
+                .flat_map(|ms| [ms.keys.as_slice().unwrap().as_ptr() as usize,ms.values.as_slice().unwrap().as_ptr() as usize])
+                .collect();
+            b.sort_unstable();
+            b
+        };
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut res = std::collections::BTreeMap::new();
+        map.try_reserve(30_000).unwrap();
+        let reserved = buffers(&map);
+        for i in 0 .. 30_000u64 {
+            let k = (i * 7919) % 20_011;
+            map.insert(k,i);
+            res.insert(k,i);
+            if i % 3 == 0 {
+                map.remove(&((i * 13) % 20_011));
+                res.remove(&((i * 13) % 20_011));
+            }
+        }
+        assert_eq!(buffers(&map),reserved);
+        assert!(map.sorted_iter().map(|(k,v)| (*k,*v)).eq(res.into_iter()));
+    }
+
 }
@@ -0,0 +1,226 @@
+use bytemuck::Pod;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::path::Path;
+
+use crate::{
+    CivInvalid,
+    civs::{
+        range_span,
+        map::CivMapIoError,
+    },
+};
+
+// Plain layout of a map: header, then the sorted live keys and their values as arrays of K and V
+//   in native byte order, each section starts at a multiple of SECTION_ALIGN.
+//   Header: "CIVP", major u32, minor u32, len, key size, key align, value size, value align
+//   (u64 little endian) and BYTE_ORDER_MARK written in native byte order.
+const CURRENT_CIVS_MAPPED_VERSION: (u32,u32) = (0,1);
+const HEADER_SIZE: usize = 60;
+const SECTION_ALIGN: usize = 64;
+const BYTE_ORDER_MARK: u64 = 0x0102030405060708;
+
+fn align_up(off: usize) -> usize {
+    off.div_ceil(SECTION_ALIGN) * SECTION_ALIGN
+}
+
+struct Layout {
+    len: usize,
+    keys: usize,
+    values: usize,
+    end: usize,
+}
+impl Layout {
+    fn new<K,V>(len: usize) -> Option<Layout> {
+        let keys = align_up(HEADER_SIZE);
+        let values = align_up(len.checked_mul(std::mem::size_of::<K>())?.checked_add(keys)?);
+        let end = len.checked_mul(std::mem::size_of::<V>())?.checked_add(values)?;
+        Some(Layout { len, keys, values, end })
+    }
+}
+
+fn check_types<K,V>() -> Result<(),CivMapIoError> {
+    match (std::mem::size_of::<K>() > 0)&&(std::mem::size_of::<V>() > 0)
+        &&(std::mem::align_of::<K>() <= SECTION_ALIGN)&&(std::mem::align_of::<V>() <= SECTION_ALIGN) {
+        true => Ok(()),
+        false => Err(CivMapIoError::InvalidLayout),
+    }
+}
+
+fn io_error(e: std::io::Error) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Io(e))
+}
+
+fn write_padding<W: Write>(wrt: &mut W, from: usize, to: usize) -> Result<(),CivMapIoError> {
+    wrt.write_all(&vec![0; to - from]).map_err(|e| CivMapIoError::WriteData(io_error(e)))
+}
+
+// entries must produce the same sorted entries on each call
+pub(crate) fn write_mapped<K: Pod, V: Pod, W: Write, I: Iterator<Item = (K,V)>>(mut wrt: W, entries: impl Fn() -> I) -> Result<(),CivMapIoError> {
+    check_types::<K,V>()?;
+    let len = entries().count();
+    let layout = Layout::new::<K,V>(len).ok_or(CivMapIoError::InvalidLayout)?;
+    let version = CURRENT_CIVS_MAPPED_VERSION;
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(b"CIVP");
+    header.extend_from_slice(&version.0.to_le_bytes());
+    header.extend_from_slice(&version.1.to_le_bytes());
+    for x in [len,std::mem::size_of::<K>(),std::mem::align_of::<K>(),std::mem::size_of::<V>(),std::mem::align_of::<V>()] {
+        header.extend_from_slice(&(x as u64).to_le_bytes());
+    }
+    header.extend_from_slice(&BYTE_ORDER_MARK.to_ne_bytes());
+    wrt.write_all(&header).map_err(|_|CivMapIoError::WriteHeader)?;
+    write_padding(&mut wrt,HEADER_SIZE,layout.keys)?;
+    for (k,_) in entries().take(len) {
+        wrt.write_all(bytemuck::bytes_of(&k)).map_err(|e| CivMapIoError::WriteData(io_error(e)))?;
+    }
+    write_padding(&mut wrt,layout.keys + len * std::mem::size_of::<K>(),layout.values)?;
+    for (_,v) in entries().take(len) {
+        wrt.write_all(bytemuck::bytes_of(&v)).map_err(|e| CivMapIoError::WriteData(io_error(e)))?;
+    }
+    wrt.flush().map_err(|e| CivMapIoError::WriteData(io_error(e)))
+}
+
+// Read-only map over a file written by CivMap::into_mapped_writer, keys and values are used
+//   in place. The file must not be changed while it is mapped.
+pub struct MappedCivMap<K,V> {
+    mmap: Mmap,
+    layout: Layout,
+    _kv: PhantomData<(K,V)>,
+}
+impl<K: Pod + Ord, V: Pod> MappedCivMap<K,V> {
+    // keys are checked to be sorted and unique (a scan of the keys section), lookups of a file
+    //   that isn't would miss keys
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedCivMap<K,V>,CivMapIoError> {
+        let map = MappedCivMap::open_unchecked(path)?;
+        if let Some(index) = map.keys().windows(2).position(|w| w[0] >= w[1]) {
+            return Err(CivMapIoError::Invalid(CivInvalid::Unsorted { level: 0, index: index + 1 }));
+        }
+        Ok(map)
+    }
+    // as open, but the order of the keys of a trusted file is not checked, nothing is read
+    //   beyond the header
+    pub fn open_unchecked<P: AsRef<Path>>(path: P) -> Result<MappedCivMap<K,V>,CivMapIoError> {
+        let file = File::open(path).map_err(|e| CivMapIoError::ReadData(io_error(e)))?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|e| CivMapIoError::ReadData(io_error(e)))?;
+        MappedCivMap::from_mmap(mmap)
+    }
+    fn from_mmap(mmap: Mmap) -> Result<MappedCivMap<K,V>,CivMapIoError> {
+        check_types::<K,V>()?;
+        if mmap.len() < HEADER_SIZE { return Err(CivMapIoError::ReadHeader); }
+        if &mmap[0 .. 4] != b"CIVP" { return Err(CivMapIoError::InvalidHeader); }
+        let u32_at = |off: usize| u32::from_le_bytes([mmap[off],mmap[off+1],mmap[off+2],mmap[off+3]]);
+        let u64_at = |off: usize| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&mmap[off .. off+8]);
+            buf
+        };
+        let (maj,min) = (u32_at(4),u32_at(8));
+        if (maj,min) != CURRENT_CIVS_MAPPED_VERSION { return Err(CivMapIoError::InvalidVersion(maj,min)); }
+        let mut fields = [0; 5];
+        for (i,x) in fields.iter_mut().enumerate() {
+            *x = u64::from_le_bytes(u64_at(12 + 8*i));
+        }
+        let types = [std::mem::size_of::<K>(),std::mem::align_of::<K>(),std::mem::size_of::<V>(),std::mem::align_of::<V>()];
+        if fields[1 ..].iter().zip(types.iter()).any(|(x,t)| *x != *t as u64)
+            ||(u64::from_ne_bytes(u64_at(52)) != BYTE_ORDER_MARK) {
+            return Err(CivMapIoError::InvalidLayout);
+        }
+        let layout = usize::try_from(fields[0]).ok()
+            .and_then(Layout::new::<K,V>)
+            .ok_or(CivMapIoError::InvalidLayout)?;
+        if layout.end > mmap.len() { return Err(CivMapIoError::InvalidLayout); }
+        let map = MappedCivMap {
+            mmap,
+            layout,
+            _kv: PhantomData,
+        };
+        // sections are aligned as the mapping starts at a page boundary
+        bytemuck::try_cast_slice::<u8,K>(map.key_bytes()).map_err(|_|CivMapIoError::InvalidLayout)?;
+        bytemuck::try_cast_slice::<u8,V>(map.value_bytes()).map_err(|_|CivMapIoError::InvalidLayout)?;
+        Ok(map)
+    }
+    fn key_bytes(&self) -> &[u8] {
+        &self.mmap[self.layout.keys .. self.layout.keys + self.layout.len * std::mem::size_of::<K>()]
+    }
+    fn value_bytes(&self) -> &[u8] {
+        &self.mmap[self.layout.values .. self.layout.end]
+    }
+    pub fn len(&self) -> usize {
+        self.layout.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.layout.len == 0
+    }
+    pub fn keys(&self) -> &[K] {
+        bytemuck::cast_slice(self.key_bytes())
+    }
+    pub fn values(&self) -> &[V] {
+        bytemuck::cast_slice(self.value_bytes())
+    }
+    pub fn contains(&self, k: &K) -> bool {
+        self.keys().binary_search(k).is_ok()
+    }
+    pub fn get(&self, k: &K) -> Option<&V> {
+        match self.keys().binary_search(k) {
+            Ok(idx) => Some(&self.values()[idx]),
+            Err(_) => None,
+        }
+    }
+    pub fn iter(&self) -> std::iter::Zip<std::slice::Iter<'_,K>,std::slice::Iter<'_,V>> {
+        self.keys().iter().zip(self.values())
+    }
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> std::iter::Zip<std::slice::Iter<'_,K>,std::slice::Iter<'_,V>> {
+        let span = range_span(self.keys(),&range);
+        self.keys()[span.clone()].iter().zip(&self.values()[span])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CivMap;
+
+    #[test]
+    fn mapped_map() {
+        let path = std::env::temp_dir().join(format!("civs-test-mapped-{}.civp",std::process::id()));
+        let mut map: CivMap<u64,u32> = CivMap::with_merge_op(|a,b| a + b);
+        let mut res = std::collections::BTreeMap::new();
+        for i in 0 .. 30_000u64 {
+            let k = (i * 7919) % 10_007;
+            map.upsert(k,1);
+            *res.entry(k).or_insert(0) += 1;
+            if i % 5 == 0 {
+                map.remove(&((i * 13) % 10_007));
+                res.remove(&((i * 13) % 10_007));
+            }
+        }
+        map.into_mapped_writer(std::io::BufWriter::new(File::create(&path).unwrap())).unwrap();
+
+        let mapped: MappedCivMap<u64,u32> = MappedCivMap::open(&path).unwrap();
+        assert_eq!(mapped.len(),res.len());
+        assert!(mapped.iter().map(|(k,v)| (*k,*v)).eq(res.iter().map(|(k,v)| (*k,*v))));
+        for k in 0 .. 10_007 {
+            assert_eq!(mapped.get(&k),res.get(&k));
+        }
+        assert!(mapped.range(100 .. 200).map(|(k,v)| (*k,*v)).eq(res.range(100 .. 200).map(|(k,v)| (*k,*v))));
+        assert!(mapped.range(..= 5_000).rev().map(|(k,_)| *k).eq(res.range(..= 5_000).rev().map(|(k,_)| *k)));
+        assert!(matches!(MappedCivMap::<u32,u32>::open(&path),Err(CivMapIoError::InvalidLayout)));
+
+        // keys out of order are found by open, open_unchecked trusts them
+        let mut bytes = std::fs::read(&path).unwrap();
+        let keys = align_up(HEADER_SIZE);
+        bytes.copy_within(keys .. keys + 8,keys + 8);
+        std::fs::write(&path,&bytes).unwrap();
+        assert!(matches!(MappedCivMap::<u64,u32>::open(&path),Err(CivMapIoError::Invalid(CivInvalid::Unsorted { level: 0, index: 1 }))));
+        assert_eq!(MappedCivMap::<u64,u32>::open_unchecked(&path).unwrap().len(),res.len());
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(200).unwrap();
+        assert!(matches!(MappedCivMap::<u64,u32>::open(&path),Err(CivMapIoError::InvalidLayout)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,18 +1,484 @@
-use serde::{Serialize,Deserialize,ser::{Serializer,SerializeStruct}};
+use serde::{Serialize,Deserialize,ser::{Serializer,SerializeStruct,SerializeSeq,Error as _},de::DeserializeOwned};
+use bincode::Options;
+use std::io::Read;
+use std::borrow::Borrow;
+use std::convert::Infallible;
+use std::ops::{Bound,Range,RangeBounds};
+use std::cmp::Ordering;
+use std::ptr;
 
-use crate::Filled;
+use crate::{Filled,Flags,CivInvalid,CivLimits};
 
 pub(crate) mod set;
 pub(crate) mod map;
+pub(crate) mod ttl;
+pub(crate) mod cursor;
+pub(crate) mod spill;
+#[cfg(feature = "bytemuck")]
+pub(crate) mod mapped;
 
 use set::SetMultiSlot;
-use map::MapMultiSlot;
+use spill::Counted;
 
 
 pub(crate) const TOMBS_LIMIT: f64 = 0.05;
 pub(crate) const AUTO_SHRINK_LIMIT: usize = 30_000_000;
 
 
+// index span of the sorted keys which are in range
+fn range_span<K: Ord, R: RangeBounds<K>>(keys: &[K], range: &R) -> Range<usize> {
+    match try_range_span(keys.len(),range,|pred| Ok::<_,Infallible>(keys.partition_point(pred))) {
+        Ok(span) => span,
+        Err(e) => match e {},
+    }
+}
+// range_span of len keys searched by partition_point
+fn try_range_span<K: Ord, R: RangeBounds<K>, E, P: FnMut(&dyn Fn(&K) -> bool) -> Result<usize,E>>(len: usize, range: &R, mut partition_point: P) -> Result<Range<usize>,E> {
+    let from = match range.start_bound() {
+        Bound::Included(a) => partition_point(&|k| k < a)?,
+        Bound::Excluded(a) => partition_point(&|k| k <= a)?,
+        Bound::Unbounded => 0,
+    };
+    let to = match range.end_bound() {
+        Bound::Included(b) => partition_point(&|k| k <= b)?,
+        Bound::Excluded(b) => partition_point(&|k| k < b)?,
+        Bound::Unbounded => len,
+    };
+    Ok(from .. std::cmp::max(from,to))
+}
+
+type OpRef<'t,V> = &'t dyn Fn(&V,&V) -> V;
+
+// Merges sorted b into sorted a from the back, growing the buffer of a in place; b is left empty.
+//   Entries of b are newer: equal keys are combined by op(older,newer) or kept in order older, newer.
+//   Both vectors are empty during the merge, so a panic of Ord or op leaks the entries
+//   instead of dropping them twice. Returns the count of folded entries.
+fn merge_back<K: Ord, V>(ak: &mut Vec<K>, av: &mut Vec<V>, bk: &mut Vec<K>, bv: &mut Vec<V>, op: Option<OpRef<V>>) -> usize {
+    let (la,lb) = (ak.len(),bk.len());
+    let concat = match (ak.last(),bk.first()) {
+        (Some(a),Some(b)) => a < b,
+        _ => true,
+    };
+    if concat {
+        ak.append(bk);
+        av.append(bv);
+        return 0;
+    }
+    ak.reserve_exact(lb);
+    av.reserve_exact(lb);
+    let mut folded = 0;
+    unsafe {
+        ak.set_len(0);
+        av.set_len(0);
+        bk.set_len(0);
+        bv.set_len(0);
+        let (pak,pav) = (ak.as_mut_ptr(),av.as_mut_ptr());
+        let (pbk,pbv) = (bk.as_mut_ptr(),bv.as_mut_ptr());
+        // w == i + j + folded, [w .. la+lb) is filled, w > i while j > 0
+        let (mut i, mut j, mut w) = (la,lb,la+lb);
+        while j > 0 {
+            let ord = match i {
+                0 => Ordering::Less,
+                _ => (*pak.add(i-1)).cmp(&*pbk.add(j-1)),
+            };
+            match (ord,op) {
+                (Ordering::Greater,_) => {
+                    i -= 1;
+                    w -= 1;
+                    ptr::write(pak.add(w),ptr::read(pak.add(i)));
+                    ptr::write(pav.add(w),ptr::read(pav.add(i)));
+                },
+                (Ordering::Equal,Some(op)) => {
+                    let v = op(&*pav.add(i-1),&*pbv.add(j-1));
+                    i -= 1;
+                    j -= 1;
+                    w -= 1;
+                    folded += 1;
+                    ptr::drop_in_place(pbk.add(j));
+                    ptr::drop_in_place(pbv.add(j));
+                    ptr::drop_in_place(pav.add(i));
+                    ptr::write(pak.add(w),ptr::read(pak.add(i)));
+                    ptr::write(pav.add(w),v);
+                },
+                _ => {
+                    j -= 1;
+                    w -= 1;
+                    ptr::write(pak.add(w),ptr::read(pbk.add(j)));
+                    ptr::write(pav.add(w),ptr::read(pbv.add(j)));
+                },
+            }
+        }
+        // a[0..i] stayed in place, closing the gap of folded entries
+        if w > i {
+            ptr::copy(pak.add(w),pak.add(i),la+lb-w);
+            ptr::copy(pav.add(w),pav.add(i),la+lb-w);
+        }
+        ak.set_len(la+lb-folded);
+        av.set_len(la+lb-folded);
+    }
+    folded
+}
+
+// capacity of the level in the layout of the slot size, None if it overflows
+fn level_capacity(slot_size: usize, level: usize) -> Option<usize> {
+    slot_size.checked_mul(1usize.checked_shl(level as u32)?)
+}
+
+// error of a reservation of more then usize::MAX bytes
+fn capacity_overflow() -> std::collections::TryReserveError {
+    match Vec::<u8>::new().try_reserve(usize::MAX) {
+        Err(e) => e,
+        Ok(()) => unreachable!(),
+    }
+}
+
+// number of the levels of the slot size needed to hold total entries, as the levels [0,n)
+//   hold slot_size * (2^n - 1), None if the capacity overflows
+fn levels_for(slot_size: usize, total: usize) -> Option<usize> {
+    let mut levels = 0;
+    while level_capacity(slot_size,levels)? - slot_size < total {
+        levels += 1;
+    }
+    Some(levels)
+}
+
+// level in the layout of the slot size: flags of the capacity, keys sorted and unique
+//   (dead ones too), no live flags behind the keys
+// keys are the len keys of the level, the ones of a spilled level are streamed
+fn check_level<K: Ord, B: Borrow<K>>(level: usize, slot_size: usize, capacity: usize, flags: &Flags, len: usize, keys: impl Iterator<Item = B>) -> Result<(),CivInvalid> {
+    let expected = level_capacity(slot_size,level).unwrap_or(usize::MAX);
+    if capacity != expected { return Err(CivInvalid::Capacity { level, capacity, expected }); }
+    if flags.bits.len() != capacity.div_ceil(64) {
+        return Err(CivInvalid::Flags { level, words: flags.bits.len(), expected: capacity.div_ceil(64) });
+    }
+    let dead_tail = flags.bits.iter().enumerate().any(|(w,bits)| match len.saturating_sub(w * 64) {
+        from if from < 64 => (bits >> from) != 0,
+        _ => false,
+    });
+    if dead_tail { return Err(CivInvalid::DeadTail { level }); }
+    let mut prev: Option<B> = None;
+    for (index,k) in keys.enumerate() {
+        if prev.is_some_and(|p| p.borrow() >= k.borrow()) {
+            return Err(CivInvalid::Unsorted { level, index });
+        }
+        prev = Some(k);
+    }
+    Ok(())
+}
+
+// v0.1 files are the bincode encoding of the slot and the levels, they are read only.
+//   Since v0.2 the header is followed by a directory (the type fingerprint, whether the file is compacted
+//   and the sections with the CRC32 of their bytes) and then by the sections in its order: the slot and
+//   the levels. Offsets are from the start of the file. The sections are portable: metadata is u64
+//   instead of usize and there are no sizes of the types, which are checked by the fingerprint.
+//   All the integers are of fixed width and little endian.
+const SECTIONED_HEADER_SIZE: u64 = 12;
+
+// versions of the map and set files read, v0.2 is the one written
+fn is_file_version(version: (u32,u32)) -> bool {
+    matches!(version,(0,1)|(0,2))
+}
+
+// bincode configuration of the sectioned files, the one of bincode::serialize stated explicitly
+fn file_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct Directory {
+    fingerprint: Fingerprint,
+    compacted: Compacted,
+    slot: Section,
+    levels: Vec<Section>,
+}
+// Compacted files hold the live entries only: the level sections are runs of sorted keys (and values)
+//   without flags. Runs of Levels are the levels in their order, the single run of Single holds all
+//   the entries and is spread over the slot and full levels on loading.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+enum Compacted {
+    No,
+    Levels,
+    Single,
+}
+#[derive(Debug,Clone,Copy,Serialize,Deserialize)]
+struct Section {
+    offset: u64,
+    length: u64,
+    // offsets of the encoded vectors from the section start: slot data, level keys and values or set data
+    columns: [u64; 2],
+    checksum: u32,
+}
+
+impl Section {
+    // section of the encoded t
+    fn of<T: Serialize>(t: &T, columns: [u64; 2]) -> Result<Section,bincode::Error> {
+        let mut crc = Crc::new(std::io::sink());
+        file_options().serialize_into(&mut crc,t)?;
+        Ok(Section {
+            offset: 0,
+            length: crc.length,
+            columns,
+            checksum: crc.hasher.finalize(),
+        })
+    }
+}
+// Type fingerprint of a file: of the size and alignment of the key and value types, with a hash set
+//   by the user (e.g. of a schema) when the layout alone doesn't tell the types apart. v0.1 files
+//   have none.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+struct Fingerprint {
+    layout: u64,
+    user: Option<u64>,
+}
+impl Fingerprint {
+    fn new<K,V>(user: Option<u64>) -> Fingerprint {
+        Fingerprint { layout: layout_fingerprint::<K,V>(), user }
+    }
+    // user fingerprint of the loaded file, Err((expected,found)) if it isn't the expected one
+    fn check<K,V>(found: Option<Fingerprint>, expected: Expected) -> Result<Option<u64>,(u64,Option<u64>)> {
+        let layout = layout_fingerprint::<K,V>();
+        match (found,expected) {
+            (_,Expected::Unchecked) => Ok(found.and_then(|fp| fp.user)),
+            (Some(fp),_) if fp.layout != layout => Err((layout,Some(fp.layout))),
+            (found,Expected::User(e)) => match found.and_then(|fp| fp.user) {
+                Some(user) if user == e => Ok(Some(user)),
+                user => Err((e,user)),
+            },
+            (found,Expected::Layout) => Ok(found.and_then(|fp| fp.user)),
+        }
+    }
+}
+// fingerprint a file is read with
+#[derive(Debug,Clone,Copy)]
+enum Expected {
+    // of the key and value layout, the default
+    Layout,
+    // the layout and the one set by set_fingerprint
+    User(u64),
+    Unchecked,
+}
+
+// FNV-1a of the size and alignment of the key and value types
+fn layout_fingerprint<K,V>() -> u64 {
+    let layout = [std::mem::size_of::<K>(),std::mem::align_of::<K>(),std::mem::size_of::<V>(),std::mem::align_of::<V>()];
+    let bytes = layout.into_iter().flat_map(|n| (n as u64).to_le_bytes());
+    bytes.fold(0xcbf29ce484222325,|h,b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl Directory {
+    // places the sections one after another behind the directory
+    fn new(fingerprint: Fingerprint, compacted: Compacted, slot: Section, levels: Vec<Section>) -> Result<Directory,bincode::Error> {
+        let mut dir = Directory { fingerprint, compacted, slot, levels };
+        let mut offset = SECTIONED_HEADER_SIZE + dir.write(std::io::sink())?;
+        for section in std::iter::once(&mut dir.slot).chain(dir.levels.iter_mut()) {
+            section.offset = offset;
+            offset += section.length;
+        }
+        Ok(dir)
+    }
+    // directory of a sectioned file, None if the sections can't be read from a stream
+    fn read<R: Read>(rdr: &mut R) -> Result<Option<Directory>,bincode::Error> {
+        let dir: Directory = file_options().deserialize_from(rdr)?;
+        let size = file_options().serialized_size(&dir)?;
+        Ok(match dir.is_contiguous(SECTIONED_HEADER_SIZE + size) {
+            true => Some(dir),
+            false => None,
+        })
+    }
+    // returns the encoded size
+    fn write<W: std::io::Write>(&self, wrt: W) -> Result<u64,bincode::Error> {
+        let mut wrt = Crc::new(wrt);
+        file_options().serialize_into(&mut wrt,self)?;
+        Ok(wrt.length)
+    }
+    fn is_contiguous(&self, mut offset: u64) -> bool {
+        for section in std::iter::once(&self.slot).chain(self.levels.iter()) {
+            if section.offset != offset { return false; }
+            offset = match offset.checked_add(section.length) {
+                Some(off) => off,
+                None => return false,
+            };
+        }
+        true
+    }
+}
+
+// sequence of the items of a fresh iterator, for the runs of compacted files which are encoded
+//   twice: for the directory and to the file
+struct Items<F>(usize,F);
+impl<F,I> Serialize for Items<F>
+where
+    F: Fn() -> I,
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0))?;
+        let mut n = 0;
+        for item in (self.1)() {
+            seq.serialize_element(&item)?;
+            n += 1;
+        }
+        // entries of a spilled level which can't be read are missing
+        if n != self.0 {
+            return Err(S::Error::custom(format!("{} items of {} are written",n,self.0)));
+        }
+        seq.end()
+    }
+}
+
+// lengths of the slot and the levels holding a single run of len entries, the levels are full
+fn single_run_parts(len: usize, slot_size: usize) -> (usize,Vec<usize>) {
+    let mut levels = Vec::new();
+    let mut count = len / slot_size;
+    let mut capacity = slot_size;
+    while count > 0 {
+        levels.push(match count & 1 {
+            1 => capacity,
+            _ => 0,
+        });
+        count >>= 1;
+        capacity <<= 1;
+    }
+    (len % slot_size,levels)
+}
+
+// counts and hashes the bytes written or read through it
+struct Crc<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+    length: u64,
+}
+impl<T> Crc<T> {
+    fn new(inner: T) -> Crc<T> {
+        Crc {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            length: 0,
+        }
+    }
+    fn matches(&self, section: &Section) -> bool {
+        (self.length == section.length)&&(self.hasher.clone().finalize() == section.checksum)
+    }
+}
+impl<W: std::io::Write> std::io::Write for Crc<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[.. n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<R: Read> Read for Crc<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[.. n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+}
+
+#[derive(Debug)]
+struct LimitExceeded;
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"bytes limit exceeded")
+    }
+}
+impl std::error::Error for LimitExceeded {}
+
+// reader failing with LimitExceeded instead of reading more than the limit
+struct Bounded<R> {
+    inner: R,
+    left: u64,
+}
+impl<R> Bounded<R> {
+    fn new(inner: R, limit: u64) -> Bounded<R> {
+        Bounded { inner, left: limit }
+    }
+}
+impl<R: Read> Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        if self.left == 0 { return Err(std::io::Error::other(LimitExceeded)); }
+        let max = std::cmp::min(buf.len() as u64,self.left) as usize;
+        let n = self.inner.read(&mut buf[.. max])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+// counts the entries of a loaded level, false if it exceeds the limits
+fn within_limits(limits: &CivLimits, entries: &mut usize, capacity: usize, len: usize) -> bool {
+    *entries = entries.saturating_add(len);
+    (capacity <= limits.level_capacity)&&(*entries <= limits.entries)
+}
+
+fn is_limit(e: &bincode::Error) -> bool {
+    match &**e {
+        bincode::ErrorKind::Io(e) => e.get_ref().is_some_and(|e| e.is::<LimitExceeded>()),
+        _ => false,
+    }
+}
+
+enum SectionError {
+    Checksum,
+    Data(bincode::Error),
+}
+impl SectionError {
+    fn or<E>(self, checksum: E, limit: E, data: fn(bincode::Error) -> E) -> E {
+        match self {
+            SectionError::Checksum => checksum,
+            SectionError::Data(e) => limit_or(e,limit,data),
+        }
+    }
+}
+fn limit_or<E>(e: bincode::Error, limit: E, data: fn(bincode::Error) -> E) -> E {
+    match is_limit(&e) {
+        true => limit,
+        false => data(e),
+    }
+}
+
+// reads exactly the section, the reader must be at its offset. The checksum is verified,
+//   a broken section is reported as such even if it can't be decoded.
+fn read_section<T: DeserializeOwned, R: Read>(rdr: &mut R, section: &Section) -> Result<T,SectionError> {
+    let mut rdr = Crc::new(rdr.take(section.length));
+    let res = file_options().deserialize_from(&mut rdr);
+    // the section can't be checked if the rest of it is unreadable
+    if let Err(e) = std::io::copy(&mut rdr,&mut std::io::sink()) {
+        return Err(SectionError::Data(res.err().unwrap_or_else(|| e.into())));
+    }
+    if !rdr.matches(section) { return Err(SectionError::Checksum); }
+    res.map_err(SectionError::Data)
+}
+
+fn read_slot<K: DeserializeOwned, V: DeserializeOwned, R: Read>(rdr: &mut R, section: &Section) -> Result<Slot<K,V>,SectionError> {
+    read_section::<PortableSlot<K,V>,R>(rdr,section).map(|slot| slot.0)
+}
+
+// reads exactly the section through f, which gets the file position of the reader. The checksum
+//   is verified as by read_section.
+fn scan_section<T, R: Read, F>(rdr: &mut R, section: &Section, f: F) -> Result<T,SectionError>
+where
+    F: FnOnce(&mut Counted<Crc<std::io::Take<&mut R>>>) -> Result<T,bincode::Error>,
+{
+    let mut rdr = Counted::new(Crc::new(rdr.take(section.length)),section.offset);
+    let res = f(&mut rdr);
+    let mut rdr = rdr.into_inner();
+    if let Err(e) = std::io::copy(&mut rdr,&mut std::io::sink()) {
+        return Err(SectionError::Data(res.err().unwrap_or_else(|| e.into())));
+    }
+    if !rdr.matches(section) { return Err(SectionError::Checksum); }
+    res.map_err(SectionError::Data)
+}
+
 #[derive(Deserialize)]
 struct SerdeSlot<K,V> {
     size: usize,
@@ -47,6 +513,37 @@ where
     }
 }
 
+// slot of a sectioned file
+#[derive(Serialize)]
+struct SlotRef<'t,K,V> {
+    size: u64,
+    data: &'t [(K,V)],
+}
+impl<'t,K: Serialize, V: Serialize> SlotRef<'t,K,V> {
+    // the data column follows the size
+    fn section(&self) -> Result<Section,bincode::Error> {
+        Section::of(self,[file_options().serialized_size(&self.size)?,0])
+    }
+}
+#[derive(Deserialize)]
+struct SerdePortableSlot<K,V> {
+    size: u64,
+    data: Vec<(K,V)>,
+}
+#[derive(Deserialize)]
+#[serde(try_from = "SerdePortableSlot<K,V>")]
+struct PortableSlot<K,V>(Slot<K,V>);
+impl<K,V> std::convert::TryFrom<SerdePortableSlot<K,V>> for PortableSlot<K,V> {
+    type Error = String;
+    fn try_from(slot: SerdePortableSlot<K,V>) -> Result<PortableSlot<K,V>,String> {
+        let size = usize::try_from(slot.size).map_err(|_| format!("Unvalid slot size {}",slot.size))?;
+        Ok(PortableSlot(Slot {
+            size,
+            data: slot.data,
+        }))
+    }
+}
+
 #[derive(Debug,Clone,Deserialize)]
 #[serde(try_from = "SerdeSlot<K,V>")]
 struct Slot<K,V>{
@@ -54,6 +551,18 @@ struct Slot<K,V>{
     data: Vec<(K,V)>,
 }
 impl<K,V> Slot<K,V> {
+    fn portable(&self) -> SlotRef<'_,K,V> {
+        SlotRef {
+            size: self.size as u64,
+            data: &self.data,
+        }
+    }
+    fn section(&self) -> Result<Section,bincode::Error> where K: Serialize, V: Serialize {
+        self.portable().section()
+    }
+    fn write<W: std::io::Write>(&self, wrt: W) -> Result<(),bincode::Error> where K: Serialize, V: Serialize {
+        file_options().serialize_into(wrt,&self.portable())
+    }
     fn len(&self) -> usize {
         self.data.len()
     }
@@ -63,6 +572,18 @@ impl<K,V> Slot<K,V> {
     fn heap_mem(&self) -> usize {
         self.data.capacity() * std::mem::size_of::<(K,V)>()
     }
+    fn check(&self) -> Result<(),CivInvalid> where K: Ord {
+        if (self.size == 0)||(self.data.len() > self.size) {
+            return Err(CivInvalid::Slot);
+        }
+        // the slot is unsorted, a sorted copy of its keys has duplicates side by side
+        let mut keys: Vec<&K> = self.data.iter().map(|(k,_)| k).collect();
+        keys.sort_unstable();
+        match keys.windows(2).any(|w| w[0] == w[1]) {
+            true => Err(CivInvalid::Slot),
+            false => Ok(()),
+        }
+    }
 }
 impl<K: Ord,V> Slot<K,V> {
     fn new() -> Slot<K,V> {
@@ -92,12 +613,6 @@ impl<K: Ord,V> Slot<K,V> {
             None => None,
         }
     }
-    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
-        match self.contains(k) {
-            Some(idx) => Some(&mut self.data[idx].1),
-            None => None,
-        }
-    }
     fn insert(&mut self, k: K, v: V) -> (Option<V>,Filled) {
         let opt_v = match self.contains(&k) {
             Some(idx) => {
@@ -112,6 +627,23 @@ impl<K: Ord,V> Slot<K,V> {
         };
         (opt_v,if self.data.len() >= self.size { Filled::Full } else { Filled::HasSlots })
     }
+    fn push(&mut self, k: K, v: V) -> Filled {
+        self.data.push((k,v));
+        if self.data.len() >= self.size { Filled::Full } else { Filled::HasSlots }
+    }
+    fn upsert(&mut self, k: K, v: V, op: &dyn Fn(&V,&V) -> V) -> (bool,Filled) { // is new entry
+        let new = match self.contains(&k) {
+            Some(idx) => {
+                self.data[idx].1 = op(&self.data[idx].1,&v);
+                false
+            },
+            None => {
+                self.data.push((k,v));
+                true
+            },
+        };
+        (new,if self.data.len() >= self.size { Filled::Full } else { Filled::HasSlots })
+    }
     fn remove(&mut self, k: &K) -> Option<V> {
         match self.contains(&k) {
             Some(idx) => Some(self.data.swap_remove(idx).1),
@@ -121,18 +653,21 @@ impl<K: Ord,V> Slot<K,V> {
     fn clear(&mut self) {
         self.data.clear();
     }
+    fn remove_range<R: RangeBounds<K>>(&mut self, range: &R) -> usize {
+        let ln = self.data.len();
+        self.data.retain(|(k,_)| !range.contains(k));
+        ln - self.data.len()
+    }
+    // keys are unique, the unstable sort does not allocate
+    fn sort(&mut self) {
+        self.data.sort_unstable_by(|(k1,_),(k2,_)|k1.cmp(k2));
+    }
     fn sorted_drain(&mut self) -> std::vec::Drain<(K,V)> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
+        self.sort();
         self.data.drain(..)
     }
-    fn into_map_multislot(&mut self) -> MapMultiSlot<K,V> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
-        let vc: Vec<(K,V)> = self.data.drain(..).collect();
-        self.clear();
-        MapMultiSlot::new(vc)
-    }
     fn into_set_multislot(&mut self) -> SetMultiSlot<K> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
+        self.sort();
         let vc: Vec<K> = self.data.drain(..).map(|(k,_)|k).collect();
         self.clear();
         SetMultiSlot::new(vc)
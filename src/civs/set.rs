@@ -1,14 +1,26 @@
 use serde::{
     Serialize,Deserialize,
-    ser::{Serializer,SerializeStruct},
-    de::DeserializeOwned,
+    ser::{Serializer,SerializeStruct,SerializeSeq},
+    de::{Deserializer,DeserializeOwned,Visitor,SeqAccess,Error as _},
 };
 use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
-use std::io::{Read,Write};
+use bincode::Options;
+use std::io::{self,Read,Write,Seek,SeekFrom,BufReader};
+use std::ops::RangeBounds;
+use std::collections::TryReserveError;
+use std::path::Path;
+use std::sync::{Arc,atomic::{AtomicBool,Ordering}};
+use std::borrow::Borrow;
 
 use crate::{
-    Flags,Filled,Binary,
-    civs::{Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT},
+    Flags,Ones,Filled,Binary,CivError,CivInvalid,CivLimits,Layout,
+    civs::{
+        Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT,try_range_span,merge_back,
+        Directory,Compacted,Section,Fingerprint,Expected,SectionError,SlotRef,PortableSlot,Items,level_capacity,levels_for,capacity_overflow,single_run_parts,file_options,read_section,read_slot,scan_section,is_file_version,check_level,
+        Bounded,within_limits,limit_or,
+        cursor::{Runs,Heads,sorted_order},
+        spill::{Column,SpillFile,index_column,read_ok,read_keys},
+    },
 };
 
 #[derive(Deserialize)]
@@ -20,15 +32,13 @@ struct SerdeSetMultiSlot<K> {
 }
 impl<K> std::convert::TryFrom<SerdeSetMultiSlot<K>> for SetMultiSlot<K> {
     type Error = String;
-    fn try_from(mut slot: SerdeSetMultiSlot<K>) -> Result<SetMultiSlot<K>,String> {
+    // buffer is reserved up to the capacity by CivSet::from_parts, after the limits are checked
+    fn try_from(slot: SerdeSetMultiSlot<K>) -> Result<SetMultiSlot<K>,String> {
         if slot.data_size != std::mem::size_of::<K>() { return Err(format!("Unvalid data size {}, must be {}",std::mem::size_of::<K>(),slot.data_size)); }
-        if (slot.data.len() > 0) && (slot.data.len() < slot.capacity) {
-            slot.data.reserve(slot.capacity - slot.data.len());
-        }
         Ok(SetMultiSlot {
             capacity: slot.capacity,
-            flags: Flags(slot.flags),
-            data: slot.data,
+            flags: Flags::from_words(slot.flags),
+            data: slot.data.into(),
         })
     }
 }
@@ -47,78 +57,203 @@ where
     }
 }
 
+// level since v0.2
+#[derive(Serialize)]
+struct SetMultiSlotRef<'t,K> {
+    capacity: u64,
+    flags: &'t Flags,
+    data: &'t Column<K>,
+}
+#[derive(Deserialize)]
+struct SerdePortableSetMultiSlot<K> {
+    capacity: u64,
+    flags: Vec<u64>,
+    data: Vec<K>,
+}
+#[derive(Deserialize)]
+#[serde(try_from = "SerdePortableSetMultiSlot<K>")]
+struct PortableSetMultiSlot<K>(SetMultiSlot<K>);
+impl<K> std::convert::TryFrom<SerdePortableSetMultiSlot<K>> for PortableSetMultiSlot<K> {
+    type Error = String;
+    fn try_from(slot: SerdePortableSetMultiSlot<K>) -> Result<PortableSetMultiSlot<K>,String> {
+        let capacity = usize::try_from(slot.capacity).map_err(|_| format!("Unvalid capacity {}",slot.capacity))?;
+        Ok(PortableSetMultiSlot(SetMultiSlot {
+            capacity,
+            flags: Flags::from_words(slot.flags),
+            data: slot.data.into(),
+        }))
+    }
+}
+
 #[derive(Debug,Clone,Deserialize)]
 #[serde(try_from = "SerdeSetMultiSlot<K>")]
 pub(crate) struct SetMultiSlot<K> {
     capacity: usize,
     flags: Flags,
-    data: Vec<K>,
+    data: Column<K>,
 }
 impl<K> SetMultiSlot<K> {
     fn heap_mem(&self) -> usize {
         self.flags.heap_mem() + self.data.capacity() * std::mem::size_of::<K>()
     }
+    fn portable(&self) -> SetMultiSlotRef<'_,K> {
+        SetMultiSlotRef {
+            capacity: self.capacity as u64,
+            flags: &self.flags,
+            data: &self.data,
+        }
+    }
+    fn section(&self) -> Result<Section,bincode::Error> where K: Serialize {
+        // the flags follow the capacity
+        let header = file_options().serialized_size(&(self.capacity as u64))?;
+        let data = header + file_options().serialized_size(&self.flags)?;
+        Section::of(&self.portable(),[data,0])
+    }
+    fn write<W: Write>(&self, wrt: W) -> Result<(),bincode::Error> where K: Serialize {
+        file_options().serialize_into(wrt,&self.portable())
+    }
+    fn read<R: Read>(rdr: &mut R, section: &Section) -> Result<SetMultiSlot<K>,SectionError>
+    where
+        K: DeserializeOwned,
+    {
+        read_section::<PortableSetMultiSlot<K>,R>(rdr,section).map(|ms| ms.0)
+    }
+    // Level of a sectioned file with the keys left in the file, the reader must be at its offset.
+    //   The section is read through once to check it and to index the keys.
+    fn lazy<R: Read>(rdr: &mut R, section: &Section, file: &Arc<SpillFile>) -> Result<SetMultiSlot<K>,SectionError>
+    where
+        K: DeserializeOwned,
+    {
+        scan_section(rdr,section,|rdr| {
+            let (capacity,flags): (u64,Vec<u64>) = bincode::deserialize_from(&mut *rdr)?;
+            let capacity = usize::try_from(capacity).map_err(|_| bincode::ErrorKind::Custom(format!("Unvalid capacity {}",capacity)))?;
+            let data_offset = rdr.position();
+            let (len,marks) = index_column::<K,_>(rdr)?;
+            let data = match len {
+                0 => Column::default(),
+                _ => Column::spilled(file.clone(),data_offset,len,Some(marks)),
+            };
+            Ok(SetMultiSlot {
+                capacity,
+                flags: Flags::from_words(flags),
+                data,
+            })
+        })
+    }
 }
 impl<K: Ord> SetMultiSlot<K> {
+    // level of a run of a compacted file, the keys are live
+    fn run(capacity: usize, data: Vec<K>) -> SetMultiSlot<K> {
+        let mut flags = Flags::nulls(capacity);
+        flags.set_ones(data.len());
+        SetMultiSlot {
+            capacity,
+            flags,
+            data: data.into(),
+        }
+    }
+    fn try_new_empty(sz: usize, slot_sz: usize) -> Result<SetMultiSlot<K>,TryReserveError> {
+        let cap = level_capacity(slot_sz,sz-1).ok_or_else(capacity_overflow)?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(cap)?;
+        Ok(SetMultiSlot {
+            capacity: cap,
+            flags: Flags::try_nulls(cap)?,
+            data: data.into(),
+        })
+    }
     fn new_empty(sz: usize, slot_sz: usize) -> SetMultiSlot<K> {
         let cap = slot_sz * (0x1 << (sz-1));
         SetMultiSlot {
             capacity: cap,
             flags: Flags::nulls(cap),
-            data: Vec::with_capacity(cap),
+            data: Column::default(),
         }
     }
     pub(crate) fn new(data: Vec<K>) -> SetMultiSlot<K> {
         SetMultiSlot {
             capacity: data.len(),
             flags: Flags::ones(data.len()),
-            data: data,
+            data: data.into(),
         }
     }
     fn empty(&self) -> bool {
         self.data.len() == 0
     }
     fn check_len(&self) -> usize {
-        self.flags.0.iter().fold(0,|acc,x| acc + x.count_ones() as usize)
+        self.flags.count()
     }
-    fn contains(&self, k: &K) -> Option<usize> {
-        if (self.data.len() == 0)||(*k < self.data[0])||(*k > self.data[self.data.len()-1]) { return None; }
-        match self.data.binary_search(k) {
+    fn contains(&self, k: &K) -> io::Result<Option<usize>> {
+        if let Some(data) = self.data.as_slice() {
+            if data.is_empty()||(*k < data[0])||(*k > data[data.len()-1]) { return Ok(None); }
+        }
+        Ok(match self.data.binary_search(k)? {
             Ok(idx) => match self.flags.get(idx) {
                 true => Some(idx),
                 false => None,
             },
             Err(_) => None,
+        })
+    }
+    fn span<R: RangeBounds<K>>(&self, range: &R) -> io::Result<std::ops::Range<usize>> {
+        try_range_span(self.data.len(),range,|pred| self.data.partition_point(pred))
+    }
+    // a level of a lazily opened set is read back to be changed
+    fn unspill(&mut self) -> io::Result<()> {
+        if self.data.is_spilled() {
+            self.data = self.data.read_all()?.into();
         }
-    }            
+        Ok(())
+    }
     fn clear(&mut self) {
         self.flags.set_nulls();
         self.data.clear();
     }
+    // drops dead keys, flags are left as is
+    fn retain_live(&mut self) {
+        if self.flags.count() == self.data.len() { return; }
+        let flags = &self.flags;
+        let mut idx = 0;
+        self.data.heap().retain(|_| { idx += 1; flags.get(idx-1) });
+    }
     fn shrink_to_fit(&mut self) {
         self.data.shrink_to_fit();
     }
-    fn filtered_iter(&self) -> SetMultiSlotFilterIterator<K> {
+    fn filtered_iter<'t>(&'t self, poisoned: &'t AtomicBool) -> SetMultiSlotFilterIterator<'t,K> {
         SetMultiSlotFilterIterator {
-            iter: self.data.iter().enumerate(),
-            flags: &self.flags,
+            data: &self.data,
+            ones: self.flags.iter_ones_range(0,self.data.len()),
+            poisoned,
         }
     }
 }
 
+// keys which can't be read are skipped
 struct SetMultiSlotFilterIterator<'t,K> {
-    iter: std::iter::Enumerate<std::slice::Iter<'t,K>>,
-    flags: &'t Flags,
+    data: &'t Column<K>,
+    ones: Ones<'t>,
+    poisoned: &'t AtomicBool,
 }
 impl<'t,K> Iterator for SetMultiSlotFilterIterator<'t,K> {
     type Item = &'t K;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        loop {
+            let n = self.ones.next()?;
+            if let Some(k) = read_ok(self.poisoned,data.get(n)) {
+                return Some(k);
+            }
+        }
+    }
+}
+impl<'t,K> DoubleEndedIterator for SetMultiSlotFilterIterator<'t,K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let data = self.data;
         loop {
-            match self.iter.next() {
-                Some((n,k)) if self.flags.get(n) => break Some(k),
-                Some(_) => continue,
-                None => break None,
+            let n = self.ones.next_back()?;
+            if let Some(k) = read_ok(self.poisoned,data.get(n)) {
+                return Some(k);
             }
         }
     }
@@ -148,9 +283,206 @@ impl<'t,K> Iterator for Iter<'t,K> {
         None
     }
 }
+impl<'t,K> DoubleEndedIterator for Iter<'t,K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while !self.data_iter.is_empty() {
+            match self.data_iter[0].next_back() {
+                Some(k) => return Some(k),
+                None => { self.data_iter.remove(0); },
+            }
+        }
+        if let Some(iter) = &mut self.cur_data_iter {
+            if let Some(k) = iter.next_back() {
+                return Some(k);
+            }
+        }
+        match &mut self.slot_iter {
+            Some(iter) => iter.next_back().map(|(k,())| k),
+            None => None,
+        }
+    }
+}
+
+
+struct SetRuns<'t,K> {
+    slot: &'t [(K,())],
+    order: &'t [usize],
+    data: &'t [SetMultiSlot<K>],
+    poisoned: &'t AtomicBool,
+}
+impl<'t,K> Runs<K> for SetRuns<'t,K> {
+    fn runs(&self) -> usize {
+        self.data.len() + 1
+    }
+    fn run_len(&self, r: usize) -> usize {
+        match r {
+            0 => self.order.len(),
+            _ => self.data[r-1].data.len(),
+        }
+    }
+    fn key(&self, r: usize, p: usize) -> Option<&K> {
+        match r {
+            0 => Some(&self.slot[self.order[p]].0),
+            _ => read_ok(self.poisoned,self.data[r-1].data.get(p)),
+        }
+    }
+    fn next_live(&self, r: usize, from: usize) -> usize {
+        match r {
+            0 => std::cmp::min(from,self.order.len()),
+            _ => {
+                let ms = &self.data[r-1];
+                ms.flags.iter_ones_range(from,ms.data.len()).next().unwrap_or(ms.data.len())
+            },
+        }
+    }
+    fn prev_live(&self, r: usize, before: usize) -> Option<usize> {
+        match r {
+            0 => before.checked_sub(1),
+            _ => self.data[r-1].flags.prev_one(before),
+        }
+    }
+}
+
+pub struct SortedIter<'t,K> {
+    set: &'t CivSet<K>,
+    order: Vec<usize>,
+    front: Heads,
+    back: Heads,
+    left: usize,
+}
+impl<'t,K: Ord> Iterator for SortedIter<'t,K> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        let set: &'t CivSet<K> = self.set;
+        self.front.next(&set.runs(&self.order)).and_then(|pos| set.entry_at(&self.order,pos))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left,Some(self.left))
+    }
+}
+impl<'t,K: Ord> DoubleEndedIterator for SortedIter<'t,K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        let set: &'t CivSet<K> = self.set;
+        self.back.prev(&set.runs(&self.order)).and_then(|pos| set.entry_at(&self.order,pos))
+    }
+}
+impl<'t,K: Ord> ExactSizeIterator for SortedIter<'t,K> {}
+
+pub struct Cursor<'t,K> {
+    set: &'t CivSet<K>,
+    order: Vec<usize>,
+    heads: Heads,
+}
+impl<'t,K: Ord> Cursor<'t,K> {
+    fn entry(&self, pos: Option<(usize,usize)>) -> Option<&'t K> {
+        let set: &'t CivSet<K> = self.set;
+        pos.and_then(|pos| set.entry_at(&self.order,pos))
+    }
+    pub fn current(&self) -> Option<&'t K> {
+        self.entry(self.heads.current())
+    }
+    // moves to the first key not less then k
+    pub fn seek(&mut self, k: &K) -> Option<&'t K> {
+        let pos = self.heads.seek(&self.set.runs(&self.order),k);
+        self.entry(pos)
+    }
+    pub fn seek_first(&mut self) -> Option<&'t K> {
+        self.heads = Heads::first(&self.set.runs(&self.order));
+        self.next()
+    }
+    pub fn seek_last(&mut self) -> Option<&'t K> {
+        self.heads = Heads::last(&self.set.runs(&self.order));
+        self.prev()
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&'t K> {
+        let pos = self.heads.next(&self.set.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn prev(&mut self) -> Option<&'t K> {
+        let pos = self.heads.prev(&self.set.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn peek_next(&self) -> Option<&'t K> {
+        self.entry(self.heads.peek_next(&self.set.runs(&self.order)))
+    }
+    pub fn peek_prev(&self) -> Option<&'t K> {
+        self.entry(self.heads.peek_prev(&self.set.runs(&self.order)))
+    }
+}
+
+pub struct CursorMut<'t,K> {
+    set: &'t mut CivSet<K>,
+    order: Vec<usize>,
+    heads: Heads,
+}
+impl<'t,K: Ord> CursorMut<'t,K> {
+    fn entry(&self, pos: Option<(usize,usize)>) -> Option<&K> {
+        pos.and_then(|pos| self.set.entry_at(&self.order,pos))
+    }
+    pub fn current(&self) -> Option<&K> {
+        self.entry(self.heads.current())
+    }
+    pub fn seek(&mut self, k: &K) -> Option<&K> {
+        let pos = self.heads.seek(&self.set.runs(&self.order),k);
+        self.entry(pos)
+    }
+    pub fn seek_first(&mut self) -> Option<&K> {
+        self.heads = Heads::first(&self.set.runs(&self.order));
+        self.next()
+    }
+    pub fn seek_last(&mut self) -> Option<&K> {
+        self.heads = Heads::last(&self.set.runs(&self.order));
+        self.prev()
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&K> {
+        let pos = self.heads.next(&self.set.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn prev(&mut self) -> Option<&K> {
+        let pos = self.heads.prev(&self.set.runs(&self.order));
+        self.entry(pos)
+    }
+    pub fn peek_next(&self) -> Option<&K> {
+        self.entry(self.heads.peek_next(&self.set.runs(&self.order)))
+    }
+    pub fn peek_prev(&self) -> Option<&K> {
+        self.entry(self.heads.peek_prev(&self.set.runs(&self.order)))
+    }
+    // removes current key, the cursor is left in the gap between its neighbours
+    pub fn remove_current(&mut self) -> bool {
+        let (r,p) = match self.heads.current() {
+            Some(pos) => pos,
+            None => return false,
+        };
+        match r {
+            0 => {
+                let idx = self.order.remove(p);
+                let last = self.set.slot.len() - 1;
+                self.set.slot.data.swap_remove(idx);
+                for i in self.order.iter_mut() {
+                    if *i == last { *i = idx; }
+                }
+            },
+            _ => {
+                self.set.tombs += 1;
+                self.set.data[r-1].flags.unset(p);
+            },
+        }
+        self.set.len -= 1;
+        self.heads.removed(&self.set.runs(&self.order),r,p);
+        true
+    }
+}
 
 
-const CURRENT_CIVS_SET_VERSION: (u32,u32) = (0,1);
+const CURRENT_CIVS_SET_VERSION: (u32,u32) = (0,2);
 
 #[derive(Debug)]
 pub enum CivSetIoError {
@@ -162,6 +494,14 @@ pub enum CivSetIoError {
     ReadData(bincode::Error),
     InvalidHeader,
     InvalidVersion(u32,u32),
+    // section of the file is damaged or truncated
+    Checksum,
+    // set is broken, found by the strict loading
+    Invalid(CivInvalid),
+    // file exceeds the limits of from_reader_with_limits
+    Limit,
+    // fingerprint of the file is not the expected one, it has none or was written for another type
+    TypeMismatch { expected: u64, found: Option<u64> },
 }
 
 impl<K: Ord + Serialize + DeserializeOwned> Binary for CivSet<K> {
@@ -178,46 +518,328 @@ impl<K: Ord + Serialize + DeserializeOwned> Binary for CivSet<K> {
         write!(wrt,"CIVS").map_err(|_|CivSetIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivSetIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivSetIoError::WriteHeader)?;
-        bincode::serialize_into(&mut wrt,&self.slot).map_err(CivSetIoError::WriteSlot)?;
-        bincode::serialize_into(&mut wrt,&self.data).map_err(CivSetIoError::WriteData)
-    }
-    fn from_reader<R: Read>(mut rdr: R) -> Result<CivSet<K>,Self::IoError> {
-        let mut buf = [0; 4];
-        rdr.read_exact(&mut buf).map_err(|_|CivSetIoError::ReadHeader)?;
-        if buf != "CIVS".as_bytes()[0..4] { return Err(CivSetIoError::InvalidHeader); }
-        let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
-        let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
-        if (maj != 0)||(min != 1) { return Err(CivSetIoError::InvalidVersion(maj,min)); }
-        let slot: Slot<K,()> = bincode::deserialize_from(&mut rdr).map_err(CivSetIoError::ReadSlot)?;
-        let data: Vec<SetMultiSlot<K>> = bincode::deserialize_from(&mut rdr).map_err(CivSetIoError::ReadData)?;
-        let mut len = slot.len();
-        let mut tombs = 0;
-        for ms in &data {
-            let ln = ms.check_len();
-            len += ln;
-            tombs += ms.capacity - ln;
-        }
-        Ok(CivSet {
-            len: len,
-            tombs: tombs,
-            slot: slot,
-            data: data,
-            
-            tmp_merge_vec: Vec::new(),
-            tmp_merge_flags: Flags::tmp(),
-        })
+        let dir = self.directory().map_err(CivSetIoError::WriteData)?;
+        dir.write(&mut wrt).map_err(|_|CivSetIoError::WriteHeader)?;
+        self.slot.write(&mut wrt).map_err(CivSetIoError::WriteSlot)?;
+        for ms in &self.data {
+            ms.write(&mut wrt).map_err(CivSetIoError::WriteData)?;
+        }
+        Ok(())
+    }
+    fn from_reader<R: Read>(rdr: R) -> Result<CivSet<K>,Self::IoError> {
+        CivSet::from_reader_with_limits(rdr,CivLimits::default())
+    }
+}
+impl<K: Ord + Serialize + DeserializeOwned> CivSet<K> {
+    // from_reader of an untrusted file, reading more bytes or entries than the limits or a larger level
+    //   is CivSetIoError::Limit. Allocations are bounded by the limits.
+    pub fn from_reader_with_limits<R: Read>(rdr: R, limits: CivLimits) -> Result<CivSet<K>,CivSetIoError> {
+        CivSet::read_from(rdr,limits,Expected::Layout)
+    }
+    // from_reader of a file written with the fingerprint set by set_fingerprint
+    pub fn from_reader_with_fingerprint<R: Read>(rdr: R, fingerprint: u64) -> Result<CivSet<K>,CivSetIoError> {
+        CivSet::read_from(rdr,CivLimits::default(),Expected::User(fingerprint))
+    }
+    // from_reader without the check of the type fingerprint, for files of types with another layout
+    //   which still decode to the same values. Nothing tells a file of other types apart then.
+    pub fn from_reader_unchecked<R: Read>(rdr: R) -> Result<CivSet<K>,CivSetIoError> {
+        CivSet::read_from(rdr,CivLimits::default(),Expected::Unchecked)
+    }
+    fn read_from<R: Read>(rdr: R, limits: CivLimits, expected: Expected) -> Result<CivSet<K>,CivSetIoError> {
+        let check = |found: Option<Fingerprint>| check_fingerprint::<K>(found,expected);
+        let mut rdr = Bounded::new(rdr,limits.bytes);
+        let mut entries = 0;
+        let (found,slot,data) = match read_set_header(&mut rdr)? {
+            (0,1) => {
+                let slot: Slot<K,()> = bincode::deserialize_from(&mut rdr).map_err(|e| limit_or(e,CivSetIoError::Limit,CivSetIoError::ReadSlot))?;
+                if !within_limits(&limits,&mut entries,slot.max_size(),slot.len()) { return Err(CivSetIoError::Limit); }
+                let data: Vec<SetMultiSlot<K>> = bincode::deserialize_from(&mut rdr).map_err(|e| limit_or(e,CivSetIoError::Limit,CivSetIoError::ReadData))?;
+                for ms in &data {
+                    if !within_limits(&limits,&mut entries,ms.capacity,ms.data.len()) { return Err(CivSetIoError::Limit); }
+                }
+                (check(None)?,slot,data)
+            },
+            _ => {
+                let dir = read_set_directory(&mut rdr)?;
+                let found = check(Some(dir.fingerprint))?;
+                let slot: Slot<K,()> = read_slot(&mut rdr,&dir.slot).map_err(|e| e.or(CivSetIoError::Checksum,CivSetIoError::Limit,CivSetIoError::ReadSlot))?;
+                if !within_limits(&limits,&mut entries,slot.max_size(),slot.len()) { return Err(CivSetIoError::Limit); }
+                if dir.compacted != Compacted::No {
+                    let mut slot = slot;
+                    let data = read_runs(&mut rdr,&dir,&mut slot,&limits,&mut entries)?;
+                    (found,slot,data)
+                } else {
+                    let mut data = Vec::with_capacity(dir.levels.len());
+                    for section in &dir.levels {
+                        let ms = SetMultiSlot::read(&mut rdr,section).map_err(|e| e.or(CivSetIoError::Checksum,CivSetIoError::Limit,CivSetIoError::ReadData))?;
+                        if !within_limits(&limits,&mut entries,ms.capacity,ms.data.len()) { return Err(CivSetIoError::Limit); }
+                        data.push(ms);
+                    }
+                    (found,slot,data)
+                }
+            },
+        };
+        let mut set = CivSet::from_parts(slot,data);
+        set.fingerprint = found;
+        Ok(set)
+    }
+    // from_reader_with_limits checking the loaded set with validate
+    pub fn from_reader_strict<R: Read>(rdr: R, limits: CivLimits) -> Result<CivSet<K>,CivSetIoError> {
+        let set = CivSet::from_reader_with_limits(rdr,limits)?;
+        set.validate().map_err(CivSetIoError::Invalid)?;
+        Ok(set)
+    }
+    // Reads the slot and the level headers of a sectioned file, keys of the levels are read from the file
+    //   key by key and the levels are read back when they are merged. The file must not be changed while
+    //   the set is in use. The file is read through once to verify the checksums and to index the keys.
+    //   Files of v0.1 and compacted ones are read at once.
+    pub fn open_lazy<P: AsRef<Path>>(path: P) -> Result<CivSet<K>,CivSetIoError> {
+        let path = path.as_ref();
+        let mut rdr = BufReader::new(std::fs::File::open(path).map_err(|e| CivSetIoError::ReadData(e.into()))?);
+        let version = read_set_header(&mut rdr)?;
+        if version == (0,1) {
+            rdr.seek(SeekFrom::Start(0)).map_err(|_|CivSetIoError::ReadHeader)?;
+            return CivSet::from_reader(rdr);
+        }
+        let dir = read_set_directory(&mut rdr)?;
+        if dir.compacted != Compacted::No {
+            rdr.seek(SeekFrom::Start(0)).map_err(|_|CivSetIoError::ReadHeader)?;
+            return CivSet::from_reader(rdr);
+        }
+        let found = check_fingerprint::<K>(Some(dir.fingerprint),Expected::Layout)?;
+        let slot: Slot<K,()> = read_slot(&mut rdr,&dir.slot).map_err(|e| e.or(CivSetIoError::Checksum,CivSetIoError::Limit,CivSetIoError::ReadSlot))?;
+        let file = Arc::new(SpillFile::open(path));
+        let mut data = Vec::with_capacity(dir.levels.len());
+        for section in &dir.levels {
+            data.push(SetMultiSlot::lazy(&mut rdr,section,&file).map_err(|e| e.or(CivSetIoError::Checksum,CivSetIoError::Limit,CivSetIoError::ReadData))?);
+        }
+        let mut set = CivSet::from_parts(slot,data);
+        set.fingerprint = found;
+        Ok(set)
+    }
+}
+
+fn read_set_header<R: Read>(rdr: &mut R) -> Result<(u32,u32),CivSetIoError> {
+    let mut buf = [0; 4];
+    rdr.read_exact(&mut buf).map_err(|_|CivSetIoError::ReadHeader)?;
+    if buf != "CIVS".as_bytes()[0..4] { return Err(CivSetIoError::InvalidHeader); }
+    let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
+    let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
+    match is_file_version((maj,min)) {
+        true => Ok((maj,min)),
+        false => Err(CivSetIoError::InvalidVersion(maj,min)),
+    }
+}
+
+fn read_set_directory<R: Read>(rdr: &mut R) -> Result<Directory,CivSetIoError> {
+    match Directory::read(rdr).map_err(|e| limit_or(e,CivSetIoError::Limit,|_|CivSetIoError::ReadHeader))? {
+        Some(dir) => Ok(dir),
+        None => Err(CivSetIoError::InvalidHeader),
+    }
+}
+
+fn check_fingerprint<K>(found: Option<Fingerprint>, expected: Expected) -> Result<Option<u64>,CivSetIoError> {
+    Fingerprint::check::<K,()>(found,expected).map_err(|(expected,found)| CivSetIoError::TypeMismatch { expected, found })
+}
+impl<K: Ord + Serialize> CivSet<K> {
+    fn directory(&self) -> Result<Directory,bincode::Error> {
+        let mut levels = Vec::with_capacity(self.data.len());
+        for ms in &self.data {
+            levels.push(ms.section()?);
+        }
+        Directory::new(Fingerprint::new::<K,()>(self.fingerprint),Compacted::No,self.slot.section()?,levels)
+    }
+    // Writes the live keys only, the file is read by from_reader. The levels keep their live keys,
+    //   or with single_level all the keys are written as one run, which is spread over the slot
+    //   and full levels on loading. The set is not changed.
+    pub fn into_writer_compacted<W: Write>(&self, mut wrt: W, single_level: bool) -> Result<(),CivSetIoError> {
+        let version = CURRENT_CIVS_SET_VERSION;
+        let fingerprint = Fingerprint::new::<K,()>(self.fingerprint);
+        write!(wrt,"CIVS").map_err(|_|CivSetIoError::WriteHeader)?;
+        wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivSetIoError::WriteHeader)?;
+        wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivSetIoError::WriteHeader)?;
+        if single_level {
+            // the merged run is encoded for the directory and to the file, it's merged once
+            let data: Vec<&K> = self.sorted_iter().collect();
+            let slot: SlotRef<K,()> = SlotRef { size: self.slot.max_size() as u64, data: &[] };
+            let runs = match data.len() {
+                0 => Vec::new(),
+                _ => vec![RunRef { data }],
+            };
+            return write_runs(wrt,fingerprint,Compacted::Single,&slot,&runs);
+        }
+        let runs = self.data.iter().map(|ms| RunRef {
+            data: Items(ms.check_len(),|| ms.filtered_iter(&self.poisoned)),
+        }).collect::<Vec<_>>();
+        write_runs(wrt,fingerprint,Compacted::Levels,&self.slot.portable(),&runs)
+    }
+}
+
+// sequence of the keys
+impl<K: Ord + Serialize> Serialize for CivSet<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for k in self.sorted_iter() {
+            seq.serialize_element(k)?;
+        }
+        seq.end()
+    }
+}
+impl<'de, K: Ord + Deserialize<'de>> Deserialize<'de> for CivSet<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CivSet<K>, D::Error> {
+        struct SetVisitor<K>(std::marker::PhantomData<K>);
+        impl<'de, K: Ord + Deserialize<'de>> Visitor<'de> for SetVisitor<K> {
+            type Value = CivSet<K>;
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a sequence")
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut access: A) -> Result<CivSet<K>, A::Error> {
+                let mut set = CivSet::new();
+                while let Some(k) = access.next_element()? {
+                    set.insert(k);
+                }
+                Ok(set)
+            }
+        }
+        deserializer.deserialize_seq(SetVisitor(std::marker::PhantomData))
+    }
+}
+
+// slot and levels as in the v0.2 files
+#[derive(Serialize)]
+#[serde(rename = "CivSet")]
+struct LayoutRef<'t,K> {
+    fingerprint: Option<u64>,
+    slot: SlotRef<'t,K,()>,
+    data: Vec<SetMultiSlotRef<'t,K>>,
+}
+#[derive(Deserialize)]
+#[serde(rename = "CivSet")]
+struct SerdeLayout<K> {
+    fingerprint: Option<u64>,
+    slot: PortableSlot<K,()>,
+    data: Vec<PortableSetMultiSlot<K>>,
+}
+impl<K: Serialize> Serialize for Layout<&CivSet<K>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LayoutRef {
+            fingerprint: self.0.fingerprint,
+            slot: self.0.slot.portable(),
+            data: self.0.data.iter().map(|ms| ms.portable()).collect(),
+        }.serialize(serializer)
+    }
+}
+impl<K: Serialize> Serialize for Layout<CivSet<K>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Layout(&self.0).serialize(serializer)
+    }
+}
+// the loaded set is checked by validate
+impl<'de, K: Ord + Deserialize<'de>> Deserialize<'de> for Layout<CivSet<K>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Layout<CivSet<K>>, D::Error> {
+        let layout = SerdeLayout::deserialize(deserializer)?;
+        let mut set = CivSet::from_parts(layout.slot.0,layout.data.into_iter().map(|ms| ms.0).collect());
+        set.fingerprint = layout.fingerprint;
+        set.validate().map_err(|e| D::Error::custom(format!("invalid set: {:?}",e)))?;
+        Ok(Layout(set))
+    }
+}
+
+// run of a compacted file
+#[derive(Serialize)]
+struct RunRef<KS> {
+    data: KS,
+}
+#[derive(Deserialize)]
+struct SerdeRun<K> {
+    data: Vec<K>,
+}
+
+// the header is written
+fn write_runs<W: Write, K: Serialize, KS: Serialize>(mut wrt: W, fingerprint: Fingerprint, compacted: Compacted, slot: &SlotRef<K,()>, runs: &[RunRef<KS>]) -> Result<(),CivSetIoError> {
+    let mut levels = Vec::with_capacity(runs.len());
+    for run in runs {
+        levels.push(Section::of(run,[0,0]).map_err(CivSetIoError::WriteData)?);
+    }
+    let slot_section = slot.section().map_err(CivSetIoError::WriteSlot)?;
+    let dir = Directory::new(fingerprint,compacted,slot_section,levels).map_err(CivSetIoError::WriteData)?;
+    dir.write(&mut wrt).map_err(|_|CivSetIoError::WriteHeader)?;
+    file_options().serialize_into(&mut wrt,slot).map_err(CivSetIoError::WriteSlot)?;
+    for run in runs {
+        file_options().serialize_into(&mut wrt,run).map_err(CivSetIoError::WriteData)?;
+    }
+    Ok(())
+}
+
+// levels of a compacted file, the run of Compacted::Single is spread over the slot and full levels
+fn read_runs<K: Ord + DeserializeOwned, R: Read>(rdr: &mut R, dir: &Directory, slot: &mut Slot<K,()>, limits: &CivLimits, entries: &mut usize) -> Result<Vec<SetMultiSlot<K>>,CivSetIoError> {
+    let slot_size = slot.max_size();
+    if slot_size == 0 { return Err(CivSetIoError::Invalid(CivInvalid::Slot)); }
+    if (dir.compacted == Compacted::Single) && (dir.levels.len() > 1) { return Err(CivSetIoError::InvalidHeader); }
+    let mut data = Vec::with_capacity(dir.levels.len());
+    for (level,section) in dir.levels.iter().enumerate() {
+        let run: SerdeRun<K> = read_section(rdr,section).map_err(|e| e.or(CivSetIoError::Checksum,CivSetIoError::Limit,CivSetIoError::ReadData))?;
+        let mut keys = run.data;
+        if dir.compacted == Compacted::Levels {
+            let capacity = level_capacity(slot_size,level).ok_or(CivSetIoError::InvalidHeader)?;
+            if keys.len() > capacity {
+                return Err(CivSetIoError::Invalid(CivInvalid::Columns { level, keys: keys.len(), values: 0 }));
+            }
+            if !within_limits(limits,entries,capacity,keys.len()) { return Err(CivSetIoError::Limit); }
+            data.push(SetMultiSlot::run(capacity,keys));
+            continue;
+        }
+        let (rest,lens) = single_run_parts(keys.len(),slot_size);
+        if slot.len() + rest > slot_size { return Err(CivSetIoError::Invalid(CivInvalid::Slot)); }
+        slot.data.extend(keys.drain(keys.len() - rest ..).map(|k| (k,())));
+        // the greatest keys go to the smallest levels, the largest one keeps the buffer
+        let top = lens.len().saturating_sub(1);
+        for (level,len) in lens.into_iter().enumerate() {
+            let capacity = slot_size << level;
+            if !within_limits(limits,entries,capacity,len) { return Err(CivSetIoError::Limit); }
+            let k = match level == top {
+                true => std::mem::take(&mut keys),
+                false => keys.split_off(keys.len() - len),
+            };
+            data.push(SetMultiSlot::run(capacity,k));
+        }
+    }
+    Ok(data)
+}
+
+// drops the levels of a merge left by an error or a panic of Ord
+struct Interrupted<'t,K: Ord> {
+    set: &'t mut CivSet<K>,
+    n: usize,
+}
+impl<K: Ord> Drop for Interrupted<'_,K> {
+    fn drop(&mut self) {
+        self.set.drop_merged(self.n);
     }
 }
 
-#[derive(Clone)]
 pub struct CivSet<K> {
     len: usize,
     tombs: usize,
     slot: Slot<K,()>,
     data: Vec<SetMultiSlot<K>>,
 
-    tmp_merge_vec: Vec<K>,
-    tmp_merge_flags: Flags,
+    // a merge was interrupted by a panic of Ord or a level of a lazily opened set can't be read
+    poisoned: AtomicBool,
+    // written with the layout fingerprint
+    fingerprint: Option<u64>,
+}
+impl<K: Clone> Clone for CivSet<K> {
+    fn clone(&self) -> CivSet<K> {
+        CivSet {
+            len: self.len,
+            tombs: self.tombs,
+            slot: self.slot.clone(),
+            data: self.data.clone(),
+            poisoned: AtomicBool::new(self.poisoned.load(Ordering::Relaxed)),
+            fingerprint: self.fingerprint,
+        }
+    }
 }
 impl<K: std::fmt::Debug> std::fmt::Debug for CivSet<K> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -230,23 +852,54 @@ impl<K: std::fmt::Debug> std::fmt::Debug for CivSet<K> {
     }
 }
 impl<K: Ord> CivSet<K> {
+    fn from_parts(slot: Slot<K,()>, mut data: Vec<SetMultiSlot<K>>) -> CivSet<K> {
+        let mut len = slot.len();
+        let mut tombs = 0;
+        for ms in &mut data {
+            let ln = ms.data.len();
+            if (ln > 0) && (ln < ms.capacity) && !ms.data.is_spilled() {
+                ms.data.heap().reserve(ms.capacity - ln);
+            }
+            let ln = ms.check_len();
+            len += ln;
+            // empty levels have no tombs, a broken level is reported by validate
+            if !ms.empty() {
+                tombs += ms.capacity.saturating_sub(ln);
+            }
+        }
+        CivSet {
+            len,
+            tombs,
+            slot,
+            data,
+            poisoned: AtomicBool::new(false),
+            fingerprint: None,
+        }
+    }
     pub fn new() -> CivSet<K> {
         CivSet {
             len: 0,
             tombs: 0,
             slot: Slot::new(),
             data: Vec::new(),
-
-            tmp_merge_vec: Vec::new(),
-            tmp_merge_flags: Flags::tmp(),
+            poisoned: AtomicBool::new(false),
+            fingerprint: None,
         }
     }
+    // Fingerprint of the key type written to files with the one of their layout,
+    //   e.g. a hash of a schema. Such files are read by from_reader_with_fingerprint.
+    pub fn set_fingerprint(&mut self, fingerprint: Option<u64>) {
+        self.fingerprint = fingerprint;
+    }
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
     pub fn filtered_iter(&self) -> Iter<K> {
         let mut v = {
             let n = self.data.len();
             let mut v = Vec::with_capacity(n);            
             for i in 0 .. n {
-                v.push(self.data[n-i-1].filtered_iter());
+                v.push(self.data[n-i-1].filtered_iter(&self.poisoned));
             }
             v
         };
@@ -256,14 +909,65 @@ impl<K: Ord> CivSet<K> {
             data_iter: v,            
         }
     }
-    
+    fn runs<'t>(&'t self, order: &'t [usize]) -> SetRuns<'t,K> {
+        SetRuns {
+            slot: &self.slot.data,
+            order,
+            data: &self.data,
+            poisoned: &self.poisoned,
+        }
+    }
+    fn entry_at(&self, order: &[usize], (r,p): (usize,usize)) -> Option<&K> {
+        match r {
+            0 => Some(&self.slot.data[order[p]].0),
+            _ => read_ok(&self.poisoned,self.data[r-1].data.get(p)),
+        }
+    }
+    pub fn sorted_iter(&self) -> SortedIter<'_,K> {
+        self.range(..)
+    }
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SortedIter<'_,K> {
+        let order = sorted_order(&self.slot.data);
+        let (front,back) = Heads::range(&self.runs(&order),&range);
+        SortedIter {
+            set: self,
+            order,
+            front,
+            back,
+            left: self.count_range((range.start_bound(),range.end_bound())),
+        }
+    }
+    // positioned before the first key
+    pub fn cursor(&self) -> Cursor<'_,K> {
+        let order = sorted_order(&self.slot.data);
+        let heads = Heads::first(&self.runs(&order));
+        Cursor {
+            set: self,
+            order,
+            heads,
+        }
+    }
+    pub fn cursor_mut(&mut self) -> CursorMut<'_,K> {
+        let order = sorted_order(&self.slot.data);
+        let heads = Heads::first(&self.runs(&order));
+        CursorMut {
+            set: self,
+            order,
+            heads,
+        }
+    }
+    // The set lost keys because of a panic during a merge (the levels being merged are dropped, the set
+    //   stays usable), or a level of a lazily opened set can't be read: its keys are skipped by reads and
+    //   the level is dropped by the next merge. clear() resets it.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
     pub fn clear(&mut self) {
         self.len = 0;
         self.tombs = 0;
         self.slot.clear();
         self.data.clear();
-        self.tmp_merge_flags = Flags::tmp();
-        self.tmp_merge_vec.clear();
+        *self.poisoned.get_mut() = false;
     }
     pub fn contains(&self, k: &K) -> bool {
         match self.slot.contains(k) {
@@ -273,7 +977,7 @@ impl<K: Ord> CivSet<K> {
     }    
     fn multy_contains(&self, k: &K) -> Option<(usize,usize)> {
         for (n,ms) in self.data.iter().enumerate() {
-            if let Some(idx) = ms.contains(k) {
+            if let Some(idx) = read_ok(&self.poisoned,ms.contains(k)).flatten() {
                 return Some((n,idx));
             }
         }
@@ -281,37 +985,108 @@ impl<K: Ord> CivSet<K> {
     }
     pub fn insert(&mut self, k: K) -> bool {
         // return true if value was inserted
-        
+        match self.insert_inner(k,false) {
+            Ok(r) => r,
+            Err(e) => panic!("Unreachable insert: {:?}",e),
+        }
+    }
+    // as insert, but reports allocation failures and broken invariants instead of aborting,
+    //   the set is not changed if allocation fails
+    pub fn try_insert(&mut self, k: K) -> Result<bool,CivError> {
+        if self.is_poisoned() {
+            return Err(CivError::Poisoned);
+        }
+        self.insert_inner(k,true)
+    }
+    fn insert_inner(&mut self, k: K, reserve: bool) -> Result<bool,CivError> {
         if self.multy_contains(&k).is_some() {
-            return false;
+            return Ok(false);
+        }
+        if reserve && (self.slot.len() + 1 >= self.slot.max_size()) && self.slot.contains(&k).is_none() {
+            self.try_reserve_merge()?;
         }
         let (r,filled) = self.slot.insert(k,());
         if let Filled::Full = filled {
-            if self.data.len() == 0 {
-                self.data.push(self.slot.into_set_multislot());
-            } else {
+            // levels of a lazily opened set are read back to be merged, the ones which can't be read
+            //   are dropped and the merge goes into the first of them
+            let mut lost = false;
+            let n = loop {
                 let mut n = 0;
                 while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
-                if n == self.data.len() {
-                    self.data.push(SetMultiSlot::new_empty(n+1,self.slot.max_size()));
-                }
-                if let Err(s) = self.merge_into(n) {
-                    panic!("Unreachable merge_into: {}",s);
+                match (0 .. n).find(|i| self.data[*i].data.is_spilled()) {
+                    Some(i) => lost |= self.read_back(i),
+                    None => break n,
                 }
-                if let Err(s) = self.check_tombs(n) {
-                    panic!("Unreachable check_tombs: {}",s);
+            };
+            if lost {
+                *self.poisoned.get_mut() = true;
+            }
+            // drops data[0..=n] if Ord panics in the middle of the merge
+            let guard = Interrupted { set: self, n };
+            if guard.set.data.len() == 0 {
+                let ms = guard.set.slot.into_set_multislot();
+                guard.set.data.push(ms);
+            } else {
+                if n == guard.set.data.len() {
+                    guard.set.data.push(SetMultiSlot::new_empty(n+1,guard.set.slot.max_size()));
                 }
-                self.shrink_long();
+                guard.set.merge_into(n).map_err(CivError::Internal)?;
+                guard.set.check_tombs(n).map_err(CivError::Internal)?;
+                guard.set.shrink_long();
             }
+            std::mem::forget(guard);
         }
         match r {
             None => {
                 self.len += 1;
-                true
+                Ok(true)
             },
-            Some(_) => false,
+            Some(_) => Ok(false),
         }
     }
+    // reserves what the merge of the full slot fills: the level it goes into (pushed if missing)
+    //   and the levels above it, which hold acc and the keys moved down by check_tombs
+    fn try_reserve_merge(&mut self) -> Result<(),CivError> {
+        let sz = self.slot.max_size();
+        let mut n = 0;
+        while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
+        if n == self.data.len() {
+            self.data.try_reserve(1)?;
+            self.data.push(SetMultiSlot::try_new_empty(n+1,sz)?);
+        }
+        for ms in &mut self.data[..= n] {
+            if ms.data.is_spilled() { continue; }
+            let ln = ms.data.len();
+            ms.data.heap().try_reserve_exact(ms.capacity - ln)?;
+        }
+        Ok(())
+    }
+    // allocates all the levels needed to hold additional new keys, so merges of the next
+    //   inserts do not allocate (empty levels of AUTO_SHRINK_LIMIT and more are released by merges)
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(),CivError> {
+        let sz = self.slot.max_size();
+        let mut total = self.slot.len().saturating_add(additional);
+        for ms in &self.data {
+            if !ms.empty() { total = total.saturating_add(ms.capacity); }
+        }
+        let levels = levels_for(sz,total).ok_or_else(capacity_overflow)?;
+        // the largest level goes first, so nothing is allocated in vain
+        let have = self.data.len();
+        let mut fresh = Vec::new();
+        fresh.try_reserve_exact(levels.saturating_sub(have))?;
+        for i in (have .. levels).rev() {
+            fresh.push(SetMultiSlot::try_new_empty(i+1,sz)?);
+        }
+        for ms in self.data.iter_mut().take(levels) {
+            // levels of a lazily opened set are not read back
+            if ms.data.is_spilled() { continue; }
+            let ln = ms.data.len();
+            ms.data.heap().try_reserve_exact(ms.capacity - ln)?;
+        }
+        self.data.try_reserve(fresh.len())?;
+        self.data.extend(fresh.into_iter().rev());
+        Ok(())
+    }
     pub fn len(&self) -> usize {
         self.len
     }
@@ -330,11 +1105,173 @@ impl<K: Ord> CivSet<K> {
         if r { self.len -= 1; }
         r
     }
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let mut cnt = self.slot.remove_range(&range);
+        self.len -= cnt;
+        for ms in &mut self.data {
+            let span = match read_ok(&self.poisoned,ms.span(&range)) {
+                Some(span) => span,
+                None => continue,
+            };
+            let c = ms.flags.unset_range(span.start,span.end);
+            self.tombs += c;
+            self.len -= c;
+            cnt += c;
+        }
+        cnt
+    }
+    // number of live keys less then k
+    pub fn rank(&self, k: &K) -> usize {
+        self.rank_bound(k,false)
+    }
+    fn rank_bound(&self, k: &K, inclusive: bool) -> usize {
+        let mut r = self.slot.iter().filter(|(x,_)| (x < k)||(inclusive && (x == k))).count();
+        for ms in &self.data {
+            let idx = match inclusive {
+                true => ms.data.partition_point(|x| x <= k),
+                false => ms.data.partition_point(|x| x < k),
+            };
+            if let Some(idx) = read_ok(&self.poisoned,idx) {
+                r += ms.flags.rank(idx);
+            }
+        }
+        r
+    }
+    // i-th smallest live key
+    pub fn select(&self, i: usize) -> Option<&K> {
+        if i >= self.len { return None; }
+        let order = sorted_order(&self.slot.data);
+        let entry = |r: usize, p: usize| match r {
+            0 => self.entry_at(&order,(0,p)),
+            _ => self.entry_at(&order,(r,self.data[r-1].flags.select(p)?)),
+        };
+        // live positions [from,to) of the slot and of the levels left to look at
+        let mut runs = std::iter::once(order.len())
+            .chain(self.data.iter().map(|ms| ms.flags.count()))
+            .map(|to| (0,to))
+            .collect::<Vec<_>>();
+        let mut left = i + 1;
+        loop {
+            let active = runs.iter().filter(|(from,to)| from < to).count();
+            let step = std::cmp::max(1,left / std::cmp::max(1,active));
+            let mut best: Option<(usize,usize,&K)> = None;
+            for (r,&(from,to)) in runs.iter().enumerate() {
+                if from == to { continue; }
+                let n = std::cmp::min(to - from,step);
+                let k = entry(r,from + n - 1)?;
+                let better = match best {
+                    Some((_,_,b)) => k < b,
+                    None => true,
+                };
+                if better {
+                    best = Some((r,n,k));
+                }
+            }
+            let (r,n,k) = best?;
+            if n == left {
+                return Some(k);
+            }
+            runs[r].0 += n;
+            left -= n;
+        }
+    }
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let mut cnt = self.slot.iter().filter(|(k,_)| range.contains(k)).count();
+        for ms in &self.data {
+            if let Some(span) = read_ok(&self.poisoned,ms.span(&range)) {
+                cnt += ms.flags.rank(span.end) - ms.flags.rank(span.start);
+            }
+        }
+        cnt
+    }
     pub fn shrink_to_fit(&mut self) {
         for ms in &mut self.data {
             ms.shrink_to_fit();
         }
     }
+    // empty levels keep their buffers to be reused by the next merges, frees them
+    pub fn release_scratch(&mut self) {
+        for ms in &mut self.data {
+            if ms.empty() {
+                ms.shrink_to_fit();
+            }
+        }
+    }
+    // Checks the invariants: capacities of the levels layout, keys sorted and unique, flags of the capacity,
+    //   no key live in two levels, len and tombs counters. Levels of a lazily opened set are read.
+    pub fn validate(&self) -> Result<(),CivInvalid> {
+        self.slot.check()?;
+        let (mut len, mut tombs) = (self.slot.len(),0);
+        for (level,ms) in self.data.iter().enumerate() {
+            if ms.data.len() > ms.capacity {
+                return Err(CivInvalid::Columns { level, keys: ms.data.len(), values: 0 });
+            }
+            match ms.data.as_slice() {
+                Some(data) => {
+                    check_level::<K,_>(level,self.slot.max_size(),ms.capacity,&ms.flags,data.len(),data.iter())?;
+                    self.check_overlap(level,data.iter())?;
+                },
+                None => {
+                    // keys of a level in the file are streamed, a level which can't be read poisons the set
+                    let mut read = 0;
+                    check_level::<K,_>(level,self.slot.max_size(),ms.capacity,&ms.flags,ms.data.len(),read_keys(&ms.data,&mut read))?;
+                    if read == ms.data.len() {
+                        read = 0;
+                        self.check_overlap(level,read_keys(&ms.data,&mut read))?;
+                    }
+                    if read != ms.data.len() {
+                        self.poisoned.store(true,Ordering::Relaxed);
+                        return Err(CivInvalid::Columns { level, keys: read, values: 0 });
+                    }
+                },
+            }
+            let live = ms.check_len();
+            len += live;
+            if !ms.empty() {
+                tombs += ms.capacity - live;
+            }
+        }
+        match (len,tombs) == (self.len,self.tombs) {
+            true => Ok(()),
+            false => Err(CivInvalid::Counters { len: self.len, tombs: self.tombs, expected_len: len, expected_tombs: tombs }),
+        }
+    }
+    // live keys of data[level] live in the slot or in a newer level
+    fn check_overlap<B: Borrow<K>, I: Iterator<Item = B>>(&self, level: usize, keys: I) -> Result<(),CivInvalid> {
+        let ms = &self.data[level];
+        for (index,k) in keys.enumerate() {
+            let k = k.borrow();
+            let newer = self.slot.contains(k).is_some() || self.data[.. level].iter().any(|ms| read_ok(&self.poisoned,ms.contains(k)).flatten().is_some());
+            if newer && ms.flags.get(index) {
+                return Err(CivInvalid::Overlap { level, index });
+            }
+        }
+        Ok(())
+    }
+    // reads a level of a lazily opened set back to merge it, a level which can't be read is dropped
+    //   (the caller poisons the set). Returns if it's dropped.
+    fn read_back(&mut self, msi: usize) -> bool {
+        let ms = &mut self.data[msi];
+        if ms.unspill().is_ok() {
+            return false;
+        }
+        let live = ms.check_len();
+        self.len -= live;
+        if !ms.empty() {
+            self.tombs -= ms.capacity - live;
+        }
+        ms.clear();
+        true
+    }
+    // the levels of an interrupted merge are dropped and the set is recounted from the remaining ones
+    fn drop_merged(&mut self, n: usize) {
+        for ms in self.data.iter_mut().take(n+1) {
+            ms.clear();
+        }
+        self.len = self.check_len();
+        self.tombs = self.data.iter().filter(|ms| !ms.empty()).map(|ms| ms.capacity - ms.check_len()).sum();
+        *self.poisoned.get_mut() = true;
+    }
     fn shrink_long(&mut self) {
         for ms in &mut self.data {
             if (ms.capacity >= AUTO_SHRINK_LIMIT)&&(ms.empty()) {   
@@ -343,36 +1280,55 @@ impl<K: Ord> CivSet<K> {
         }
     }
     fn merge_into(&mut self, n: usize) -> Result<(),&'static str> {
+        // slot and data[0..n-1] are sorted in acc, acc is merged in place with data[n-1] into data[n]
         if !self.data[n].empty() { return Err("data[n] is not empty"); }
         let mut cnt = self.slot.len();
         for i in 0 .. n {
             if self.data[i].empty() { return Err("one of data[0..n] is empty"); }
-            cnt += self.data[i].data.len();
-        }
-        self.data[n].data.reserve(cnt);
-        
-        {
-            for p in self.slot.data.drain(..) {
-                self.data[n].data.push(p.0);
+            if i + 1 < n {
+                cnt += self.data[i].data.len();
             }
-            self.slot.clear();
+        }
 
-            std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
-            for i in 0 .. n {
-                std::mem::swap(&mut self.data[i].flags,&mut self.tmp_merge_flags);
-                for (j,k) in self.data[i].data.drain(..).enumerate() {
-                    if self.tmp_merge_flags.get(j) {
-                        self.tmp_merge_vec.push(k);
-                    }
+        let mut acc = match n {
+            0 => std::mem::take(self.data[0].data.heap()),
+            _ => {
+                let (lower,upper) = self.data.split_at_mut(n);
+                let (src,dst) = (&mut lower[n-1],&mut upper[0]);
+                src.retain_live();
+                let (src,dst) = (src.data.heap(),dst.data.heap());
+                if dst.capacity() >= src.len() + cnt {
+                    dst.append(src);
+                } else {
+                    std::mem::swap(dst,src);
                 }
-                std::mem::swap(&mut self.data[i].flags,&mut self.tmp_merge_flags);
-                self.data[i].clear();
-            }
-            std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
+                // the freed buffer of data[n-1] is used for acc
+                std::mem::take(src)
+            },
+        };
+        acc.reserve(cnt);
+        for p in self.slot.data.drain(..) {
+            acc.push(p.0);
+        }
+        self.slot.clear();
+        for i in 0 .. n.saturating_sub(1) {
+            let ms = &mut self.data[i];
+            acc.extend(ms.flags.filter(ms.data.heap().drain(..)));
+            ms.clear();
+        }
+        acc.sort_unstable();
 
-            self.data[n].data.sort();
+        if n == 0 {
+            self.data[0].data = acc.into();
+        } else {
+            let dst = self.data[n].data.heap();
+            let mut dst_units = vec![(); dst.len()];
+            let mut acc_units = vec![(); acc.len()];
+            merge_back(dst,&mut dst_units,&mut acc,&mut acc_units,None);
+            self.data[n-1].data = acc.into();
+            self.data[n-1].clear();
         }
-        
+
         let c = self.data[n].data.len();
         self.data[n].flags.set_ones(c);
         Ok(())
@@ -387,10 +1343,10 @@ impl<K: Ord> CivSet<K> {
         let local_tombs = self.data[n].capacity - self.data[n].data.len();
         let local_part = (local_tombs as f64) / (self.data[n].capacity as f64);
         if (local_tombs > sz) && (local_part > TOMBS_LIMIT) {
-            std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
+            let mut data = std::mem::take(self.data[n].data.heap());
             {
-                let mut count = self.tmp_merge_vec.len();
-                let mut iter = self.tmp_merge_vec.drain(..);
+                let mut count = data.len();
+                let mut iter = data.drain(..);
 
                 let mut msi = self.data[..n].iter_mut();
                 while let Some(ms) = msi.next_back() {
@@ -398,7 +1354,7 @@ impl<K: Ord> CivSet<K> {
                     if count >= cap {
                         for _ in 0 .. cap {
                             if let Some(k) = iter.next() {
-                                ms.data.push(k);
+                                ms.data.heap().push(k);
                             }
                         }
                         if ms.data.len() != cap {
@@ -406,7 +1362,14 @@ impl<K: Ord> CivSet<K> {
                         }
                         ms.flags.set_ones(cap);
                         count -= cap;
-                        if count == 0 { break; }
+                        if count == 0 {
+                            // all data[0..n] are full, no tombs left
+                            if local_tombs > self.tombs {
+                                return Err("local_tombs > self.tombs");
+                            }
+                            self.tombs -= local_tombs;
+                            break;
+                        }
                         continue;
                     }
                     if (cap - count) > sz { continue; }
@@ -414,7 +1377,7 @@ impl<K: Ord> CivSet<K> {
                     let d_tombs = local_tombs - (cap - count);
                     for _ in 0 .. count {
                         if let Some(k) = iter.next() {
-                            ms.data.push(k);
+                            ms.data.heap().push(k);
                         }
                     }
                     if ms.data.len() != count {
@@ -431,7 +1394,7 @@ impl<K: Ord> CivSet<K> {
                     return Err("merged data greater then the sum of the parts");
                 }
             }
-            std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
+            self.data[n].data = data.into();
             self.data[n].clear();
         }
         Ok(())
@@ -473,6 +1436,22 @@ impl<K: Ord> CivSet<K> {
 mod test {
     use super::*;
 
+    // set of n inserts of keys below p, with every third insert removing another key, and its BTreeSet
+    fn churned(n: u64, p: u64) -> (CivSet<u64>,std::collections::BTreeSet<u64>) {
+        let mut set = CivSet::new();
+        let mut res = std::collections::BTreeSet::new();
+        for i in 0 .. n {
+            let k = (i * 7919) % p;
+            set.insert(k);
+            res.insert(k);
+            if i % 3 == 0 {
+                set.remove(&((i * 13) % p));
+                res.remove(&((i * 13) % p));
+            }
+        }
+        (set,res)
+    }
+
     #[test]
     fn iterator() {
         let mut set = CivSet::new();
@@ -496,4 +1475,422 @@ mod test {
         };
         assert_eq!(res,r);
     }
+
+    #[test]
+    fn check_tombs_exact_fill() {
+        let mut set = CivSet::new();
+        // data[0] of 64 and data[1] of 128
+        for i in 0 .. 192 {
+            set.insert(i);
+        }
+        // leaves 64 live keys of data[1] and none of data[0]
+        for i in 64 .. 192 {
+            set.remove(&i);
+        }
+        assert_eq!(set.len(),64);
+        // merged into data[2] of 256 with 128 live keys, which fill data[1] exactly
+        for i in 192 .. 256 {
+            set.insert(i);
+        }
+        assert_eq!(set.len(),128);
+        assert_eq!(set.tombs(),0);
+    }
+
+    #[test]
+    fn remove_range() {
+        let mut set = CivSet::new();
+        for i in 0 .. 10_000 {
+            set.insert((i * 7919) % 10_000);
+        }
+        assert_eq!(set.remove_range(100 .. 9_000),8_900);
+        assert_eq!(set.len(),1_100);
+        for i in 0 .. 10_000 {
+            assert_eq!(set.contains(&i),!(100 .. 9_000).contains(&i));
+        }
+    }
+
+    #[test]
+    fn order_statistics() {
+        let mut set = CivSet::new();
+        for i in 0 .. 10_000 {
+            set.insert((i * 7919) % 10_000);
+        }
+        set.remove_range(2_000 .. 3_000);
+        for i in 0 .. 2_000 {
+            assert_eq!(set.rank(&i),i);
+            assert_eq!(set.select(i),Some(&i));
+        }
+        for i in 3_000 .. 10_000 {
+            assert_eq!(set.rank(&i),i - 1_000);
+            assert_eq!(set.select(i - 1_000),Some(&i));
+        }
+        assert_eq!(set.count_range(1_500 ..= 3_500),1_001);
+    }
+
+    #[test]
+    fn cursor() {
+        let mut set = CivSet::new();
+        for i in 0 .. 10_000 {
+            set.insert((i * 7919) % 10_000);
+        }
+        set.remove_range(2_000 .. 3_000);
+        let mut cursor = set.cursor();
+        assert_eq!(cursor.seek(&2_500),Some(&3_000));
+        assert_eq!(cursor.peek_prev(),Some(&1_999));
+        assert_eq!(cursor.prev(),Some(&1_999));
+        assert_eq!(cursor.next(),Some(&3_000));
+        assert_eq!(cursor.seek(&10_000),None);
+        assert_eq!(cursor.prev(),Some(&9_999));
+        assert_eq!(cursor.seek_first(),Some(&0));
+
+        let mut cursor = set.cursor_mut();
+        while let Some(k) = cursor.next() {
+            if k % 3 == 0 { cursor.remove_current(); }
+        }
+        assert_eq!(set.len(),(0 .. 10_000).filter(|i| (i % 3 != 0)&&!(2_000 .. 3_000).contains(i)).count());
+        let mut cursor = set.cursor();
+        for i in (0 .. 10_000).filter(|i| (i % 3 != 0)&&!(2_000 .. 3_000).contains(i)) {
+            assert!(set.contains(&i));
+            assert_eq!(cursor.next(),Some(&i));
+        }
+        assert_eq!(cursor.next(),None);
+    }
+
+    #[test]
+    fn rev_iter() {
+        let mut set = CivSet::new();
+        for i in 0 .. 10_000 {
+            set.insert((i * 7919) % 10_000);
+        }
+        set.remove_range(2_000 .. 3_000);
+        let mut fwd = set.filtered_iter().cloned().collect::<Vec<_>>();
+        fwd.reverse();
+        assert_eq!(set.filtered_iter().rev().cloned().collect::<Vec<_>>(),fwd);
+        assert!(set.sorted_iter().rev().cloned().eq((0 .. 2_000).chain(3_000 .. 10_000).rev()));
+        let mut it = set.range(1_990 .. 3_010);
+        assert_eq!(it.len(),20);
+        assert_eq!(it.next_back(),Some(&3_009));
+        assert_eq!(it.next(),Some(&1_990));
+        assert_eq!(it.rev().cloned().collect::<Vec<_>>(),(1_991 .. 2_000).chain(3_000 .. 3_009).rev().collect::<Vec<_>>());
+    }
+
+    thread_local! {
+        static FUSE: std::cell::Cell<usize> = const { std::cell::Cell::new(usize::MAX) };
+    }
+    #[derive(Debug,Clone,Copy,PartialEq,Eq)]
+    struct Bomb(u64);
+    impl PartialOrd for Bomb {
+        fn partial_cmp(&self, other: &Bomb) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Bomb {
+        fn cmp(&self, other: &Bomb) -> std::cmp::Ordering {
+            FUSE.with(|f| match f.get() {
+                0 => panic!("comparator fuse"),
+                n => f.set(n - 1),
+            });
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn panic_safety() {
+        let mut set = CivSet::new();
+        for i in 0 .. 1_000 {
+            set.insert(Bomb(i));
+        }
+        let mut len = 1_000;
+        for i in 1_000 .. 10_000 {
+            FUSE.with(|f| f.set(60));
+            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| set.insert(Bomb(i))));
+            FUSE.with(|f| f.set(usize::MAX));
+            if set.is_poisoned() { break; }
+            match r {
+                Ok(_) => len += 1,
+                // fuse went off in the lookup, nothing is changed
+                Err(_) => assert!(!set.contains(&Bomb(i))),
+            }
+            assert_eq!(set.len(),len);
+        }
+        assert!(set.is_poisoned());
+        FUSE.with(|f| f.set(usize::MAX));
+        // the interrupted merge dropped its levels, the set stays usable
+        assert!(set.validate().is_ok());
+        let mut res: std::collections::BTreeSet<u64> = set.sorted_iter().map(|k| k.0).collect();
+        assert_eq!(set.len(),res.len());
+        for j in 0 .. 20_000 {
+            let k = (j * 13) % 30_011;
+            assert_eq!(set.insert(Bomb(k)),res.insert(k));
+        }
+        assert!(set.validate().is_ok());
+        assert!(set.sorted_iter().map(|k| k.0).eq(res.into_iter()));
+        set.clear();
+        assert!(!set.is_poisoned());
+        for i in 0 .. 1_000 {
+            set.insert(Bomb(i));
+        }
+        assert_eq!(set.len(),1_000);
+        assert!(set.sorted_iter().map(|k| k.0).eq(0 .. 1_000));
+    }
+
+    #[test]
+    fn sectioned_format() {
+        let (set,_) = churned(10_000,10_007);
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        assert_eq!(&buf[4 .. 12],&[0,0,0,0,2,0,0,0]);
+        let set2: CivSet<u64> = CivSet::from_reader(&buf[..]).unwrap();
+        assert_eq!((set2.len(),set2.tombs()),(set.len(),set.tombs()));
+        assert!(set2.sorted_iter().eq(set.sorted_iter()));
+
+        let mut buf = Vec::new();
+        write!(buf,"CIVS").unwrap();
+        buf.write_u32::<LittleEndian>(0).unwrap();
+        buf.write_u32::<LittleEndian>(1).unwrap();
+        bincode::serialize_into(&mut buf,&set.slot).unwrap();
+        bincode::serialize_into(&mut buf,&set.data).unwrap();
+        let set2: CivSet<u64> = CivSet::from_reader(&buf[..]).unwrap();
+        assert!(set2.sorted_iter().eq(set.sorted_iter()));
+
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        let n = buf.len() - 20;
+        buf[n] ^= 0x04;
+        assert!(matches!(CivSet::<u64>::from_reader(&buf[..]),Err(CivSetIoError::Checksum)));
+    }
+
+    #[test]
+    fn open_lazy() {
+        let (set,mut res) = churned(20_000,20_011);
+        let path = std::env::temp_dir().join(format!("civs-test-set-lazy-{}.civs",std::process::id()));
+        set.into_writer(std::io::BufWriter::new(std::fs::File::create(&path).unwrap())).unwrap();
+        let mut set2: CivSet<u64> = CivSet::open_lazy(&path).unwrap();
+        assert!(set2.data.iter().filter(|ms| !ms.empty()).all(|ms| ms.data.is_spilled() && (ms.data.capacity() == 0)));
+        assert_eq!((set2.len(),set2.tombs()),(set.len(),set.tombs()));
+        // point reads don't load the levels
+        let &k = res.iter().nth(res.len() / 2).unwrap();
+        assert!(set2.contains(&k) && !set2.contains(&20_011));
+        assert_eq!(set2.rank(&k),res.len() / 2);
+        assert_eq!(set2.count_range(k ..),res.len() - res.len() / 2);
+        assert!(set2.data.iter().all(|ms| ms.data.capacity() == 0));
+        assert!(set2.sorted_iter().eq(res.iter()));
+        set2.validate().unwrap();
+        // the levels are read back by the merges
+        assert!(set2.remove(&k));
+        res.remove(&k);
+        for i in 0 .. 5_000u64 {
+            set2.insert(30_000 + i);
+            res.insert(30_000 + i);
+        }
+        set2.validate().unwrap();
+        assert!(set2.sorted_iter().eq(res.iter()));
+        assert!(!set2.is_poisoned());
+
+        // a damaged file is found on opening
+        drop(set2);
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        let n = buf.len() - 20;
+        buf[n] ^= 0x04;
+        std::fs::write(&path,&buf).unwrap();
+        assert!(matches!(CivSet::<u64>::open_lazy(&path),Err(CivSetIoError::Checksum)));
+
+        // a file which can't be read poisons the set
+        set.into_writer(std::io::BufWriter::new(std::fs::File::create(&path).unwrap())).unwrap();
+        let mut set2: CivSet<u64> = CivSet::open_lazy(&path).unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_len(0).unwrap();
+        assert!(!set2.contains(&k));
+        assert!(set2.is_poisoned());
+        assert!(matches!(set2.try_insert(1),Err(CivError::Poisoned)));
+        for i in 0 .. 5_000u64 {
+            set2.insert(30_000 + i);
+        }
+        assert!(set2.contains(&30_000));
+        assert!(matches!(set2.validate(),Err(CivInvalid::Columns { .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validate() {
+        let (set,_) = churned(10_000,10_007);
+        set.validate().unwrap();
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        CivSet::<u64>::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+
+        let level = set.data.iter().position(|ms| ms.data.len() > 10).unwrap();
+        let mut broken = set.clone();
+        let data = broken.data[level].data.heap();
+        data[5] = data[4];
+        assert_eq!(broken.validate(),Err(CivInvalid::Unsorted { level, index: 5 }));
+        let mut buf = Vec::new();
+        broken.into_writer(&mut buf).unwrap();
+        assert!(matches!(CivSet::<u64>::from_reader_strict(&buf[..],CivLimits::default()),Err(CivSetIoError::Invalid(CivInvalid::Unsorted { .. }))));
+        let mut broken = set.clone();
+        broken.slot.data.push((u64::MAX,()));
+        broken.slot.data.push((u64::MAX,()));
+        assert_eq!(broken.validate(),Err(CivInvalid::Slot));
+    }
+
+    #[test]
+    fn limits() {
+        let mut set = CivSet::new();
+        for i in 0 .. 3_000u64 {
+            set.insert(i);
+        }
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        let limits = CivLimits { bytes: buf.len() as u64, entries: 3_000, level_capacity: 2_048 };
+        assert!(CivSet::<u64>::from_reader_with_limits(&buf[..],limits).unwrap().sorted_iter().eq(set.sorted_iter()));
+        assert!(matches!(CivSet::<u64>::from_reader_with_limits(&buf[..],CivLimits { bytes: 1_000, ..limits }),Err(CivSetIoError::Limit)));
+        assert!(matches!(CivSet::<u64>::from_reader_with_limits(&buf[..],CivLimits { entries: 2_999, ..limits }),Err(CivSetIoError::Limit)));
+        assert!(matches!(CivSet::<u64>::from_reader_with_limits(&buf[..],CivLimits { level_capacity: 1_024, ..limits }),Err(CivSetIoError::Limit)));
+        assert!(CivSet::<u64>::from_reader_strict(&buf[..],limits).is_ok());
+        assert!(matches!(CivSet::<u64>::from_reader_strict(&buf[..],CivLimits { entries: 2_999, ..limits }),Err(CivSetIoError::Limit)));
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut set = CivSet::new();
+        for i in 0 .. 1_000u64 {
+            set.insert(i);
+        }
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        assert_eq!(CivSet::<i64>::from_reader(&buf[..]).unwrap().len(),1_000);
+        assert!(matches!(CivSet::<u32>::from_reader(&buf[..]),Err(CivSetIoError::TypeMismatch { found: Some(_), .. })));
+        assert!(matches!(CivSet::<[u32; 2]>::from_reader(&buf[..]),Err(CivSetIoError::TypeMismatch { .. })));
+        assert_eq!(CivSet::<[u32; 2]>::from_reader_unchecked(&buf[..]).unwrap().len(),1_000);
+        set.set_fingerprint(Some(5));
+        let mut buf = Vec::new();
+        set.into_writer(&mut buf).unwrap();
+        assert_eq!(CivSet::<u64>::from_reader_with_fingerprint(&buf[..],5).unwrap().len(),1_000);
+        assert!(matches!(CivSet::<u64>::from_reader_with_fingerprint(&buf[..],6),Err(CivSetIoError::TypeMismatch { expected: 6, found: Some(5) })));
+        assert!(matches!(CivSet::<i64>::from_reader_with_fingerprint(&buf[..],6),Err(CivSetIoError::TypeMismatch { expected: 6, found: Some(5) })));
+    }
+
+    #[test]
+    fn file_versions() {
+        let (set,_) = churned(10_000,10_007);
+        // v0.1 is read only, as is the current one
+        let mut v1 = Vec::new();
+        write!(v1,"CIVS").unwrap();
+        v1.write_u32::<LittleEndian>(0).unwrap();
+        v1.write_u32::<LittleEndian>(1).unwrap();
+        bincode::serialize_into(&mut v1,&set.slot).unwrap();
+        bincode::serialize_into(&mut v1,&set.data).unwrap();
+        let mut current = Vec::new();
+        set.into_writer(&mut current).unwrap();
+        assert_eq!(&current[4 .. 12],&[0,0,0,0,2,0,0,0]);
+        let path = std::env::temp_dir().join(format!("civs-test-set-versions-{}.civs",std::process::id()));
+        for buf in [&v1,&current] {
+            let set2: CivSet<u64> = CivSet::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+            assert_eq!((set2.len(),set2.tombs()),(set.len(),set.tombs()));
+            assert!(set2.sorted_iter().eq(set.sorted_iter()));
+            std::fs::write(&path,buf).unwrap();
+            let set2: CivSet<u64> = CivSet::open_lazy(&path).unwrap();
+            assert!(set2.sorted_iter().eq(set.sorted_iter()));
+        }
+        std::fs::remove_file(&path).unwrap();
+        for version in [(0,3),(1,0)] {
+            let mut buf = current.clone();
+            buf[4 .. 8].copy_from_slice(&u32::to_le_bytes(version.0));
+            buf[8 .. 12].copy_from_slice(&u32::to_le_bytes(version.1));
+            assert!(matches!(CivSet::<u64>::from_reader(&buf[..]),Err(CivSetIoError::InvalidVersion(maj,min)) if (maj,min) == version));
+        }
+    }
+
+    #[test]
+    fn compacted() {
+        let (set,_) = churned(10_000,10_007);
+        let mut full = Vec::new();
+        set.into_writer(&mut full).unwrap();
+        for single_level in [false,true] {
+            let mut buf = Vec::new();
+            set.into_writer_compacted(&mut buf,single_level).unwrap();
+            assert!(buf.len() < full.len());
+            let set2: CivSet<u64> = CivSet::from_reader_strict(&buf[..],CivLimits::default()).unwrap();
+            assert_eq!(set2.len(),set.len());
+            assert!(set2.sorted_iter().eq(set.sorted_iter()));
+            if single_level {
+                assert_eq!(set2.tombs(),0);
+            }
+        }
+    }
+
+    #[test]
+    fn serde() {
+        #[derive(Serialize,Deserialize)]
+        struct Sets {
+            keys: CivSet<u64>,
+            layout: Layout<CivSet<u64>>,
+        }
+
+        let (set,res) = churned(10_000,10_007);
+        let buf = bincode::serialize(&set).unwrap();
+        assert_eq!(buf,bincode::serialize(&res).unwrap());
+        let buf = bincode::serialize(&Sets { keys: set.clone(), layout: Layout(set.clone()) }).unwrap();
+        let sets: Sets = bincode::deserialize(&buf).unwrap();
+        assert_eq!((sets.keys.len(),sets.keys.tombs()),(set.len(),0));
+        assert!(sets.keys.sorted_iter().eq(res.iter()));
+        assert_eq!((sets.layout.0.len(),sets.layout.0.tombs()),(set.len(),set.tombs()));
+        assert!(sets.layout.0.sorted_iter().eq(res.iter()));
+    }
+
+    #[test]
+    fn try_insert() {
+        let mut set = CivSet::new();
+        for i in 0 .. 10_000 {
+            assert!(set.try_insert((i * 7919) % 10_000).unwrap());
+        }
+        assert!(!set.try_insert(5).unwrap());
+        let levels = set.data.len();
+        assert!(matches!(set.try_reserve(usize::MAX),Err(CivError::Alloc(_))));
+        assert_eq!(set.data.len(),levels);
+        set.try_reserve(50_000).unwrap();
+        let caps = set.data.iter().map(|ms| ms.data.capacity()).collect::<Vec<_>>();
+        for i in 10_000 .. 60_000 {
+            set.insert(i);
+        }
+        assert_eq!(set.data.iter().map(|ms| ms.data.capacity()).collect::<Vec<_>>(),caps);
+        assert_eq!(set.len(),60_000);
+    }
+
+    #[test]
+    fn merge_scratch() {
+        let (mut set,res) = churned(30_000,20_011);
+        assert!(set.sorted_iter().eq(res.iter()));
+
+        let levels = set.data.iter().filter(|ms| !ms.empty()).count();
+        set.release_scratch();
+        assert_eq!(set.data.iter().filter(|ms| ms.data.capacity() > 0).count(),levels);
+        // empty levels give their buffers back, the others keep theirs
+        for ms in &set.data {
+            assert_eq!(ms.data.capacity() == 0,ms.empty());
+        }
+        assert_eq!(set.len(),res.len());
+
+        // acc goes into the buffer the merge frees: once the levels are reserved no merge allocates
+        let buffers = |set: &CivSet<u64>| {
+            let mut b: Vec<usize> = set.data.iter().map(|ms| ms.data.as_slice().unwrap().as_ptr() as usize).collect();
+            b.sort_unstable();
+            b
+        };
+        let mut set = CivSet::new();
+        let mut res = std::collections::BTreeSet::new();
+        set.try_reserve(30_000).unwrap();
+        let reserved = buffers(&set);
+        for i in 0 .. 30_000u64 {
+            let k = (i * 7919) % 20_011;
+            set.insert(k);
+            res.insert(k);
+            if i % 3 == 0 {
+                set.remove(&((i * 13) % 20_011));
+                res.remove(&((i * 13) % 20_011));
+            }
+        }
+        assert_eq!(buffers(&set),reserved);
+        assert!(set.sorted_iter().eq(res.iter()));
+    }
 }
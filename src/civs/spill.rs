@@ -0,0 +1,538 @@
+use serde::{Serialize,Serializer,ser::{SerializeSeq,Error as _},de::DeserializeOwned};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self,BufReader,BufWriter,Read,Seek,SeekFrom,Write};
+use std::marker::PhantomData;
+use std::path::{Path,PathBuf};
+use std::sync::{
+    Arc,Mutex,MutexGuard,OnceLock,
+    atomic::{AtomicBool,AtomicUsize,Ordering},
+};
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// entries of a spilled column are found through the offsets of every BLOCK-th one
+//   and are read (and cached) by blocks
+const BLOCK: usize = 64;
+
+pub(crate) fn into_io(e: bincode::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn read_item<T: DeserializeOwned>(rdr: &mut BufReader<File>) -> io::Result<T> {
+    bincode::deserialize_from(rdr).map_err(into_io)
+}
+
+// level file, a temporary one is removed when the last column referencing it is dropped
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    temporary: bool,
+}
+impl SpillFile {
+    pub(crate) fn create(dir: &Path) -> io::Result<(SpillFile,BufWriter<File>)> {
+        let path = dir.join(format!("civs-{}-{}.level",std::process::id(),SPILL_COUNTER.fetch_add(1,Ordering::Relaxed)));
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        Ok((SpillFile { path, temporary: true },BufWriter::new(file)))
+    }
+    // saved map, it must not be changed while the columns are in use
+    pub(crate) fn open(path: &Path) -> SpillFile {
+        SpillFile {
+            path: path.to_path_buf(),
+            temporary: false,
+        }
+    }
+}
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// writer or reader keeping its position, for the offsets of the blocks
+pub(crate) struct Counted<T> {
+    inner: T,
+    pos: u64,
+}
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, pos: u64) -> Counted<T> {
+        Counted { inner, pos }
+    }
+    pub(crate) fn position(&self) -> u64 {
+        self.pos
+    }
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// Writes the items as a bincode Vec<T> at the position of wrt, returns the offsets of the blocks
+pub(crate) fn write_column<T: Serialize, W: Write>(wrt: &mut Counted<W>, items: &[T]) -> io::Result<Vec<u64>> {
+    wrt.write_all(&(items.len() as u64).to_le_bytes())?;
+    let mut marks = Vec::with_capacity(items.len().div_ceil(BLOCK));
+    for (i,x) in items.iter().enumerate() {
+        if i.is_multiple_of(BLOCK) {
+            marks.push(wrt.position());
+        }
+        bincode::serialize_into(&mut *wrt,x).map_err(into_io)?;
+    }
+    Ok(marks)
+}
+
+// Reads a bincode Vec<T> at the position of rdr without keeping the items, returns its length
+//   and the offsets of the blocks
+pub(crate) fn index_column<T: DeserializeOwned, R: Read>(rdr: &mut Counted<R>) -> io::Result<(usize,Vec<u64>)> {
+    let mut prefix = [0; 8];
+    rdr.read_exact(&mut prefix)?;
+    let len = usize::try_from(u64::from_le_bytes(prefix)).map_err(io::Error::other)?;
+    let mut marks = Vec::new();
+    for i in 0 .. len {
+        if i.is_multiple_of(BLOCK) {
+            marks.push(rdr.position());
+        }
+        bincode::deserialize_from::<_,T>(&mut *rdr).map_err(into_io)?;
+    }
+    Ok((len,marks))
+}
+
+// an entry of a spilled level which can't be read poisons the map or set
+pub(crate) fn read_ok<T>(poisoned: &AtomicBool, r: io::Result<T>) -> Option<T> {
+    match r {
+        Ok(x) => Some(x),
+        Err(_) => {
+            poisoned.store(true,Ordering::Relaxed);
+            None
+        },
+    }
+}
+
+// keys of a spilled column up to the first one which can't be read, counted by read
+pub(crate) fn read_keys<'t,K>(keys: &Column<K>, read: &'t mut usize) -> impl Iterator<Item = K> + 't where K: 't {
+    keys.reader().into_iter().flatten().map_while(|k| k.ok()).inspect(move |_| *read += 1)
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_,T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct Reads<T> {
+    rdr: Option<BufReader<File>>,
+    // blocks given out by reference, they are dropped only through &mut
+    blocks: HashMap<usize,Box<[T]>>,
+}
+
+pub(crate) struct Spilled<T> {
+    file: Arc<SpillFile>,
+    // of the bincode encoded Vec<T>
+    offset: u64,
+    len: usize,
+    read: fn(&mut BufReader<File>) -> io::Result<T>,
+    // offsets of the entries 0, BLOCK, 2*BLOCK .., found by a scan of the column if not known
+    marks: OnceLock<Vec<u64>>,
+    reads: Mutex<Reads<T>>,
+    // references to the cached entries are shared
+    _sync: PhantomData<T>,
+}
+impl<T> Spilled<T> {
+    fn reader<'r>(&self, reads: &'r mut Reads<T>, pos: u64) -> io::Result<&'r mut BufReader<File>> {
+        let rdr = match &mut reads.rdr {
+            Some(rdr) => rdr,
+            rdr @ None => rdr.insert(BufReader::new(File::open(&self.file.path)?)),
+        };
+        rdr.seek(SeekFrom::Start(pos))?;
+        Ok(rdr)
+    }
+    fn marks(&self, reads: &mut Reads<T>) -> io::Result<&[u64]> {
+        if let Some(marks) = self.marks.get() {
+            return Ok(marks);
+        }
+        let read = self.read;
+        let rdr = self.reader(reads,self.offset)?;
+        let mut prefix = [0; 8];
+        rdr.read_exact(&mut prefix)?;
+        if u64::from_le_bytes(prefix) != self.len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,"column length is changed"));
+        }
+        let mut marks = Vec::with_capacity(self.len.div_ceil(BLOCK));
+        for b in 0 .. self.len.div_ceil(BLOCK) {
+            if b > 0 {
+                for _ in 0 .. BLOCK {
+                    read(rdr)?;
+                }
+            }
+            marks.push(rdr.stream_position()?);
+        }
+        Ok(self.marks.get_or_init(|| marks))
+    }
+    fn block(&self, reads: &mut Reads<T>, b: usize) -> io::Result<Vec<T>> {
+        let pos = self.marks(reads)?[b];
+        let read = self.read;
+        let rdr = self.reader(reads,pos)?;
+        let n = std::cmp::min(BLOCK,self.len - b * BLOCK);
+        let mut block = Vec::with_capacity(n);
+        for _ in 0 .. n {
+            block.push(read(rdr)?);
+        }
+        Ok(block)
+    }
+    fn item(&self, idx: usize) -> io::Result<&T> {
+        let b = idx / BLOCK;
+        let mut reads = lock(&self.reads);
+        if !reads.blocks.contains_key(&b) {
+            let block = self.block(&mut reads,b)?;
+            reads.blocks.insert(b,block.into_boxed_slice());
+        }
+        let item: *const T = &reads.blocks[&b][idx % BLOCK];
+        // the boxed block is not moved or dropped while self is borrowed
+        Ok(unsafe { &*item })
+    }
+    // reads the entry without caching it
+    fn read_owned(&self, idx: usize) -> io::Result<T> {
+        let mut reads = lock(&self.reads);
+        let pos = self.marks(&mut reads)?[idx / BLOCK];
+        let read = self.read;
+        let rdr = self.reader(&mut reads,pos)?;
+        for _ in 0 .. idx % BLOCK {
+            read(rdr)?;
+        }
+        read(rdr)
+    }
+    // first position with pred false (entries with pred true go first), the blocks are searched
+    //   by their first entries and a single block is read
+    fn partition_point<P: FnMut(&T) -> bool>(&self, mut pred: P) -> io::Result<usize> {
+        let mut reads = lock(&self.reads);
+        let blocks = self.len.div_ceil(BLOCK);
+        let (mut a, mut b) = (0, blocks);
+        while a < b {
+            let m = (a + b) / 2;
+            let first = match reads.blocks.get(&m) {
+                Some(block) => pred(&block[0]),
+                None => {
+                    let pos = self.marks(&mut reads)?[m];
+                    let read = self.read;
+                    pred(&read(self.reader(&mut reads,pos)?)?)
+                },
+            };
+            match first {
+                true => a = m + 1,
+                false => b = m,
+            }
+        }
+        if a == 0 {
+            return Ok(0);
+        }
+        let within = match reads.blocks.get(&(a-1)) {
+            Some(block) => block.partition_point(&mut pred),
+            None => self.block(&mut reads,a-1)?.partition_point(&mut pred),
+        };
+        Ok((a-1) * BLOCK + within)
+    }
+    fn cached(&self) -> usize {
+        lock(&self.reads).blocks.values().map(|block| block.len()).sum()
+    }
+}
+
+// Keys or values of a level, which can be moved to a level file. Entries of a spilled column are read
+//   by blocks, the ones given out by reference are cached until evicted. A spilled column is read
+//   back before it is changed.
+pub(crate) enum Column<T> {
+    Heap(Vec<T>),
+    Spilled(Spilled<T>),
+}
+impl<T> Column<T> {
+    // marks are the offsets of the blocks if known
+    pub(crate) fn spilled(file: Arc<SpillFile>, offset: u64, len: usize, marks: Option<Vec<u64>>) -> Column<T> where T: DeserializeOwned {
+        Column::Spilled(Spilled {
+            file,
+            offset,
+            len,
+            read: read_item::<T>,
+            marks: match marks {
+                Some(marks) => OnceLock::from(marks),
+                None => OnceLock::new(),
+            },
+            reads: Mutex::new(Reads {
+                rdr: None,
+                blocks: HashMap::new(),
+            }),
+            _sync: PhantomData,
+        })
+    }
+    pub(crate) fn is_spilled(&self) -> bool {
+        matches!(self,Column::Spilled(_))
+    }
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Column::Heap(v) => v.len(),
+            Column::Spilled(s) => s.len,
+        }
+    }
+    // heap part only
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Column::Heap(v) => v.capacity(),
+            Column::Spilled(s) => s.cached(),
+        }
+    }
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Column::Heap(v) => v.clear(),
+            Column::Spilled(_) => *self = Column::Heap(Vec::new()),
+        }
+    }
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if let Column::Heap(v) = self {
+            v.shrink_to_fit();
+        }
+    }
+    pub(crate) fn get(&self, idx: usize) -> io::Result<&T> {
+        match self {
+            Column::Heap(v) => Ok(&v[idx]),
+            Column::Spilled(s) => s.item(idx),
+        }
+    }
+    pub(crate) fn last(&self) -> io::Result<Option<&T>> {
+        match self.len() {
+            0 => Ok(None),
+            n => self.get(n-1).map(Some),
+        }
+    }
+    // entry of a spilled column read without caching it
+    pub(crate) fn read_owned(&self, idx: usize) -> Option<io::Result<T>> {
+        match self {
+            Column::Heap(_) => None,
+            Column::Spilled(s) => Some(s.read_owned(idx)),
+        }
+    }
+    pub(crate) fn partition_point<P: FnMut(&T) -> bool>(&self, pred: P) -> io::Result<usize> {
+        match self {
+            Column::Heap(v) => Ok(v.partition_point(pred)),
+            Column::Spilled(s) => s.partition_point(pred),
+        }
+    }
+    pub(crate) fn binary_search(&self, x: &T) -> io::Result<Result<usize,usize>> where T: Ord {
+        match self {
+            Column::Heap(v) => Ok(v.binary_search(x)),
+            Column::Spilled(s) => {
+                let idx = s.partition_point(|y| y < x)?;
+                match (idx < s.len) && (s.read_owned(idx)? == *x) {
+                    true => Ok(Ok(idx)),
+                    false => Ok(Err(idx)),
+                }
+            },
+        }
+    }
+    pub(crate) fn as_slice(&self) -> Option<&[T]> {
+        match self {
+            Column::Heap(v) => Some(v),
+            Column::Spilled(_) => None,
+        }
+    }
+    // the column must be read back by unspill before
+    pub(crate) fn heap(&mut self) -> &mut Vec<T> {
+        match self {
+            Column::Heap(v) => v,
+            Column::Spilled(s) => unreachable!("spilled column {:?} is changed",s.file.path),
+        }
+    }
+    // entries of a spilled column
+    pub(crate) fn read_all(&self) -> io::Result<Vec<T>> {
+        let mut v = Vec::new();
+        v.try_reserve_exact(self.len()).map_err(io::Error::other)?;
+        for x in self.reader()? {
+            v.push(x?);
+        }
+        Ok(v)
+    }
+    pub(crate) fn evict(&mut self) {
+        if let Column::Spilled(s) = self {
+            s.reads.get_mut().unwrap_or_else(|e| e.into_inner()).blocks.clear();
+        }
+    }
+    // sequential reader of a spilled column, without caching
+    pub(crate) fn reader(&self) -> io::Result<ColumnReader<T>> {
+        match self {
+            Column::Heap(_) => Err(io::Error::new(io::ErrorKind::Unsupported,"column is not spilled")),
+            Column::Spilled(s) => {
+                let mut rdr = BufReader::new(File::open(&s.file.path)?);
+                // length prefix is checked by len
+                rdr.seek(SeekFrom::Start(s.offset + 8))?;
+                Ok(ColumnReader {
+                    rdr,
+                    read: s.read,
+                    left: s.len,
+                })
+            },
+        }
+    }
+}
+impl<T> Default for Column<T> {
+    fn default() -> Column<T> {
+        Column::Heap(Vec::new())
+    }
+}
+impl<T> From<Vec<T>> for Column<T> {
+    fn from(v: Vec<T>) -> Column<T> {
+        Column::Heap(v)
+    }
+}
+impl<T: Clone> Clone for Column<T> {
+    fn clone(&self) -> Column<T> {
+        match self {
+            Column::Heap(v) => Column::Heap(v.clone()),
+            Column::Spilled(s) => Column::Spilled(Spilled {
+                file: s.file.clone(),
+                offset: s.offset,
+                len: s.len,
+                read: s.read,
+                marks: s.marks.clone(),
+                reads: Mutex::new(Reads {
+                    rdr: None,
+                    blocks: HashMap::new(),
+                }),
+                _sync: PhantomData,
+            }),
+        }
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for Column<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Column::Heap(v) => v.fmt(f),
+            Column::Spilled(s) => f.debug_struct("Spilled")
+                .field("path", &s.file.path)
+                .field("len", &s.len)
+                .finish(),
+        }
+    }
+}
+// a spilled column is streamed from its file
+impl<T: Serialize> Serialize for Column<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Column::Heap(v) => v.serialize(serializer),
+            Column::Spilled(_) => {
+                let rdr = self.reader().map_err(S::Error::custom)?;
+                let mut seq = serializer.serialize_seq(Some(self.len()))?;
+                for x in rdr {
+                    seq.serialize_element(&x.map_err(S::Error::custom)?)?;
+                }
+                seq.end()
+            },
+        }
+    }
+}
+
+pub(crate) struct ColumnReader<T> {
+    rdr: BufReader<File>,
+    read: fn(&mut BufReader<File>) -> io::Result<T>,
+    left: usize,
+}
+impl<T> Iterator for ColumnReader<T> {
+    type Item = io::Result<T>;
+    fn next(&mut self) -> Option<io::Result<T>> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        Some((self.read)(&mut self.rdr))
+    }
+}
+
+// Writes a level in the bincode level encoding entry by entry: keys go to the level file,
+//   values to a temporary one which is appended on finish. All entries are live.
+pub(crate) struct LevelWriter<K,V> {
+    file: SpillFile,
+    wrt: Counted<BufWriter<File>>,
+    tmp: SpillFile,
+    tmp_wrt: Counted<BufWriter<File>>,
+    capacity: usize,
+    flags_offset: u64,
+    keys_offset: u64,
+    len: usize,
+    key_marks: Vec<u64>,
+    value_marks: Vec<u64>,
+    _kv: PhantomData<(K,V)>,
+}
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> LevelWriter<K,V> {
+    pub(crate) fn create(dir: &Path, capacity: usize) -> io::Result<LevelWriter<K,V>> {
+        let (file,mut wrt) = SpillFile::create(dir)?;
+        let (tmp,tmp_wrt) = SpillFile::create(dir)?;
+        bincode::serialize_into(&mut wrt,&(capacity,std::mem::size_of::<K>(),std::mem::size_of::<V>())).map_err(into_io)?;
+        let flags_offset = wrt.stream_position()?;
+        bincode::serialize_into(&mut wrt,&vec![0u64; capacity.div_ceil(64)]).map_err(into_io)?;
+        let keys_offset = wrt.stream_position()?;
+        wrt.write_all(&[0; 8])?;
+        Ok(LevelWriter {
+            file,
+            wrt: Counted::new(wrt,keys_offset + 8),
+            tmp,
+            tmp_wrt: Counted::new(tmp_wrt,0),
+            capacity,
+            flags_offset,
+            keys_offset,
+            len: 0,
+            key_marks: Vec::new(),
+            value_marks: Vec::new(),
+            _kv: PhantomData,
+        })
+    }
+    pub(crate) fn push(&mut self, k: &K, v: &V) -> io::Result<()> {
+        if self.len == self.capacity {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,"level is full"));
+        }
+        if self.len.is_multiple_of(BLOCK) {
+            self.key_marks.push(self.wrt.position());
+            self.value_marks.push(self.tmp_wrt.position());
+        }
+        bincode::serialize_into(&mut self.wrt,k).map_err(into_io)?;
+        bincode::serialize_into(&mut self.tmp_wrt,v).map_err(into_io)?;
+        self.len += 1;
+        Ok(())
+    }
+    pub(crate) fn finish(self) -> io::Result<(Column<K>,Column<V>)> {
+        let LevelWriter { file, wrt, tmp, tmp_wrt, flags_offset, keys_offset, len, key_marks, mut value_marks, .. } = self;
+        let mut wrt = wrt.into_inner();
+        let values_offset = wrt.stream_position()?;
+        wrt.write_all(&(len as u64).to_le_bytes())?;
+        let mut values = tmp_wrt.into_inner().into_inner().map_err(|e| e.into_error())?;
+        values.seek(SeekFrom::Start(0))?;
+        io::copy(&mut values,&mut wrt)?;
+        drop(values);
+        drop(tmp);
+        for mark in &mut value_marks {
+            *mark += values_offset + 8;
+        }
+
+        wrt.seek(SeekFrom::Start(keys_offset))?;
+        wrt.write_all(&(len as u64).to_le_bytes())?;
+        // flags count is already written
+        wrt.seek(SeekFrom::Start(flags_offset + 8))?;
+        for _ in 0 .. (len / 64) {
+            wrt.write_all(&u64::MAX.to_le_bytes())?;
+        }
+        if len % 64 > 0 {
+            wrt.write_all(&((1u64 << (len % 64)) - 1).to_le_bytes())?;
+        }
+        wrt.flush()?;
+        let file = Arc::new(file);
+        Ok((Column::spilled(file.clone(),keys_offset,len,Some(key_marks)),Column::spilled(file,values_offset,len,Some(value_marks))))
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64,Ordering},
+};
+use crate::civs::map::{CivMap,RemovedItem,Compaction};
+
+// Expiry timestamps are opaque u64 (seconds, millis, ...), entry is expired when expire <= now.
+//   Every `now` passed to a method taking &mut self is kept as a watermark, merges physically
+//   drop entries expired according to it. Reads by &self only filter by their `now`.
+#[derive(Debug)]
+pub struct CivTtlMap<K,V> {
+    map: CivMap<K,(u64,V)>,
+    clock: Arc<AtomicU64>,
+}
+impl<K: Ord, V> Default for CivTtlMap<K,V> {
+    fn default() -> CivTtlMap<K,V> {
+        CivTtlMap::new()
+    }
+}
+// the clone gets its own watermark, moving it does not affect the merges of the original
+impl<K: Ord + Clone, V: Clone> Clone for CivTtlMap<K,V> {
+    fn clone(&self) -> CivTtlMap<K,V> {
+        CivTtlMap::with_map(self.map.clone(),self.clock.load(Ordering::Relaxed))
+    }
+}
+impl<K: Ord, V> CivTtlMap<K,V> {
+    pub fn new() -> CivTtlMap<K,V> {
+        CivTtlMap::with_map(CivMap::new(),0)
+    }
+    fn with_map(mut map: CivMap<K,(u64,V)>, now: u64) -> CivTtlMap<K,V> {
+        let clock = Arc::new(AtomicU64::new(now));
+        let watermark = clock.clone();
+        map.set_compaction_filter(move |_,(expire,_)| match *expire > watermark.load(Ordering::Relaxed) {
+            true => Compaction::Keep,
+            false => Compaction::Drop,
+        });
+        CivTtlMap {
+            map,
+            clock,
+        }
+    }
+    fn tick(&mut self, now: u64) {
+        self.clock.fetch_max(now,Ordering::Relaxed);
+    }
+    // number of stored entries, expired ones are included until merged or swept
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.len() == 0
+    }
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+    pub fn contains(&self, k: &K, now: u64) -> bool {
+        self.get(k,now).is_some()
+    }
+    pub fn get(&self, k: &K, now: u64) -> Option<&V> {
+        match self.map.get(k) {
+            Some((expire,v)) if *expire > now => Some(v),
+            _ => None,
+        }
+    }
+    pub fn get_mut(&mut self, k: &K, now: u64) -> Option<&mut V> {
+        self.tick(now);
+        match self.map.get_mut(k) {
+            Some((expire,v)) if *expire > now => Some(v),
+            _ => None,
+        }
+    }
+    pub fn expire_at(&self, k: &K, now: u64) -> Option<u64> {
+        match self.map.get(k) {
+            Some((expire,_)) if *expire > now => Some(*expire),
+            _ => None,
+        }
+    }
+    // returns previous value if it was not expired
+    pub fn insert(&mut self, k: K, v: V, expire: u64, now: u64) -> Option<V> {
+        self.tick(now);
+        match self.map.insert(k,(expire,v)) {
+            Some((expire,v)) if expire > now => Some(v),
+            _ => None,
+        }
+    }
+    pub fn remove(&mut self, k: &K, now: u64) -> Option<RemovedItem<'_,V>> {
+        self.tick(now);
+        match self.map.remove(k) {
+            Some(RemovedItem::Ref((expire,v))) if *expire > now => Some(RemovedItem::Ref(v)),
+            Some(RemovedItem::Owned((expire,v))) if expire > now => Some(RemovedItem::Owned(v)),
+            _ => None,
+        }
+    }
+    pub fn expire_until(&mut self, now: u64) {
+        self.tick(now);
+        self.map.retain(|_,(expire,_)| *expire > now);
+    }
+    pub fn filtered_iter(&self, now: u64) -> impl DoubleEndedIterator<Item = (&K,&V)> {
+        self.map.filtered_iter().filter_map(move |(k,(expire,v))| match *expire > now {
+            true => Some((k,v)),
+            false => None,
+        })
+    }
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let mut map: CivTtlMap<u64,u64> = CivTtlMap::new();
+        for i in 0 .. 10_000 {
+            map.insert(i,i,i + 100,i);
+        }
+        assert!(!map.contains(&0,9_999));
+        assert!(map.contains(&9_950,9_999));
+        // merges have already dropped most of the expired entries
+        assert!(map.len() < 1_000);
+
+        map.expire_until(9_999);
+        assert_eq!(map.len(),100);
+        assert_eq!(map.filtered_iter(9_999).count(),100);
+        assert_eq!(map.remove(&9_990,10_000).map(|ritem|ritem.copied()),Some(9_990));
+        assert_eq!(map.get(&9_995,10_200),None);
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut map: CivTtlMap<u64,u64> = CivTtlMap::new();
+        for i in 0 .. 1_000 {
+            map.insert(i,i,1_000 + i,0);
+        }
+        let mut copy = map.clone();
+        // the watermark of the copy does not drop the entries of the original
+        for i in 1_000 .. 2_000 {
+            copy.insert(i,i,u64::MAX,1_500);
+        }
+        assert!(copy.len() < 1_500);
+        for i in 1_000 .. 2_000 {
+            map.insert(i,i,u64::MAX,0);
+        }
+        assert_eq!(map.len(),2_000);
+        assert_eq!(map.filtered_iter(0).count(),2_000);
+        assert!(map.contains(&0,999));
+        assert!(!copy.contains(&0,999));
+    }
+}
@@ -1,12 +1,15 @@
-use serde::{Serialize,Deserialize};
+use serde::Serialize;
 use std::io::{Write,Read};
 
 mod civs;
 
 pub use crate::civs::{
-    set::{CivSet,CivSetIoError},
-    map::{CivMap,CivMapIoError,RemovedItem,Iter},
+    set::{CivSet,CivSetIoError,SortedIter as SetSortedIter,Cursor as SetCursor,CursorMut as SetCursorMut},
+    map::{CivMap,CivMapIoError,RemovedItem,MergedValue,Compaction,Iter,SortedIter,Cursor,CursorMut},
+    ttl::CivTtlMap,
 };
+#[cfg(feature = "bytemuck")]
+pub use crate::civs::mapped::MappedCivMap;
 
 
 pub trait Binary: Sized {
@@ -23,24 +26,105 @@ enum Filled {
     Full,
 }
 
-#[derive(Debug,Clone,Serialize,Deserialize)]
-struct Flags(Vec<u64>);
+#[derive(Debug)]
+pub enum CivError {
+    Alloc(std::collections::TryReserveError),
+    Internal(&'static str),
+    Poisoned,
+    // spilled level can't be read
+    Io(std::io::Error),
+}
+impl From<std::collections::TryReserveError> for CivError {
+    fn from(e: std::collections::TryReserveError) -> CivError {
+        CivError::Alloc(e)
+    }
+}
+
+// broken invariant found by validate, levels are numbered from the newest one
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum CivInvalid {
+    // slot is of zero size, overfilled or has a key twice
+    Slot,
+    Capacity { level: usize, capacity: usize, expected: usize },
+    // keys and values lengths differ or exceed the capacity
+    Columns { level: usize, keys: usize, values: usize },
+    Flags { level: usize, words: usize, expected: usize },
+    // live flag of a missing entry
+    DeadTail { level: usize },
+    Unsorted { level: usize, index: usize },
+    // live key of the level is live in a newer one (in a map without a merge op or in a set)
+    Overlap { level: usize, index: usize },
+    Counters { len: usize, tombs: usize, expected_len: usize, expected_tombs: usize },
+}
+
+// Limits of loading an untrusted file by from_reader_with_limits, the default has none
+#[derive(Debug,Clone,Copy)]
+pub struct CivLimits {
+    // bytes read from the reader
+    pub bytes: u64,
+    // entries of the slot and the levels, dead ones included
+    pub entries: usize,
+    // capacity of a level and the slot size
+    pub level_capacity: usize,
+}
+impl Default for CivLimits {
+    fn default() -> CivLimits {
+        CivLimits {
+            bytes: u64::MAX,
+            entries: usize::MAX,
+            level_capacity: usize::MAX,
+        }
+    }
+}
+
+// Layout preserving serde of CivMap and CivSet: the slot and the levels as they are, dead entries included,
+//   for a fast round trip with bincode. CivMap and CivSet themselves are serialized as a map and a sequence
+//   of their live entries. A merge op, a filter and the like are not serialized, the loaded layout is
+//   checked by validate.
+#[derive(Debug,Clone)]
+pub struct Layout<T>(pub T);
+
+// rank index: fenwick tree over ones count of blocks of RANK_BLOCK words
+const RANK_BLOCK: usize = 8;
+
+#[derive(Debug,Clone)]
+struct Flags {
+    bits: Vec<u64>,
+    ranks: Vec<u64>,
+}
+impl Serialize for Flags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bits.serialize(serializer)
+    }
+}
 impl Flags {
-    fn heap_mem(&self) -> usize {
-        self.0.capacity() * std::mem::size_of::<u64>()
+    fn from_words(v: Vec<u64>) -> Flags {
+        let mut flags = Flags {
+            bits: v,
+            ranks: Vec::new(),
+        };
+        flags.reindex();
+        flags
     }
-    fn tmp() -> Flags {
-        Flags(vec![])
+    fn heap_mem(&self) -> usize {
+        (self.bits.capacity() + self.ranks.capacity()) * std::mem::size_of::<u64>()
     }
     fn nulls(sz: usize) -> Flags {
-        if sz == 0 { return Flags(Vec::new()); }
+        if sz == 0 { return Flags::from_words(Vec::new()); }
         let ln = 1 + (sz-1)/64;
         let mut v = Vec::with_capacity(ln);
         for _ in 0 .. ln { v.push(0); }
-        Flags(v)
+        Flags::from_words(v)
+    }
+    fn try_nulls(sz: usize) -> Result<Flags,std::collections::TryReserveError> {
+        let ln = sz.div_ceil(64);
+        let mut v = Vec::new();
+        v.try_reserve_exact(ln)?;
+        v.resize(ln,0);
+        Ok(Flags::from_words(v))
     }
     fn ones(sz: usize) -> Flags {
-        if sz == 0 { return Flags(Vec::new()); }
+        if sz == 0 { return Flags::from_words(Vec::new()); }
         let ln = 1 + (sz-1)/64;
         let mut v = Vec::with_capacity(ln);
         let mut s = sz;
@@ -53,49 +137,258 @@ impl Flags {
             if s > 64 { s -= 64; } else { s = 0; } 
         }
         while v.len() < ln { v.push(0); }
-        Flags(v)
+        Flags::from_words(v)
     }
     fn set_nulls(&mut self) {
-        for i in 0 .. self.0.len() {
-            self.0[i] = 0;
+        for i in 0 .. self.bits.len() {
+            self.bits[i] = 0;
         }
+        self.reindex();
     }
     fn set_ones(&mut self, sz: usize) {
+        if sz == 0 { return self.set_nulls(); }
         let ln = 1 + (sz-1)/64;
-        while self.0.len() < ln { self.0.push(0); }
+        while self.bits.len() < ln { self.bits.push(0); }
         let mut s = sz;
         for i in 0 .. ln {
             match s {
-                0 => self.0[i] = 0,
-                t @ _ if t < 64 => self.0[i] = 0xFFFFFFFFFFFFFFFFu64 >> (64 - t),
-                _ => self.0[i] = 0xFFFFFFFFFFFFFFFFu64,
+                0 => self.bits[i] = 0,
+                t @ _ if t < 64 => self.bits[i] = 0xFFFFFFFFFFFFFFFFu64 >> (64 - t),
+                _ => self.bits[i] = 0xFFFFFFFFFFFFFFFFu64,
             }
             if s > 64 { s -= 64; } else { s = 0; } 
         }
+        self.reindex();
     }
     #[inline]
     fn get(&self, idx: usize) -> bool {
         let i = idx/64;
         let j = idx%64;
-        (self.0[i] & (0x1u64 << j)) > 0
+        (self.bits[i] & (0x1u64 << j)) > 0
     }
     #[inline]
     fn unset(&mut self, idx: usize) {
         let i = idx/64;
         let j = idx%64;
-        self.0[i] &= 0xFFFFFFFFFFFFFFFFu64 - (0x1u64 << j);
+        if (self.bits[i] & (0x1u64 << j)) > 0 {
+            self.bits[i] &= 0xFFFFFFFFFFFFFFFFu64 - (0x1u64 << j);
+            self.rank_dec(i / RANK_BLOCK,1);
+        }
     }
-    /*#[inline]
+    fn unset_range(&mut self, from: usize, to: usize) -> usize { // count of unset ones
+        let mut cnt = 0;
+        let mut idx = from;
+        while idx < to {
+            let i = idx/64;
+            let j = idx%64;
+            let n = std::cmp::min(64 - j, to - idx);
+            let mask = match n {
+                64 => 0xFFFFFFFFFFFFFFFFu64,
+                _ => ((0x1u64 << n) - 1) << j,
+            };
+            let c = (self.bits[i] & mask).count_ones() as u64;
+            if c > 0 {
+                self.bits[i] &= !mask;
+                self.rank_dec(i / RANK_BLOCK,c);
+            }
+            cnt += c as usize;
+            idx += n;
+        }
+        cnt
+    }
+    #[inline]
     fn set(&mut self, idx: usize) {
         let i = idx/64;
         let j = idx%64;
-        self.0[i] |= 0x1u64 << j;
-    }*/
+        if (self.bits[i] & (0x1u64 << j)) == 0 {
+            self.bits[i] |= 0x1u64 << j;
+            self.rank_inc(i / RANK_BLOCK,1);
+        }
+    }
+    // flags of the same size with the positions of removed in [0,len) cut out
+    fn cut(&self, removed: &Flags, len: usize) -> Flags {
+        let mut flags = Flags::nulls(self.bits.len() * 64);
+        let mut w = 0;
+        for idx in 0 .. len {
+            if removed.get(idx) { continue; }
+            if self.get(idx) {
+                flags.bits[w/64] |= 0x1u64 << (w%64);
+            }
+            w += 1;
+        }
+        flags.reindex();
+        flags
+    }
     /*fn clear(&mut self) {
-        for v in &mut self.0 {
+        for v in &mut self.bits {
             *v = 0;
         }
     }*/
+
+    fn reindex(&mut self) {
+        let blocks = self.bits.len().div_ceil(RANK_BLOCK);
+        self.ranks.clear();
+        for b in 0 .. blocks {
+            let end = std::cmp::min(self.bits.len(), (b+1)*RANK_BLOCK);
+            let c = self.bits[b*RANK_BLOCK .. end].iter().fold(0,|acc,x| acc + x.count_ones() as u64);
+            self.ranks.push(c);
+        }
+        for i in 0 .. blocks {
+            let p = i | (i + 1);
+            if p < blocks {
+                self.ranks[p] += self.ranks[i];
+            }
+        }
+    }
+    fn rank_inc(&mut self, block: usize, c: u64) {
+        let mut i = block;
+        while i < self.ranks.len() {
+            self.ranks[i] += c;
+            i |= i + 1;
+        }
+    }
+    fn rank_dec(&mut self, block: usize, c: u64) {
+        let mut i = block;
+        while i < self.ranks.len() {
+            self.ranks[i] -= c;
+            i |= i + 1;
+        }
+    }
+    fn blocks_rank(&self, blocks: usize) -> usize { // ones in the first blocks
+        let mut r = 0;
+        let mut i = blocks;
+        while i > 0 {
+            r += self.ranks[i-1];
+            i &= i - 1;
+        }
+        r as usize
+    }
+    fn count(&self) -> usize {
+        self.blocks_rank(self.ranks.len())
+    }
+    fn rank(&self, idx: usize) -> usize { // ones in [0,idx)
+        let i = idx/64;
+        let j = idx%64;
+        let b = i / RANK_BLOCK;
+        let mut r = self.blocks_rank(b);
+        for w in b*RANK_BLOCK .. i {
+            r += self.bits[w].count_ones() as usize;
+        }
+        if j > 0 {
+            r += (self.bits[i] & (0xFFFFFFFFFFFFFFFFu64 >> (64 - j))).count_ones() as usize;
+        }
+        r
+    }
+    fn select(&self, n: usize) -> Option<usize> { // index of the n-th one
+        let mut n = n as u64;
+        let mut b = 0;
+        let mut step = self.ranks.len().next_power_of_two();
+        while step > 0 {
+            if (b + step <= self.ranks.len())&&(self.ranks[b+step-1] <= n) {
+                n -= self.ranks[b+step-1];
+                b += step;
+            }
+            step /= 2;
+        }
+        for i in b*RANK_BLOCK .. std::cmp::min(self.bits.len(), (b+1)*RANK_BLOCK) {
+            let c = self.bits[i].count_ones() as u64;
+            if n < c {
+                let mut w = self.bits[i];
+                for _ in 0 .. n {
+                    w &= w - 1;
+                }
+                return Some(i*64 + w.trailing_zeros() as usize);
+            }
+            n -= c;
+        }
+        None
+    }
+    fn next_one(&self, from: usize) -> Option<usize> { // first one in [from,..)
+        self.iter_ones_range(from,self.bits.len()*64).next()
+    }
+    fn prev_one(&self, before: usize) -> Option<usize> { // last one in [0,before)
+        self.iter_ones_range(0,before).next_back()
+    }
+    fn iter_ones(&self) -> Ones<'_> {
+        self.iter_ones_range(0,self.bits.len()*64)
+    }
+    fn iter_ones_range(&self, from: usize, to: usize) -> Ones<'_> {
+        let to = std::cmp::min(to,self.bits.len()*64);
+        Ones {
+            bits: &self.bits,
+            front: std::cmp::min(from,to),
+            back: to,
+        }
+    }
+    // items of iter at the positions of ones
+    fn filter<I: Iterator>(&self, iter: I) -> FilterOnes<'_,I> {
+        FilterOnes {
+            iter,
+            ones: self.iter_ones(),
+            pos: 0,
+        }
+    }
+}
+
+// indexes of ones, dead words are skipped at once
+struct Ones<'t> {
+    bits: &'t [u64],
+    front: usize,
+    back: usize,
+}
+impl<'t> Iterator for Ones<'t> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.front < self.back {
+            let i = self.front/64;
+            let w = self.bits[i] & (0xFFFFFFFFFFFFFFFFu64 << (self.front%64));
+            if w == 0 {
+                self.front = (i+1)*64;
+                continue;
+            }
+            let p = i*64 + w.trailing_zeros() as usize;
+            if p >= self.back { break; }
+            self.front = p + 1;
+            return Some(p);
+        }
+        self.front = self.back;
+        None
+    }
+}
+impl<'t> DoubleEndedIterator for Ones<'t> {
+    fn next_back(&mut self) -> Option<usize> {
+        while self.back > self.front {
+            let i = (self.back-1)/64;
+            let w = self.bits[i] & (0xFFFFFFFFFFFFFFFFu64 >> (63 - (self.back-1)%64));
+            if w == 0 {
+                self.back = i*64;
+                continue;
+            }
+            let p = i*64 + 63 - w.leading_zeros() as usize;
+            if p < self.front { break; }
+            self.back = p;
+            return Some(p);
+        }
+        self.back = self.front;
+        None
+    }
+}
+
+struct FilterOnes<'t,I> {
+    iter: I,
+    ones: Ones<'t>,
+    pos: usize,
+}
+impl<'t,I: Iterator> Iterator for FilterOnes<'t,I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let p = self.ones.next()?;
+        let skip = p - self.pos;
+        self.pos = p + 1;
+        self.iter.nth(skip)
+    }
 }
 
 